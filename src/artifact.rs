@@ -0,0 +1,188 @@
+use std::path::PathBuf;
+
+use miette::SourceSpan;
+
+use crate::{
+    errors::{BFError, BFErrors},
+    program::Instruction,
+};
+
+const HEADER: &str = "bfem 1";
+const STRIPPED_HEADER: &str = "bfem stripped 1";
+
+/// A program read back from an artifact.
+pub struct Artifact {
+    /// The file the program was compiled from, if it was recorded
+    pub source: Option<PathBuf>,
+    /// Aliases in the order they should be allocated
+    pub aliases: Vec<String>,
+    pub instructions: Vec<(SourceSpan, Instruction)>,
+    /// Whether the spans are real, or placeholders for a stripped artifact
+    pub stripped: bool,
+}
+
+fn write_layer(instructions: &[(SourceSpan, Instruction)], strip: bool, out: &mut String) {
+    for (span, instruction) in instructions {
+        if !strip {
+            out.push_str(&format!("{} {} ", span.offset(), span.len()));
+        }
+
+        match instruction {
+            Instruction::Add(count) => out.push_str(&format!("add {}\n", count)),
+            Instruction::Subtract(count) => out.push_str(&format!("sub {}\n", count)),
+            Instruction::Left(count) => out.push_str(&format!("left {}\n", count)),
+            Instruction::Right(count) => out.push_str(&format!("right {}\n", count)),
+            Instruction::Input => out.push_str("in\n"),
+            Instruction::Output(count) => out.push_str(&format!("out {}\n", count)),
+            Instruction::SetZero => out.push_str("zero\n"),
+            Instruction::Set(value) => out.push_str(&format!("set {}\n", value)),
+            Instruction::DebugPrintCell => out.push_str("debug\n"),
+            Instruction::Goto(name) => out.push_str(&format!("goto {}\n", name)),
+            Instruction::Seek(index) => out.push_str(&format!("seek {}\n", index)),
+            Instruction::Comment(text) => out.push_str(&format!("comment {}\n", text)),
+            Instruction::Loop(inner_instructions) => {
+                out.push_str("loop\n");
+                write_layer(inner_instructions, strip, out);
+                out.push_str("end\n");
+            }
+        }
+    }
+}
+
+/// Write `instructions` as an artifact, the compiled form of a program that
+/// `bfem compile` writes to a `.bfc` file. Artifacts are line based:
+///
+/// ```text
+/// bfem 1
+/// source examples/variable_decl.bfem
+/// alias b
+/// 9 3 add 3
+/// 12 8 loop
+/// ...
+/// end
+/// ```
+///
+/// Each instruction is prefixed by its source offset and length. Stripped
+/// artifacts (`bfem stripped 1`) leave out the spans and the source line, so
+/// they are smaller but errors can't point at where they happened.
+pub fn write(
+    instructions: &[(SourceSpan, Instruction)],
+    aliases: &[String],
+    source: Option<&str>,
+    strip: bool,
+) -> String {
+    let mut out = String::new();
+    if strip {
+        out.push_str(STRIPPED_HEADER);
+        out.push('\n');
+    } else {
+        out.push_str(HEADER);
+        out.push('\n');
+        if let Some(source) = source {
+            out.push_str(&format!("source {}\n", source));
+        }
+    }
+
+    for alias in aliases {
+        out.push_str(&format!("alias {}\n", alias));
+    }
+
+    write_layer(instructions, strip, &mut out);
+    out
+}
+
+fn invalid(line: usize, message: &str) -> BFError {
+    BFError::new(
+        BFErrors::SyntaxError,
+        format!("Invalid artifact on line {}: {}", line, message),
+    )
+}
+
+fn parse_count<T: std::str::FromStr>(line: usize, argument: &str) -> Result<T, BFError> {
+    argument
+        .parse()
+        .map_err(|_| invalid(line, &format!("bad count {:?}", argument)))
+}
+
+/// Read an artifact produced by [`write`].
+pub fn read(text: &str) -> Result<Artifact, BFError> {
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line));
+
+    let stripped = match lines.next() {
+        Some((_, HEADER)) => false,
+        Some((_, STRIPPED_HEADER)) => true,
+        _ => return Err(invalid(1, "not a bfem artifact")),
+    };
+
+    let mut source = None;
+    let mut aliases = vec![];
+    let mut instructions = vec![];
+    // Loops being read, with their span and the instructions outside them
+    let mut open_loops: Vec<(SourceSpan, Vec<(SourceSpan, Instruction)>)> = vec![];
+
+    for (number, line) in lines {
+        if let Some(path) = line.strip_prefix("source ") {
+            source = Some(PathBuf::from(path));
+            continue;
+        }
+        if let Some(name) = line.strip_prefix("alias ") {
+            aliases.push(name.to_string());
+            continue;
+        }
+        if line == "end" {
+            let (span, outer) = open_loops
+                .pop()
+                .ok_or_else(|| invalid(number, "end without a loop"))?;
+            let body = std::mem::replace(&mut instructions, outer);
+            instructions.push((span, Instruction::Loop(body)));
+            continue;
+        }
+
+        let mut rest = line;
+        let span: SourceSpan = if stripped {
+            (0, 0).into()
+        } else {
+            let mut parts = rest.splitn(3, ' ');
+            let offset: usize = parse_count(number, parts.next().unwrap_or(""))?;
+            let length: usize = parse_count(number, parts.next().unwrap_or(""))?;
+            rest = parts.next().unwrap_or("");
+            (offset, length).into()
+        };
+
+        let (name, argument) = rest.split_once(' ').unwrap_or((rest, ""));
+        let instruction = match name {
+            "add" => Instruction::Add(parse_count(number, argument)?),
+            "sub" => Instruction::Subtract(parse_count(number, argument)?),
+            "left" => Instruction::Left(parse_count(number, argument)?),
+            "right" => Instruction::Right(parse_count(number, argument)?),
+            "in" => Instruction::Input,
+            "out" => Instruction::Output(parse_count(number, argument)?),
+            "zero" => Instruction::SetZero,
+            "set" => Instruction::Set(parse_count(number, argument)?),
+            "debug" => Instruction::DebugPrintCell,
+            "goto" => Instruction::Goto(argument.to_string()),
+            "seek" => Instruction::Seek(parse_count(number, argument)?),
+            "comment" => Instruction::Comment(argument.to_string()),
+            "loop" => {
+                open_loops.push((span, std::mem::take(&mut instructions)));
+                continue;
+            }
+            _ => return Err(invalid(number, &format!("unknown instruction {:?}", name))),
+        };
+        instructions.push((span, instruction));
+    }
+
+    if !open_loops.is_empty() {
+        return Err(invalid(text.lines().count(), "loop is never ended"));
+    }
+
+    Ok(Artifact {
+        source,
+        aliases,
+        instructions,
+        stripped,
+    })
+}
@@ -1,15 +1,31 @@
-use std::fmt::Display;
+use std::{fmt::Display, io, path::Path};
 
+use clap::ValueEnum;
 use miette::{
-    Diagnostic, GraphicalReportHandler, GraphicalTheme, NarratableReportHandler, Report, SourceSpan,
+    miette, GraphicalReportHandler, GraphicalTheme, LabeledSpan, NamedSource,
+    NarratableReportHandler, Report, Severity, SourceSpan,
 };
 use thiserror::Error;
 
-use crate::program::Instruction;
+/// How errors are written out.
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum ErrorFormat {
+    /// A rendered report highlighting the source
+    Human,
+    /// One JSON object per line, for other tools to read
+    Json,
+}
 
 #[derive(Debug, Copy, Clone)]
 pub enum BFErrors {
     RuntimeError,
+    SyntaxError,
+    StepLimitExceeded,
+    TapeLimitExceeded,
+    OutputLimitExceeded,
+    TimeLimitExceeded,
+    Interrupted,
+    IoError,
 }
 
 #[derive(Error, Debug)]
@@ -30,11 +46,118 @@ impl BFError {
     }
 }
 
+impl From<io::Error> for BFError {
+    fn from(error: io::Error) -> Self {
+        Self::new(BFErrors::IoError, error.to_string())
+    }
+}
+
+/// Convert the start of `span` into a 1-based (line, column) pair. Columns
+/// count characters rather than bytes, so multi-byte sources line up with
+/// what an editor shows.
+pub fn line_col(src: &str, span: SourceSpan) -> (usize, usize) {
+    let offset = span.offset().min(src.len());
+    let before = src.get(..offset).unwrap_or(src);
+
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map(|index| index + 1).unwrap_or(0);
+    let column = before[line_start..].chars().count() + 1;
+
+    (line, column)
+}
+
+/// Render `error` against the source it came from, highlighting `span` if it
+/// is known.
+pub fn render_error(path: &Path, src: &str, error: &BFError, span: Option<SourceSpan>) -> String {
+    let (labels, location) = match span {
+        Some(span) => {
+            let (line, column) = line_col(src, span);
+            (
+                vec![LabeledSpan::new_with_span(
+                    Some("error occurs here".to_string()),
+                    span,
+                )],
+                format!("{}:{}:{}: ", path.display(), line, column),
+            )
+        }
+        None => (vec![], String::new()),
+    };
+    let report = miette!(labels = labels, "{}{}", location, error.message);
+
+    fmt_report(report.with_source_code(NamedSource::new(path.to_str().unwrap(), src.to_string())))
+}
+
+/// Quote `value` as a JSON string.
+pub(crate) fn json_string(value: &str) -> String {
+    let mut out = String::from("\"");
+    for character in value.chars() {
+        match character {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            character if character.is_control() => {
+                out.push_str(&format!("\\u{:04x}", character as u32))
+            }
+            character => out.push(character),
+        }
+    }
+    out.push('"');
+
+    out
+}
+
+/// Render `warning` against the source it came from, highlighting `span`.
+pub fn render_warning(path: &Path, src: &str, warning: &str, span: SourceSpan) -> String {
+    let (line, column) = line_col(src, span);
+    let report = miette!(
+        severity = Severity::Warning,
+        labels = vec![LabeledSpan::new_with_span(None, span)],
+        "{}:{}:{}: {}",
+        path.display(),
+        line,
+        column,
+        warning
+    );
+
+    fmt_report(report.with_source_code(NamedSource::new(path.to_str().unwrap(), src.to_string())))
+}
+
+fn render_json(path: &Path, kind: &str, message: &str, span: Option<SourceSpan>) -> String {
+    let (offset, length) = match span {
+        Some(span) => (span.offset().to_string(), span.len().to_string()),
+        None => ("null".to_string(), "null".to_string()),
+    };
+
+    format!(
+        "{{\"kind\":{},\"message\":{},\"offset\":{},\"length\":{},\"file\":{}}}",
+        json_string(kind),
+        json_string(message),
+        offset,
+        length,
+        json_string(&path.display().to_string())
+    )
+}
+
+/// Render `error` as a single line JSON object with `kind`, `message`,
+/// `offset`, `length` and `file`. The offset and length are byte based, and
+/// `null` if the span isn't known.
+pub fn render_error_json(path: &Path, error: &BFError, span: Option<SourceSpan>) -> String {
+    render_json(path, &format!("{:?}", error.error), &error.message, span)
+}
+
+/// Render `warning` in the same form as [`render_error_json`], with the kind
+/// `Warning`.
+pub fn render_warning_json(path: &Path, warning: &str, span: SourceSpan) -> String {
+    render_json(path, "Warning", warning, Some(span))
+}
+
 pub fn fmt_report(diag: Report) -> String {
     let mut out = String::new();
     // Mostly for dev purposes.
     if std::env::var("STYLE").is_ok() {
-        let mut themed = GraphicalReportHandler::new_themed(GraphicalTheme::unicode())
+        GraphicalReportHandler::new_themed(GraphicalTheme::unicode())
             .with_width(80)
             .render_report(&mut out, diag.as_ref())
             .unwrap();
@@ -56,3 +179,41 @@ pub fn fmt_report(diag: Report) -> String {
     };
     out
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_col_counts_lines_and_characters() {
+        let src = "+\n# héllo\n>>é-";
+        // The `-` on the third line, after a two byte character
+        let offset = src.rfind('-').unwrap();
+
+        assert_eq!(line_col(src, (0, 1).into()), (1, 1));
+        assert_eq!(line_col(src, (offset, 1).into()), (3, 4));
+    }
+
+    #[test]
+    fn rendered_errors_start_with_the_location() {
+        let src = "+\n+\n  -";
+        let error = BFError::new(BFErrors::RuntimeError, "Cell went below 0".to_string());
+        let rendered = render_error(Path::new("test.bfem"), src, &error, Some((6, 1).into()));
+
+        assert!(
+            rendered.contains("test.bfem:3:3: Cell went below 0"),
+            "{}",
+            rendered
+        );
+    }
+
+    #[test]
+    fn io_errors_keep_their_message() {
+        let io_error = std::fs::read_to_string("/definitely/not/a/file.bfem").unwrap_err();
+        let message = io_error.to_string();
+        let error = BFError::from(io_error);
+
+        assert!(matches!(error.error, BFErrors::IoError));
+        assert_eq!(error.message, message);
+    }
+}
@@ -0,0 +1,199 @@
+use std::{
+    io::{self, ErrorKind, Read},
+    str::FromStr,
+};
+
+use clap::ValueEnum;
+use getch::Getch;
+
+/// What `,` does with the byte it reads.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum InputMode {
+    /// Store the byte in the current cell
+    Replace,
+    /// Add the byte to the current cell, following the cell mode
+    Add,
+}
+
+/// What `,` does once there is no input left to read.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum EofPolicy {
+    /// Set the current cell to 0
+    Zero,
+    /// Set the current cell to 255, like reading -1
+    Max,
+    /// Leave the current cell as it is
+    Unchanged,
+}
+
+/// A deterministic source of input bytes, used in place of the terminal.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InputPattern {
+    /// Cycle through every byte value, starting from 0.
+    Repeat,
+    /// Pseudo-random bytes generated from the given seed.
+    Seed(u64),
+}
+
+impl FromStr for InputPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "repeat" {
+            return Ok(InputPattern::Repeat);
+        }
+
+        match s.strip_prefix("seed:") {
+            Some(seed) => seed
+                .parse()
+                .map(InputPattern::Seed)
+                .map_err(|_| format!("Invalid seed: {}", seed)),
+            None => Err(format!(
+                "Unknown input pattern {} (expected repeat or seed:N)",
+                s
+            )),
+        }
+    }
+}
+
+/// Where `,` reads its bytes from.
+pub enum Input {
+    /// Read a key at a time from the terminal. The terminal is only put into
+    /// raw mode once the first byte is read.
+    Terminal(Option<Getch>),
+    /// Read from a fixed buffer. The state is the position in the buffer, and
+    /// 0 is read once it runs out.
+    Bytes(Vec<u8>, usize),
+    /// Produce bytes from a pattern. The state is the position in the
+    /// pattern.
+    Pattern(InputPattern, u64),
+}
+
+/// Copies of terminal input open the terminal again when they first read,
+/// rather than sharing it.
+impl Clone for Input {
+    fn clone(&self) -> Self {
+        match self {
+            Input::Terminal(_) => Input::terminal(),
+            Input::Bytes(bytes, position) => Input::Bytes(bytes.clone(), *position),
+            Input::Pattern(pattern, state) => Input::Pattern(*pattern, *state),
+        }
+    }
+}
+
+impl Input {
+    pub fn terminal() -> Self {
+        Input::Terminal(None)
+    }
+
+    pub fn bytes(bytes: &[u8]) -> Self {
+        Input::Bytes(bytes.to_vec(), 0)
+    }
+
+    pub fn pattern(pattern: InputPattern) -> Self {
+        match pattern {
+            InputPattern::Repeat => Input::Pattern(pattern, 0),
+            InputPattern::Seed(seed) => Input::Pattern(pattern, seed),
+        }
+    }
+
+    /// Restore the terminal if it was put into raw mode. It will be set up
+    /// again if more input is read.
+    pub fn release(&mut self) {
+        if let Input::Terminal(getch) = self {
+            *getch = None;
+        }
+    }
+
+    /// Read the next byte, blocking until one is available, or `None` once
+    /// the input has ended.
+    pub fn read(&mut self) -> Option<u8> {
+        match self {
+            Input::Terminal(getch) => {
+                let getch = getch.get_or_insert_with(Getch::new);
+                let result = match getch {
+                    // Raw mode turns Ctrl-D into a byte rather than closing
+                    // the input, so that byte is the end
+                    Getch::Termios(_) => getch.getch().map(|c| Some(c).filter(|c| *c != 4)),
+                    // Not a terminal, so getch would read the end as a 0
+                    Getch::None => {
+                        let mut byte = [0];
+                        io::stdin()
+                            .read(&mut byte)
+                            .map(|read| (read == 1).then_some(byte[0]))
+                    }
+                };
+                match result {
+                    Ok(c) => c,
+                    // Ctrl-C was pressed; the program stops before the next
+                    // instruction
+                    Err(error) if error.kind() == ErrorKind::Interrupted => Some(0),
+                    // Input that can't be read any more has ended too
+                    Err(_) => None,
+                }
+            }
+            Input::Bytes(bytes, position) => {
+                let value = bytes.get(*position).copied();
+                *position += 1;
+                value
+            }
+            Input::Pattern(InputPattern::Repeat, state) => {
+                let value = *state as u8;
+                *state = state.wrapping_add(1);
+                Some(value)
+            }
+            Input::Pattern(InputPattern::Seed(_), state) => {
+                // splitmix64, which is well-behaved for any seed (including 0)
+                *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+                let mut z = *state;
+                z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+                Some((z ^ (z >> 31)) as u8)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(input: &mut Input, count: usize) -> Vec<u8> {
+        (0..count).map(|_| input.read().unwrap()).collect()
+    }
+
+    #[test]
+    fn input_patterns_parse() {
+        assert_eq!("repeat".parse(), Ok(InputPattern::Repeat));
+        assert_eq!("seed:42".parse(), Ok(InputPattern::Seed(42)));
+        assert!("seed:x".parse::<InputPattern>().is_err());
+        assert!("random".parse::<InputPattern>().is_err());
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_bytes() {
+        let first = read(&mut Input::pattern(InputPattern::Seed(7)), 64);
+        let second = read(&mut Input::pattern(InputPattern::Seed(7)), 64);
+        let other = read(&mut Input::pattern(InputPattern::Seed(8)), 64);
+
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+    }
+
+    #[test]
+    fn repeat_cycles_through_every_byte() {
+        let bytes = read(&mut Input::pattern(InputPattern::Repeat), 258);
+
+        assert_eq!(bytes[..3], [0, 1, 2]);
+        assert_eq!(bytes[255..], [255, 0, 1]);
+    }
+
+    #[test]
+    fn bytes_end_once_they_run_out() {
+        let mut input = Input::bytes(&[7]);
+
+        assert_eq!(input.read(), Some(7));
+        assert_eq!(input.read(), None);
+        assert_eq!(input.read(), None);
+    }
+}
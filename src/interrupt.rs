@@ -0,0 +1,36 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, OnceLock,
+};
+
+/// The flag set when Ctrl-C is pressed, once the handler is installed.
+static INTERRUPTED: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+#[cfg(unix)]
+extern "C" fn handle_interrupt(_signal: libc::c_int) {
+    if let Some(flag) = INTERRUPTED.get() {
+        flag.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Catch Ctrl-C instead of letting it kill the process, returning the flag
+/// that is set when it is pressed. Only supported on unix; elsewhere the flag
+/// is never set.
+pub fn install() -> Arc<AtomicBool> {
+    let flag = INTERRUPTED
+        .get_or_init(|| Arc::new(AtomicBool::new(false)))
+        .clone();
+
+    #[cfg(unix)]
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        // No SA_RESTART, so a read blocked on `,` returns and the interrupt
+        // is noticed
+        action.sa_flags = 0;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut());
+    }
+
+    flag
+}
@@ -0,0 +1,81 @@
+pub mod artifact;
+pub mod errors;
+pub mod input;
+pub mod interrupt;
+pub mod parser;
+pub mod profile;
+pub mod program;
+pub mod tape;
+
+use clap::Args;
+use parser::Passes;
+use tape::{Addressing, CellMode, TapeMode};
+
+#[derive(Args, Clone, Copy, Default)]
+pub struct DisableFlags {
+    /// Disable variable aliases
+    #[arg(long)]
+    pub disable_aliases: bool,
+    /// Disable consecutive instruction optimisations
+    #[arg(long)]
+    pub disable_optimise: bool,
+    /// Disable merging runs of the same instruction, keeping other optimisations
+    #[arg(long)]
+    pub disable_merge: bool,
+    /// Disable alias pre-allocation
+    #[arg(long)]
+    pub disable_alloc: bool,
+    /// Keep every `,`, `.` and `?` exactly where it is written, so no pass
+    /// merges, copies or moves them and passes aren't checked for keeping
+    /// them in order
+    #[arg(long = "no-optimize-io-order")]
+    pub disable_io_reorder: bool,
+    /// Only run these optimisation passes, as a comma separated list of
+    /// runlength, clearloop, deadloop, constfold and unroll
+    #[arg(long)]
+    pub passes: Option<Passes>,
+}
+
+#[derive(Args, Clone, Copy)]
+pub struct TapeFlags {
+    #[arg(long, value_enum, default_value_t=TapeMode::Circular)]
+    pub tape_mode: TapeMode,
+    /// What `@N` does past the end of the tape, if not what the tape mode does
+    #[arg(long, value_enum)]
+    pub seek_policy: Option<TapeMode>,
+    #[arg(long, value_enum, default_value_t=CellMode::Circular)]
+    pub cell_mode: CellMode,
+    #[arg(long, default_value_t = 30000)]
+    pub tape_size: u128,
+    /// The value every cell starts with
+    #[arg(long, default_value_t = 0)]
+    pub fill: u8,
+    /// Where `@N` counts cells from, and `?` reports the pointer from, once
+    /// cells are added to the front of an append tape
+    #[arg(long, value_enum, default_value_t = Addressing::Start)]
+    pub addressing: Addressing,
+}
+
+#[derive(Args, Clone, Copy)]
+pub struct ParseFlags {
+    /// Deepest loop nesting allowed
+    #[arg(long, default_value_t = 1000)]
+    pub max_depth: usize,
+    /// Keep `#` comments in the instruction tree instead of dropping them
+    #[arg(long)]
+    pub keep_comments: bool,
+    /// Free aliases first used inside a loop when the loop ends, so other
+    /// loops can use the same name for a cell of their own
+    #[arg(long)]
+    pub scoped_aliases: bool,
+}
+
+impl Default for ParseFlags {
+    fn default() -> Self {
+        Self {
+            max_depth: 1000,
+            keep_comments: false,
+            scoped_aliases: false,
+        }
+    }
+}
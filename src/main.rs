@@ -1,11 +1,20 @@
-mod program;
-mod tape;
-mod errors;
-mod parser;
+use std::{
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    process,
+    time::{Duration, Instant},
+};
 
-use clap::{Parser, Subcommand, Args};
-use program::Program;
-use tape::Tape;
+use bfem::{
+    errors::{self, BFError, ErrorFormat},
+    input, interrupt,
+    parser::{self, Pass, Passes},
+    program::{Indent, Limits, Program},
+    tape::{CellMode, DumpFormat, DumpStyle, Tape, TapeMode},
+    DisableFlags, ParseFlags, TapeFlags,
+};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 
 /// BrainF*ck Easy Mode (BFEM). Brainf*ck with quality-of-life improvements.
 #[derive(Parser)]
@@ -20,6 +29,27 @@ struct Cli {
 
     #[command(flatten)]
     tape_flags: TapeFlags,
+
+    #[command(flatten)]
+    parse_flags: ParseFlags,
+
+    #[command(flatten)]
+    io_flags: IoFlags,
+
+    #[command(flatten)]
+    limit_flags: LimitFlags,
+
+    /// How to write errors to stderr
+    #[arg(long, value_enum, default_value_t = ErrorFormat::Human)]
+    error_format: ErrorFormat,
+
+    /// Don't print informational output such as the instruction tree or info sheet
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Fail with a nonzero exit code if the program has any warnings
+    #[arg(long)]
+    warnings_as_errors: bool,
 }
 
 #[derive(Subcommand)]
@@ -28,68 +58,951 @@ enum Commands {
     Compile(CompileArgs),
     /// Run the given file
     Run(RunArgs),
+    /// Check the given file for errors without running it
+    Check(RunArgs),
+    /// Time running the given file, without showing its output
+    Bench(BenchArgs),
     /// Show a detailed preview of parser info
-    Explain(RunArgs)
+    Explain(ExplainArgs),
+    /// Print the given file reformatted, keeping its comments
+    Fmt(FmtArgs),
+    /// Print the smallest source that runs the same way as the given file
+    Minify(MinifyArgs),
+    /// Convert between plain brainfuck (.bf) and BFEM, choosing the direction from the extensions
+    Convert(ConvertArgs),
+    /// Describe each tape and cell mode
+    ListModes,
 }
 
 #[derive(Args)]
 struct CompileArgs {
     path: std::path::PathBuf,
-    
+
     output: std::path::PathBuf,
 
     /// Output instruction tree (and then exit)
     #[arg(short, long)]
     tree: bool,
+
+    /// Replace aliases with relative moves, failing if any can't be
+    #[arg(long)]
+    relative_moves: bool,
+
+    /// Leave source positions out of a .bfc artifact
+    #[arg(long)]
+    strip: bool,
+
+    /// Print the size the output would be instead of writing it
+    #[arg(long)]
+    dry_run: bool,
 }
 
 #[derive(Args)]
 struct RunArgs {
     path: std::path::PathBuf,
+
+    /// Print the tape around the pointer and every alias's value if the program errors
+    #[arg(long)]
+    dump_on_error: bool,
+
+    /// How cell values are shown when the tape is printed
+    #[arg(long, value_enum, default_value_t = DumpFormat::Dec)]
+    dump_format: DumpFormat,
+
+    /// Fill the tape with the bytes of this file, from cell 0, before running
+    #[arg(long)]
+    seed_tape: Option<PathBuf>,
+
+    /// Print how often each instruction ran and the hottest loops when finished
+    #[arg(long)]
+    profile: bool,
+
+    /// Print the lowest and highest cells the pointer reached, and how many it moved over
+    #[arg(long)]
+    track_pointer: bool,
+
+    /// Also run the program unoptimised, and fail if the output or tape differ
+    #[arg(long)]
+    verify: bool,
+
+    /// Start the pointer on this alias's cell rather than on cell 0
+    #[arg(long)]
+    entry: Option<String>,
+
+    /// Parse the source a chunk at a time rather than reading it all first
+    #[arg(long)]
+    stream: bool,
+
+    /// Start from a tape saved by --save-state, including its modes, instead of a fresh one
+    #[arg(long)]
+    load_state: Option<PathBuf>,
+
+    /// Save the tape's cells, pointer and modes to this file once the run finishes
+    #[arg(long)]
+    save_state: Option<PathBuf>,
 }
 
-#[derive(Args, Clone, Copy)]
-pub struct DisableFlags {
-    /// Disable variable aliases
+#[derive(Args)]
+struct ExplainArgs {
+    path: std::path::PathBuf,
+
+    /// Allocate aliases and show which cell each one landed on
     #[arg(long)]
-    disable_aliases: bool,
-    /// Disable consecutive instruction optimisations
+    trace_aliases: bool,
+
+    /// List the aliases the program declares, without allocating them
     #[arg(long)]
-    disable_optimise: bool,
-    /// Disable alias pre-allocation
+    aliases: bool,
+
+    /// Run the program, then show each byte it wrote with its glyph and hex value
+    #[arg(long)]
+    ascii_table: bool,
+
+    /// Print a JSON map of source ranges to instruction descriptions
     #[arg(long)]
-    disable_alloc: bool,
+    source_map: bool,
+
+    /// Estimate which cells the program can use, without running it
+    #[arg(long)]
+    tape_preview: bool,
+
+    /// Show whether each loop ends its iterations where it began, and how much it changes the cell it tests
+    #[arg(long)]
+    loop_balance: bool,
+
+    /// Estimate the most steps a run can take, without running it
+    #[arg(long)]
+    cost: bool,
+
+    /// Print the whole source with line numbers before the info sheet
+    #[arg(long)]
+    line_numbers: bool,
+
+    /// Print the program's control flow as a Graphviz DOT graph
+    #[arg(long)]
+    graph: bool,
+
+    /// Show what each optimisation pass changed, one pass at a time
+    #[arg(long, visible_alias = "explain-optimizations")]
+    optimisations: bool,
+
+    /// Run this many instructions as written (without optimisation), then show the tape and the instruction that's next
+    #[arg(long)]
+    step: Option<u64>,
+}
+
+#[derive(Args)]
+struct FmtArgs {
+    path: std::path::PathBuf,
+
+    /// How to indent loop bodies: tabs, or spaces:N for N spaces
+    #[arg(long, default_value = "spaces:4")]
+    indent: Indent,
+}
+
+#[derive(Args)]
+struct BenchArgs {
+    path: std::path::PathBuf,
+
+    /// How many times to run the program, resetting it in between
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    iterations: u32,
+}
+
+#[derive(Args)]
+struct MinifyArgs {
+    path: std::path::PathBuf,
+}
+
+#[derive(Args)]
+struct ConvertArgs {
+    path: std::path::PathBuf,
+
+    output: std::path::PathBuf,
+}
+
+#[derive(Args)]
+pub struct IoFlags {
+    /// Read `,` from a deterministic pattern (repeat or seed:N) instead of the terminal
+    #[arg(long)]
+    input_pattern: Option<input::InputPattern>,
+    /// Whether `,` replaces the current cell or adds to it
+    #[arg(long, value_enum, default_value_t = input::InputMode::Replace)]
+    input_mode: input::InputMode,
+    /// What `,` does to the current cell once the input has ended
+    #[arg(long, value_enum, default_value_t = input::EofPolicy::Zero)]
+    eof: input::EofPolicy,
+    /// Print each byte read by `,` as it is read
+    #[arg(long)]
+    echo_input: bool,
+    /// Print a newline after the program's output if it didn't end with one
+    #[arg(long)]
+    ensure_newline: bool,
+    /// Write the bytes output by `.` to this file instead of stdout, exactly
+    /// as they are
+    #[arg(long, conflicts_with_all = ["numeric", "ensure_newline", "charset"])]
+    output_file: Option<PathBuf>,
+    /// Translate each value output by `.` through this file's 256 bytes, one for each value
+    #[arg(long)]
+    charset: Option<PathBuf>,
+    /// Write each byte output by `.` as a decimal number
+    #[arg(long)]
+    numeric: bool,
+    /// Text written after each number in numeric output
+    #[arg(long, default_value = " ", requires = "numeric")]
+    numeric_sep: String,
+    /// Write each number on its own line in numeric output
+    #[arg(long, conflicts_with = "numeric_sep", requires = "numeric")]
+    numeric_newline: bool,
+}
+
+impl IoFlags {
+    /// The separator to write after each number, if output is numeric.
+    fn numeric_separator(&self) -> Option<String> {
+        match (self.numeric, self.numeric_newline) {
+            (false, _) => None,
+            (true, true) => Some("\n".to_string()),
+            (true, false) => Some(self.numeric_sep.clone()),
+        }
+    }
 }
 
 #[derive(Args)]
-pub struct TapeFlags {
-    #[arg(long, value_enum, default_value_t=tape::TapeMode::Circular)]
-    tape_mode: tape::TapeMode,
-    #[arg(long, value_enum, default_value_t=tape::CellMode::Circular)]
-    cell_mode: tape::CellMode,
-    #[arg(long, default_value_t = 30000)]
-    tape_size: u128,
+pub struct LimitFlags {
+    /// Apply default resource limits, for running untrusted programs
+    #[arg(long)]
+    sandbox: bool,
+    /// Stop after this many steps
+    #[arg(long)]
+    max_steps: Option<u64>,
+    /// Stop if the tape grows beyond this many cells
+    #[arg(long)]
+    max_tape: Option<u128>,
+    /// Stop after writing this many bytes of output
+    #[arg(long)]
+    max_output: Option<u64>,
+    /// Stop after running for this many seconds
+    #[arg(long)]
+    time_limit: Option<f64>,
+}
+
+impl LimitFlags {
+    fn limits(&self) -> Limits {
+        let defaults = if self.sandbox {
+            Limits::sandbox()
+        } else {
+            Limits::default()
+        };
+
+        Limits {
+            max_steps: self.max_steps.or(defaults.max_steps),
+            max_tape: self.max_tape.or(defaults.max_tape),
+            max_output: self.max_output.or(defaults.max_output),
+            max_time: self
+                .time_limit
+                .map(Duration::from_secs_f64)
+                .or(defaults.max_time),
+        }
+    }
+}
+
+/// Print `error`, which came from the file at `path`, and exit.
+fn exit_with_error(path: &Path, error: &BFError, error_format: ErrorFormat) -> ! {
+    match error_format {
+        ErrorFormat::Human => eprintln!("{}", errors::render_error(path, "", error, None)),
+        ErrorFormat::Json => eprintln!("{}", errors::render_error_json(path, error, None)),
+    }
+    process::exit(1);
+}
+
+/// Read a tape saved with `--save-state`, reporting any errors and exiting if there are some.
+fn load_state(path: &Path, error_format: ErrorFormat) -> Result<Tape, BFError> {
+    let bytes = fs::read(path).map_err(file_error(path))?;
+    match Tape::from_snapshot(&bytes) {
+        Ok(tape) => Ok(tape),
+        Err(error) => exit_with_error(path, &error, error_format),
+    }
+}
+
+/// `error` from reading or writing the file at `path`, saying which file it
+/// was.
+fn file_error(path: &Path) -> impl Fn(io::Error) -> BFError + '_ {
+    move |error| {
+        let error = BFError::from(error);
+        BFError::new(
+            error.error,
+            format!("{}: {}", path.display(), error.message),
+        )
+    }
+}
+
+/// Parse the file at `path`, printing every problem found and exiting if it
+/// isn't valid. `.bfc` files are loaded as compiled artifacts.
+fn load_program(
+    path: &Path,
+    tape: Tape,
+    flag: DisableFlags,
+    parse_flags: ParseFlags,
+    error_format: ErrorFormat,
+) -> Result<Program, BFError> {
+    let src = fs::read_to_string(path).map_err(file_error(path))?;
+    Ok(load_source(
+        path,
+        src,
+        tape,
+        flag,
+        parse_flags,
+        error_format,
+    ))
+}
+
+/// Load a program from `src`, which was read from `path`, reporting any errors and exiting if there are some.
+fn load_source(
+    path: &Path,
+    src: String,
+    tape: Tape,
+    flag: DisableFlags,
+    parse_flags: ParseFlags,
+    error_format: ErrorFormat,
+) -> Program {
+    let render = |error: &errors::BFError, span: Option<_>| match error_format {
+        ErrorFormat::Human => errors::render_error(path, &src, error, span),
+        ErrorFormat::Json => errors::render_error_json(path, error, span),
+    };
+
+    let mut program = if path.extension().and_then(|ext| ext.to_str()) == Some("bfc") {
+        match Program::from_artifact(path.to_path_buf(), &src, tape, flag, parse_flags) {
+            Ok(program) => program,
+            Err(error) => {
+                eprintln!("{}", render(&error, None));
+                process::exit(1);
+            }
+        }
+    } else {
+        match Program::parse(path.to_path_buf(), src.clone(), tape, flag, parse_flags) {
+            Ok(program) => program,
+            Err(errors) => {
+                for (span, error) in &errors {
+                    eprintln!("{}", render(error, Some(*span)));
+                }
+                if error_format == ErrorFormat::Human {
+                    eprintln!("Found {} error(s)", errors.len());
+                }
+                process::exit(1);
+            }
+        }
+    };
+
+    program.set_error_format(error_format);
+    program
+}
+
+/// Set up `program` and lower it to plain brainfuck, reporting every alias
+/// or seek that can't be lowered and exiting if there are some.
+fn emit_brainfuck(program: &mut Program) -> String {
+    if let Err(error) = program.setup() {
+        program.report_error(&error, None);
+        process::exit(1);
+    }
+
+    match program.emit_brainfuck() {
+        Ok(output) => output,
+        Err(errors) => {
+            for (span, error) in &errors {
+                program.report_error(error, Some(*span));
+            }
+            process::exit(1);
+        }
+    }
+}
+
+/// Exit with an error if any warnings were reported, for `--warnings-as-errors`.
+fn deny_warnings(count: usize) {
+    if count > 0 {
+        eprintln!(
+            "{} warning{} treated as errors",
+            count,
+            if count == 1 { "" } else { "s" }
+        );
+        process::exit(1);
+    }
+}
+
+/// The steps and time each run of `bench` took, then the fastest, median and slowest time and the steps taken in all.
+fn bench_report(runs: &[(u64, Duration)]) -> String {
+    let milliseconds = |time: Duration| format!("{:.3} ms", time.as_secs_f64() * 1000.0);
+    let mut report = String::new();
+    for (index, (steps, time)) in runs.iter().enumerate() {
+        report.push_str(&format!(
+            "Run {}: {} steps in {}\n",
+            index + 1,
+            steps,
+            milliseconds(*time)
+        ));
+    }
+
+    let mut times: Vec<Duration> = runs.iter().map(|(_, time)| *time).collect();
+    times.sort();
+    report.push_str(&format!(
+        "Min {}, median {}, max {}\n",
+        milliseconds(times[0]),
+        milliseconds(times[times.len() / 2]),
+        milliseconds(times[times.len() - 1])
+    ));
+    report.push_str(&format!(
+        "Total: {} steps\n",
+        runs.iter().map(|(steps, _)| steps).sum::<u64>()
+    ));
+    report
+}
+
+/// A section of `list-modes`: each mode's name, help text and example.
+fn describe_modes<T: ValueEnum>(heading: &str, example: fn(&T) -> &'static str) -> String {
+    let rows: Vec<(String, String, &str)> = T::value_variants()
+        .iter()
+        .filter_map(|mode| {
+            let value = mode.to_possible_value()?;
+            let help = value
+                .get_help()
+                .map(|help| help.to_string())
+                .unwrap_or_default();
+            Some((value.get_name().to_string(), help, example(mode)))
+        })
+        .collect();
+    let width = rows
+        .iter()
+        .map(|(name, _, _)| name.len())
+        .max()
+        .unwrap_or(0);
+
+    let mut out = format!("{}\n", heading);
+    for (name, help, example) in rows {
+        out.push_str(&format!("  {:<width$}  {}\n", name, help, width = width));
+        out.push_str(&format!(
+            "  {:<width$}  e.g. {}\n",
+            "",
+            example,
+            width = width
+        ));
+    }
+    out
 }
 
 fn main() {
     let cli = Cli::parse();
+    if let Err(error) = Tape::validate_flags(cli.tape_flags).and_then(|_| run(&cli)) {
+        exit_with_error(Path::new(""), &error, cli.error_format);
+    }
+}
 
+/// Run the command given, returning any error reading or writing a file.
+fn run(cli: &Cli) -> Result<(), BFError> {
     match &cli.command {
         Commands::Compile(args) => {
-            let mut program = Program::read_file(args.path.clone(), Tape::new(cli.tape_flags), cli.disable_flags);
-            
-            println!("{:?}", program.get_instructions());
-        },
+            let mut program = load_program(
+                &args.path,
+                Tape::new(cli.tape_flags),
+                cli.disable_flags,
+                cli.parse_flags,
+                cli.error_format,
+            )?;
+
+            if args.relative_moves {
+                if let Err(error) = program.setup() {
+                    program.report_error(&error, None);
+                    process::exit(1);
+                }
+
+                let errors = program.lower_gotos();
+                for (span, error) in &errors {
+                    program.report_error(error, Some(*span));
+                }
+                if !errors.is_empty() {
+                    process::exit(1);
+                }
+            }
+
+            if args.tree {
+                println!("{:?}", program.get_instructions());
+                return Ok(());
+            }
+
+            let output = match args.output.extension().and_then(|ext| ext.to_str()) {
+                Some("bf") => emit_brainfuck(&mut program),
+                Some("rs") => {
+                    if let Err(error) = program.setup() {
+                        program.report_error(&error, None);
+                        process::exit(1);
+                    }
+                    program.emit_rust()
+                }
+                Some("bfc") => program.to_artifact(args.strip),
+                _ if cli.quiet => return Ok(()),
+                _ => {
+                    println!("{:?}", program.get_instructions());
+                    return Ok(());
+                }
+            };
+
+            if args.dry_run {
+                println!(
+                    "{} bytes, {} instructions",
+                    output.len(),
+                    program.instructions_flat().count()
+                );
+            } else {
+                fs::write(&args.output, output).map_err(file_error(&args.output))?;
+            }
+        }
         Commands::Run(args) => {
-            let mut program = Program::read_file(args.path.clone(), Tape::new(cli.tape_flags), cli.disable_flags);
-            program.setup();
+            let configure = |program: &mut Program| -> Result<(), BFError> {
+                if let Some(pattern) = cli.io_flags.input_pattern {
+                    program.set_input_pattern(pattern);
+                }
+                program.set_input_mode(cli.io_flags.input_mode);
+                program.set_eof_policy(cli.io_flags.eof);
+                if let Some(path) = &cli.io_flags.charset {
+                    let table = fs::read(path).map_err(file_error(path))?;
+                    if let Err(error) = program.set_charset(&table) {
+                        program.report_error(&error, None);
+                        process::exit(1);
+                    }
+                }
+                program.set_limits(cli.limit_flags.limits());
+                if args.seed_tape.is_some() || args.load_state.is_some() {
+                    program.set_preserve_initial_state(true);
+                }
+                if let Err(error) = program.setup() {
+                    program.report_error(&error, None);
+                    process::exit(1);
+                }
+                if let Some(name) = &args.entry {
+                    if let Err(error) = program.set_entry(name) {
+                        program.report_error(&error, None);
+                        process::exit(1);
+                    }
+                }
+                Ok(())
+            };
+
+            let mut tape = match &args.load_state {
+                Some(path) => {
+                    let mut tape = load_state(path, cli.error_format)?;
+                    tape.set_addressing(cli.tape_flags.addressing);
+                    tape
+                }
+                None => Tape::new(cli.tape_flags),
+            };
+            if let Some(path) = &args.seed_tape {
+                let bytes = fs::read(path).map_err(file_error(path))?;
+                // Seeded before parsing, so no loop is unrolled assuming the
+                // cells hold the fill, and before setup, so aliases are put in
+                // cells the seed left free
+                if let Err(error) = tape.load(&bytes) {
+                    exit_with_error(path, &error, cli.error_format);
+                }
+            }
+            let mut program = if args.stream {
+                let file = fs::File::open(&args.path).map_err(file_error(&args.path))?;
+                match Program::parse_stream(
+                    args.path.clone(),
+                    io::BufReader::new(file),
+                    tape.clone(),
+                    cli.disable_flags,
+                    cli.parse_flags,
+                ) {
+                    Ok(mut program) => {
+                        program.set_error_format(cli.error_format);
+                        program
+                    }
+                    // Read the whole source after all, to show the errors in it
+                    Err(_) => load_program(
+                        &args.path,
+                        tape.clone(),
+                        cli.disable_flags,
+                        cli.parse_flags,
+                        cli.error_format,
+                    )?,
+                }
+            } else {
+                load_program(
+                    &args.path,
+                    tape.clone(),
+                    cli.disable_flags,
+                    cli.parse_flags,
+                    cli.error_format,
+                )?
+            };
+            if cli.warnings_as_errors {
+                deny_warnings(program.report_warnings());
+            } else if let Some((span, warning)) = program.emptiness().filter(|_| !cli.quiet) {
+                // Otherwise an empty run gives no sign anything happened
+                program.report_warning(&warning, span);
+            }
+            program.set_echo_input(cli.io_flags.echo_input);
+            program.set_ensure_newline(cli.io_flags.ensure_newline);
+            program.set_numeric_output(cli.io_flags.numeric_separator());
+            if let Some(path) = &cli.io_flags.output_file {
+                if let Err(error) = program.set_output_file(path) {
+                    program.report_error(&error, None);
+                    process::exit(1);
+                }
+            }
+            program.set_interrupt_flag(interrupt::install());
+            program.set_dump_on_error(args.dump_on_error);
+            program.set_dump_format(args.dump_format);
+            program.set_profile(args.profile);
+            program.set_track_pointer(args.track_pointer);
+            if let Err(error) = configure(&mut program) {
+                program.report_error(&error, None);
+                process::exit(1);
+            }
 
+            let save_state = |program: &Program| -> Result<(), BFError> {
+                if let Some(path) = &args.save_state {
+                    fs::write(path, program.tape.to_snapshot()).map_err(file_error(path))?;
+                }
+                Ok(())
+            };
+
+            if !args.verify {
+                program.run();
+                save_state(&program)?;
+                if let Some(report) = program.profile_report() {
+                    eprint!("{}", report);
+                }
+                if let Some(report) = program.pointer_report() {
+                    eprint!("{}", report);
+                }
+                return Ok(());
+            }
+
+            // Both runs need the same input, so the terminal is read up front
+            let input = match cli.io_flags.input_pattern {
+                Some(_) => None,
+                None => {
+                    let mut input = vec![];
+                    io::stdin().read_to_end(&mut input)?;
+                    Some(input)
+                }
+            };
+            let flag = DisableFlags {
+                disable_optimise: true,
+                ..cli.disable_flags
+            };
+            let mut naive =
+                load_program(&args.path, tape, flag, cli.parse_flags, cli.error_format)?;
+            if let Err(error) = configure(&mut naive) {
+                naive.report_error(&error, None);
+                process::exit(1);
+            }
+            for program in [&mut naive, &mut program] {
+                if let Some(input) = &input {
+                    program.set_input_bytes(input);
+                }
+                program.set_log_output(true);
+            }
+            naive.discard_output();
+
+            naive.run();
             program.run();
+            save_state(&program)?;
+            if let Some(report) = program.profile_report() {
+                eprint!("{}", report);
+            }
+            if let Some(report) = program.pointer_report() {
+                eprint!("{}", report);
+            }
+            if let Some(difference) = program.run_difference(&naive) {
+                eprintln!("Optimised and unoptimised runs differ: {}", difference);
+                process::exit(1);
+            }
+        }
+        Commands::Bench(args) => {
+            let mut program = load_program(
+                &args.path,
+                Tape::new(cli.tape_flags),
+                cli.disable_flags,
+                cli.parse_flags,
+                cli.error_format,
+            )?;
+            program.set_input_mode(cli.io_flags.input_mode);
+            program.set_eof_policy(cli.io_flags.eof);
+            program.set_limits(cli.limit_flags.limits());
+            program.set_interrupt_flag(interrupt::install());
+            program.discard_output();
+            // Every run needs the same input, so the terminal is read up front
+            let input = match cli.io_flags.input_pattern {
+                Some(_) => None,
+                None => {
+                    let mut input = vec![];
+                    io::stdin().read_to_end(&mut input)?;
+                    Some(input)
+                }
+            };
+
+            let mut runs = vec![];
+            for _ in 0..args.iterations {
+                // Parsed once, and only reset between runs
+                if let Err(error) = program.reset() {
+                    program.report_error(&error, None);
+                    process::exit(1);
+                }
+                if let Some(pattern) = cli.io_flags.input_pattern {
+                    program.set_input_pattern(pattern);
+                }
+                if let Some(input) = &input {
+                    program.set_input_bytes(input);
+                }
+                let started = Instant::now();
+                program.run();
+                runs.push((program.steps(), started.elapsed()));
+            }
+
+            print!("{}", bench_report(&runs));
+        }
+        Commands::Check(args) => {
+            let program = load_program(
+                &args.path,
+                Tape::new(cli.tape_flags),
+                cli.disable_flags,
+                cli.parse_flags,
+                cli.error_format,
+            )?;
+            let warnings = program.report_warnings();
+            if cli.warnings_as_errors {
+                deny_warnings(warnings);
+            }
         }
         Commands::Explain(args) => {
-            let mut program = Program::read_file(args.path.clone(), Tape::new(cli.tape_flags), cli.disable_flags);
+            // Step n should be the nth instruction as written, not of the optimised program
+            let flag = match args.step {
+                Some(_) => DisableFlags {
+                    disable_optimise: true,
+                    disable_merge: true,
+                    ..cli.disable_flags
+                },
+                None => cli.disable_flags,
+            };
+            let mut program = load_program(
+                &args.path,
+                Tape::new(cli.tape_flags),
+                flag,
+                cli.parse_flags,
+                cli.error_format,
+            )?;
+
+            if args.trace_aliases {
+                if let Err(error) = program.setup() {
+                    program.report_error(&error, None);
+                    process::exit(1);
+                }
+
+                print!("{}", program.alias_table());
+                return Ok(());
+            }
+
+            if args.aliases {
+                print!("{}", program.alias_table());
+                return Ok(());
+            }
+
+            if args.loop_balance {
+                print!("{}", program.loop_balance());
+                return Ok(());
+            }
 
-            program.info();
+            if args.graph {
+                print!("{}", program.control_flow_dot());
+                return Ok(());
+            }
+
+            if args.optimisations {
+                print!("{}", program.optimisation_report());
+                return Ok(());
+            }
+
+            if args.cost {
+                print!("{}", program.cost_report());
+                return Ok(());
+            }
+
+            if args.tape_preview {
+                if let Err(error) = program.setup() {
+                    program.report_error(&error, None);
+                    process::exit(1);
+                }
+
+                print!("{}", program.tape_preview());
+                return Ok(());
+            }
+
+            if let Some(steps) = args.step {
+                if let Err(error) = program.setup() {
+                    program.report_error(&error, None);
+                    process::exit(1);
+                }
+
+                for _ in 0..steps {
+                    match program.step() {
+                        Ok(outcome) if outcome.finished => break,
+                        Ok(_) => (),
+                        Err(error) => {
+                            // The failed step is still the next one
+                            let span = program.next_step().map(|(span, _)| span);
+                            program.report_error(&error, span);
+                            process::exit(1);
+                        }
+                    }
+                }
+
+                print!(
+                    "{}",
+                    program.tape.dump(5, DumpStyle::Plain, DumpFormat::Dec, &[])
+                );
+                match program.next_step() {
+                    Some((span, instruction)) => {
+                        let (line, column) = program.line_col(span);
+                        println!("Next: {} at {}:{}", instruction, line, column);
+                    }
+                    None => println!("Finished"),
+                }
+                return Ok(());
+            }
+
+            if args.source_map {
+                println!("{}", program.source_map_json());
+                return Ok(());
+            }
+
+            if args.ascii_table {
+                if let Some(pattern) = cli.io_flags.input_pattern {
+                    program.set_input_pattern(pattern);
+                }
+                program.set_input_mode(cli.io_flags.input_mode);
+                program.set_eof_policy(cli.io_flags.eof);
+                if let Err(error) = program.setup() {
+                    program.report_error(&error, None);
+                    process::exit(1);
+                }
+
+                program.set_log_output(true);
+                program.run();
+                println!();
+                print!("{}", program.output_log_report());
+                return Ok(());
+            }
+
+            if !cli.quiet {
+                let warnings = program.report_warnings();
+                if cli.warnings_as_errors {
+                    deny_warnings(warnings);
+                }
+                if args.line_numbers {
+                    println!("{}", program.numbered_source());
+                }
+                program.info();
+            }
+        }
+        Commands::Fmt(args) => {
+            // Optimising would change the program's text
+            let flag = DisableFlags {
+                disable_optimise: true,
+                ..cli.disable_flags
+            };
+            let parse_flags = ParseFlags {
+                keep_comments: true,
+                ..cli.parse_flags
+            };
+            let mut program = load_program(
+                &args.path,
+                Tape::new(cli.tape_flags),
+                flag,
+                parse_flags,
+                cli.error_format,
+            )?;
+            program.set_indent(args.indent);
+
+            print!("{}", program.emit_source());
+        }
+        Commands::Minify(args) => {
+            // Unrolling makes the source longer
+            let flag = DisableFlags {
+                passes: Some(
+                    cli.disable_flags
+                        .passes
+                        .unwrap_or_else(Passes::all)
+                        .without(Pass::Unroll),
+                ),
+                ..cli.disable_flags
+            };
+            let program = load_program(
+                &args.path,
+                Tape::new(cli.tape_flags),
+                flag,
+                cli.parse_flags,
+                cli.error_format,
+            )?;
+
+            println!("{}", program.emit_minified());
+        }
+        Commands::ListModes => {
+            print!(
+                "{}",
+                describe_modes::<TapeMode>(
+                    "Tape modes (--tape-mode), for moving off either end of the tape:",
+                    TapeMode::example
+                )
+            );
+            println!();
+            print!(
+                "{}",
+                describe_modes::<CellMode>(
+                    "Cell modes (--cell-mode), for going below 0 or above 255:",
+                    CellMode::example
+                )
+            );
+        }
+        Commands::Convert(args) => {
+            let is_brainfuck =
+                |path: &Path| path.extension().and_then(|ext| ext.to_str()) == Some("bf");
+            let to_brainfuck = is_brainfuck(&args.output);
+
+            // BFEM is written out as it was parsed, so it still reads like the input
+            let mut flag = DisableFlags {
+                disable_optimise: !to_brainfuck,
+                ..cli.disable_flags
+            };
+            let mut program = if is_brainfuck(&args.path) {
+                // Anything in plain brainfuck that isn't a command is a comment
+                flag.disable_aliases = true;
+                let src = fs::read_to_string(&args.path).map_err(file_error(&args.path))?;
+                load_source(
+                    &args.path,
+                    parser::plain_brainfuck(&src),
+                    Tape::new(cli.tape_flags),
+                    flag,
+                    cli.parse_flags,
+                    cli.error_format,
+                )
+            } else {
+                load_program(
+                    &args.path,
+                    Tape::new(cli.tape_flags),
+                    flag,
+                    cli.parse_flags,
+                    cli.error_format,
+                )?
+            };
+
+            let output = if to_brainfuck {
+                emit_brainfuck(&mut program)
+            } else {
+                program.emit_source()
+            };
+            fs::write(&args.output, output).map_err(file_error(&args.output))?;
         }
     }
-}
\ No newline at end of file
+
+    Ok(())
+}
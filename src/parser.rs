@@ -1,175 +1,1222 @@
-use crate::{program::Instruction, DisableFlags};
+use std::{collections::HashSet, io::BufRead, str::FromStr};
+
+use crate::{
+    errors::{BFError, BFErrors},
+    program::Instruction,
+    tape::CellMode,
+    DisableFlags, ParseFlags,
+};
 use miette::SourceSpan;
-use std::collections::HashSet;
 
+/// The most times [`Pass::Unroll`] will copy a loop's body.
+const UNROLL_MAX_TRIPS: u8 = 16;
+/// The most instructions [`Pass::Unroll`] will turn one loop into.
+const UNROLL_MAX_INSTRUCTIONS: usize = 64;
+
+/// A single piece of BFEM syntax. Most are one character, but an alias,
+/// seek or comment token covers the text that goes with it, as does a `+`
+/// or `-` followed by a literal, and a declaration covers the whole
+/// `decl { ... }` block.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Token {
+    Add,
+    Subtract,
+    Right,
+    Left,
+    Output,
+    Input,
+    /// `?`
+    DebugPrintCell,
+    LoopStart,
+    LoopEnd,
+    /// `{name}`
+    Alias,
+    /// `{:name`, which labels the loop that follows
+    LabelStart,
+    /// The `}` closing a label
+    LabelEnd,
+    /// `@N`
+    Seek,
+    /// `#` to the end of the line
+    Comment,
+    /// `decl { ... }`, which isn't started by a single character
+    Declaration,
+}
+
+/// The token a character starts, or the character back if it doesn't start
+/// one.
+impl TryFrom<char> for Token {
+    type Error = char;
+
+    fn try_from(character: char) -> Result<Self, Self::Error> {
+        match character {
+            '+' => Ok(Token::Add),
+            '-' => Ok(Token::Subtract),
+            '>' => Ok(Token::Right),
+            '<' => Ok(Token::Left),
+            '.' => Ok(Token::Output),
+            ',' => Ok(Token::Input),
+            '?' => Ok(Token::DebugPrintCell),
+            '[' => Ok(Token::LoopStart),
+            ']' => Ok(Token::LoopEnd),
+            '{' => Ok(Token::Alias),
+            '}' => Ok(Token::LabelEnd),
+            '@' => Ok(Token::Seek),
+            '#' => Ok(Token::Comment),
+            character => Err(character),
+        }
+    }
+}
+
+/// An optimisation pass. Passes always run in the order listed here, which
+/// must put every pass after the ones it [requires](Pass::requires). No pass
+/// may change the order of input and output; see [`Pass::check_io_order`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Pass {
+    /// Merge runs of the same instruction into one with a count
+    RunLength,
+    /// Replace `[-]` loops with a single `SetZero`
+    ClearLoop,
+    /// Remove loops that can never be entered
+    DeadLoop,
+    /// Fold a `SetZero` and the adds after it into a single `Set`
+    ConstFold,
+    /// Copy out the body of small loops that run a known number of times
+    Unroll,
+}
+
+impl Pass {
+    pub const ALL: [Pass; 5] = [
+        Pass::RunLength,
+        Pass::ClearLoop,
+        Pass::DeadLoop,
+        Pass::ConstFold,
+        Pass::Unroll,
+    ];
+
+    /// The passes that must run before this one, if they run at all.
+    pub fn requires(&self) -> &'static [Pass] {
+        match self {
+            Pass::RunLength | Pass::ClearLoop => &[],
+            // Dead loops are found after the `SetZero` a clear loop becomes,
+            // which is also what constants are folded into
+            Pass::DeadLoop | Pass::ConstFold => &[Pass::ClearLoop],
+            // The trip count is only known after a merged add or `SetZero`,
+            // and the size limit counts merged instructions
+            Pass::Unroll => &[Pass::RunLength, Pass::ClearLoop],
+        }
+    }
+
+    /// Check that `passes` puts each pass after the ones it requires.
+    pub fn check_order(passes: &[Pass]) -> Result<(), String> {
+        for (index, pass) in passes.iter().enumerate() {
+            if let Some(required) = pass
+                .requires()
+                .iter()
+                .find(|required| passes[index..].contains(required))
+            {
+                return Err(format!(
+                    "The {} pass must run after the {} pass",
+                    pass.name(),
+                    required.name()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that this pass, in turning `before` into `after`, kept input and
+    /// output in the order it was written. Loops may be removed or copied out,
+    /// and runs of `.` merged, but nothing else.
+    pub fn check_io_order(
+        &self,
+        before: &[(SourceSpan, Instruction)],
+        after: &[(SourceSpan, Instruction)],
+    ) -> Result<(), String> {
+        check_io_order(self.name(), before, after)
+    }
+
+    /// The name the pass is selected by in `--passes`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Pass::RunLength => "runlength",
+            Pass::ClearLoop => "clearloop",
+            Pass::DeadLoop => "deadloop",
+            Pass::ConstFold => "constfold",
+            Pass::Unroll => "unroll",
+        }
+    }
+
+    /// Run the pass over `instructions`, for a tape with cells in
+    /// `cell_mode` that all hold `fill` when the program starts, if that is
+    /// known. If `keep_io`, input and output are left exactly as written.
+    pub fn run(
+        &self,
+        mut instructions: Vec<(SourceSpan, Instruction)>,
+        cell_mode: CellMode,
+        fill: Option<u8>,
+        keep_io: bool,
+    ) -> Vec<(SourceSpan, Instruction)> {
+        match self {
+            // An add that would overflow does nothing at all in Nothing mode,
+            // so a merged run would stop short where single steps wouldn't
+            Pass::RunLength => Parser::optimise_consecutive(
+                &mut instructions,
+                cell_mode != CellMode::Nothing,
+                !keep_io,
+            ),
+            Pass::ClearLoop => Parser::optimise_clear_loops(instructions),
+            Pass::DeadLoop => Parser::eliminate_dead_loops(instructions),
+            Pass::ConstFold => Parser::fold_constants(instructions),
+            // Only a wrapping cell is sure to count down to exactly 0
+            Pass::Unroll if cell_mode == CellMode::Circular => {
+                Parser::unroll_loops(instructions, fill, keep_io)
+            }
+            Pass::Unroll => instructions,
+        }
+    }
+}
+
+/// Check that the pass called `name`, in turning `before` into `after`, kept
+/// input and output in the order it was written; see [`Pass::check_io_order`].
+fn check_io_order(
+    name: &str,
+    before: &[(SourceSpan, Instruction)],
+    after: &[(SourceSpan, Instruction)],
+) -> Result<(), String> {
+    if IoEvent::matches(&IoEvent::list(before), &IoEvent::list(after)) {
+        Ok(())
+    } else {
+        Err(format!(
+            "The {} pass changed the order of input and output",
+            name
+        ))
+    }
+}
+
+/// Input or output a program does, in the order it is written, for
+/// [`check_io_order`].
+#[derive(Clone, Debug, PartialEq)]
+enum IoEvent {
+    /// The kind of instruction
+    Io(&'static str),
+    /// A loop that does some input or output
+    Loop(Vec<IoEvent>),
+}
+
+impl IoEvent {
+    /// The input and output in `instructions`, with `.` counted once for
+    /// every byte it writes.
+    fn list(instructions: &[(SourceSpan, Instruction)]) -> Vec<IoEvent> {
+        let mut events = vec![];
+        for (_, instruction) in instructions {
+            match instruction {
+                Instruction::Output(count) => {
+                    for _ in 0..*count {
+                        events.push(IoEvent::Io(instruction.kind()));
+                    }
+                }
+                Instruction::Input | Instruction::DebugPrintCell => {
+                    events.push(IoEvent::Io(instruction.kind()));
+                }
+                Instruction::Loop(body) => {
+                    let body = IoEvent::list(body);
+                    if !body.is_empty() {
+                        events.push(IoEvent::Loop(body));
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        events
+    }
+
+    /// Whether `after` does the same input and output as `before`. Each loop
+    /// must be kept, removed or copied out any number of times. The events
+    /// are walked with a stack of positions, each tried once, so this only
+    /// recurses into loop bodies and a long program can't run out of stack.
+    fn matches(before: &[IoEvent], after: &[IoEvent]) -> bool {
+        let mut pending = vec![(0, 0)];
+        let mut tried = HashSet::new();
+        while let Some((index, position)) = pending.pop() {
+            if !tried.insert((index, position)) {
+                continue;
+            }
+
+            match before.get(index) {
+                None if position == after.len() => return true,
+                None => (),
+                Some(IoEvent::Loop(body)) => {
+                    // Removed, or copied out any number of times
+                    pending.push((index + 1, position));
+                    let mut rest = &after[position..];
+                    while let Some(after_copy) = rest.strip_prefix(body.as_slice()) {
+                        rest = after_copy;
+                        pending.push((index + 1, after.len() - rest.len()));
+                    }
+                    // Kept as it is, which is tried first
+                    if let Some(IoEvent::Loop(other)) = after.get(position) {
+                        if IoEvent::matches(body, other) {
+                            pending.push((index + 1, position + 1));
+                        }
+                    }
+                }
+                Some(event) if after.get(position) == Some(event) => {
+                    pending.push((index + 1, position + 1));
+                }
+                Some(_) => (),
+            }
+        }
+
+        false
+    }
+}
+
+/// The instruction tree as it was after a pass, from
+/// [`Parser::pass_snapshots`].
+pub struct PassSnapshot {
+    /// The pass that was just run, or `None` before any have
+    pub pass: Option<Pass>,
+    pub instructions: Vec<(SourceSpan, Instruction)>,
+}
+
+/// A set of optimisation passes, given to `--passes` as a comma separated
+/// list of their names.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Passes(u8);
+
+impl Passes {
+    /// Every pass.
+    pub fn all() -> Self {
+        Passes(
+            Pass::ALL
+                .iter()
+                .fold(0, |passes, pass| passes | 1 << *pass as u8),
+        )
+    }
+
+    pub fn contains(&self, pass: Pass) -> bool {
+        self.0 & (1 << pass as u8) != 0
+    }
+
+    /// These passes, leaving out `pass`.
+    pub fn without(self, pass: Pass) -> Self {
+        Passes(self.0 & !(1 << pass as u8))
+    }
+}
+
+impl FromStr for Passes {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut passes = 0;
+        for name in s.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            let pass = Pass::ALL
+                .into_iter()
+                .find(|pass| pass.name() == name)
+                .ok_or_else(|| {
+                    let names: Vec<&str> = Pass::ALL.iter().map(Pass::name).collect();
+                    format!("Unknown pass {} (expected {})", name, names.join(", "))
+                })?;
+            passes |= 1 << pass as u8;
+        }
+
+        Ok(Passes(passes))
+    }
+}
+
+/// Blank out everything in plain brainfuck source that isn't one of its
+/// eight commands, as brainfuck treats it all as comments. Each removed byte
+/// becomes a space and line breaks are kept, so positions in the result
+/// match the original.
+pub fn plain_brainfuck(src: &str) -> String {
+    src.chars()
+        .map(|character| match character {
+            '+' | '-' | '>' | '<' | '.' | ',' | '[' | ']' | '\n' => character.to_string(),
+            character => " ".repeat(character.len_utf8()),
+        })
+        .collect()
+}
+
+/// How much source [`Parser::parse_stream`] reads before parsing it.
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Brackets and labels still open at the end of a chunk of source, so
+/// validation can carry on where it left off in the next one.
+#[derive(Default)]
+struct ValidateState {
+    open_loops: Vec<usize>,
+    // Each open label's offset, and how many loops were open outside it
+    open_labels: Vec<(usize, usize)>,
+}
+
+impl ValidateState {
+    /// Errors for anything still open once the whole source has been seen.
+    fn finish(self) -> Vec<(SourceSpan, BFError)> {
+        let mut errors: Vec<(SourceSpan, BFError)> = vec![];
+        for offset in self.open_loops {
+            errors.push((
+                (offset, 1).into(),
+                BFError::new(
+                    BFErrors::SyntaxError,
+                    "Loop is never closed with ]".to_string(),
+                ),
+            ));
+        }
+
+        for (offset, _) in self.open_labels {
+            errors.push((
+                (offset, 2).into(),
+                BFError::new(
+                    BFErrors::SyntaxError,
+                    "Label is never closed with }".to_string(),
+                ),
+            ));
+        }
+
+        errors
+    }
+}
+
+#[derive(Clone)]
 pub struct Parser {
+    /// The source being parsed. When streaming this is only the current
+    /// chunk, which starts `base` bytes into the program.
     src: String,
+    base: usize,
     flag: DisableFlags,
-    index: usize,
+    parse_flags: ParseFlags,
+    /// The cell mode the program will run with, which decides what can be
+    /// merged
+    cell_mode: CellMode,
+    /// The value every cell holds when the program starts, if known
+    fill: Option<u8>,
 
-    // Get names
-    aliases: HashSet<String>,
+    // Get names, in the order they are first used
+    aliases: Vec<String>,
+    // Each labelled loop's start offset and its label
+    loop_labels: Vec<(usize, String)>,
+    // Names given in `decl` blocks, in order, and where the first block is
+    declared: Vec<String>,
+    declaration_offset: Option<usize>,
+
+    // Instructions of the innermost open layer, each open loop's start
+    // offset with the layer that contains it, and a label waiting for the
+    // loop it names. Kept between chunks when streaming.
+    instructions: Vec<(SourceSpan, Instruction)>,
+    open_loops: Vec<(usize, Vec<(SourceSpan, Instruction)>)>,
+    label: Option<String>,
+
+    // With scoped aliases, each alias name in scope with the name it was
+    // given in `aliases`, and how many were in scope as each open loop began
+    scope: Vec<(String, String)>,
+    scope_starts: Vec<usize>,
 }
 
 impl Parser {
-    pub fn new(src: String, flag: DisableFlags) -> Self {
+    pub fn new(src: String, flag: DisableFlags, parse_flags: ParseFlags) -> Self {
         Self {
             src,
+            base: 0,
             flag,
-            index: 0 as usize,
-            aliases: HashSet::new(),
+            parse_flags,
+            cell_mode: CellMode::Circular,
+            fill: None,
+            aliases: vec![],
+            loop_labels: vec![],
+            declared: vec![],
+            declaration_offset: None,
+            instructions: vec![],
+            open_loops: vec![],
+            label: None,
+            scope: vec![],
+            scope_starts: vec![],
         }
     }
 
-    pub fn get_aliases(&self) -> &HashSet<String> {
+    /// Optimise for a tape whose cells are in `cell_mode`, rather than
+    /// circular ones.
+    pub fn set_cell_mode(&mut self, cell_mode: CellMode) {
+        self.cell_mode = cell_mode;
+    }
+
+    /// Optimise knowing every cell holds `fill` when the program starts.
+    /// Nothing is assumed about the starting cells otherwise, as a tape may
+    /// be seeded or loaded from a saved state.
+    pub fn set_fill(&mut self, fill: u8) {
+        self.fill = Some(fill);
+    }
+
+    pub fn get_aliases(&self) -> &Vec<String> {
         &self.aliases
     }
 
-    fn parse_one(&mut self) -> (SourceSpan, Instruction) {
-        let mut character = self.src.chars().nth(self.index).unwrap();
-        // Skip whitespaces
-        while character.is_whitespace() {
-            self.index += 1;
-            character = self.src.chars().nth(self.index).unwrap();
-        }
+    /// The label of the loop starting at `offset`, if it has one.
+    pub fn get_loop_label(&self, offset: usize) -> Option<&String> {
+        self.loop_labels
+            .iter()
+            .find(|(start, _)| *start == offset)
+            .map(|(_, label)| label)
+    }
 
-        let start_index = self.index;
-        let instruction = match character {
-            '+' => {
-                self.index += 1;
-                Instruction::Add(1)
-            }
-            '-' => {
-                self.index += 1;
-                Instruction::Subtract(1)
+    /// The names given in `decl` blocks, in the order they were declared.
+    pub fn get_declared(&self) -> &Vec<String> {
+        &self.declared
+    }
+
+    /// The byte offset of the first `decl` block, if there is one.
+    pub fn get_declaration_offset(&self) -> Option<usize> {
+        self.declaration_offset
+    }
+
+    /// Check the source for problems that would stop it parsing, such as
+    /// unbalanced brackets or unknown characters. Every problem found is
+    /// returned rather than just the first.
+    pub fn validate(&self) -> Vec<(SourceSpan, BFError)> {
+        let mut state = ValidateState::default();
+        let mut errors = self.validate_chunk(&mut state);
+        errors.extend(state.finish());
+
+        errors.sort_by_key(|(span, _)| span.offset());
+        errors
+    }
+
+    /// Validate `src` as the next chunk of the program, picking up the
+    /// brackets and labels left open by earlier chunks from `state`.
+    fn validate_chunk(&self, state: &mut ValidateState) -> Vec<(SourceSpan, BFError)> {
+        let mut errors: Vec<(SourceSpan, BFError)> = vec![];
+        let open_loops = &mut state.open_loops;
+        let open_labels = &mut state.open_labels;
+        let mut characters = self.src.char_indices();
+
+        while let Some((offset, character)) = characters.next() {
+            match character {
+                '+' | '-' => {
+                    let literal = Parser::literal_text(&self.src[offset + 1..]);
+                    if !literal.is_empty() {
+                        if let Err(message) = Parser::literal_value(literal) {
+                            errors.push((
+                                (offset + 1, literal.len()).into(),
+                                BFError::new(BFErrors::SyntaxError, message),
+                            ));
+                        }
+                        characters.nth(literal.chars().count() - 1);
+                    }
+                }
+                '>' | '<' | '.' | ',' | '?' => (),
+                '[' => {
+                    open_loops.push(self.base + offset);
+
+                    // Only report the first bracket past the limit in each chain
+                    if open_loops.len() == self.parse_flags.max_depth + 1 {
+                        errors.push((
+                            (offset, 1).into(),
+                            BFError::new(
+                                BFErrors::SyntaxError,
+                                format!(
+                                    "Loops are nested more than {} deep",
+                                    self.parse_flags.max_depth
+                                ),
+                            ),
+                        ));
+                    }
+                }
+                ']' => {
+                    if open_loops.pop().is_none() {
+                        errors.push((
+                            (offset, 1).into(),
+                            BFError::new(
+                                BFErrors::SyntaxError,
+                                "Unmatched ] with no loop to close".to_string(),
+                            ),
+                        ));
+                    }
+                }
+                '{' if !self.flag.disable_aliases && self.src[offset + 1..].starts_with(':') => {
+                    let name = Parser::label_name(&self.src[offset + 2..]);
+                    if name.is_empty() {
+                        errors.push((
+                            (offset, 2).into(),
+                            BFError::new(BFErrors::SyntaxError, "Label name is empty".to_string()),
+                        ));
+                    }
+                    if !self.src[offset + 2 + name.len()..]
+                        .trim_start()
+                        .starts_with('[')
+                    {
+                        errors.push((
+                            (offset, name.len() + 2).into(),
+                            BFError::new(
+                                BFErrors::SyntaxError,
+                                "Label must be followed by a loop".to_string(),
+                            ),
+                        ));
+                    }
+
+                    open_labels.push((self.base + offset, open_loops.len()));
+                    // Skip the : and the name
+                    characters.nth(name.chars().count());
+                }
+                '}' => match open_labels.last() {
+                    Some((_, depth)) if *depth == open_loops.len() => {
+                        open_labels.pop();
+                    }
+                    _ => errors.push((
+                        (offset, 1).into(),
+                        BFError::new(
+                            BFErrors::SyntaxError,
+                            "Unmatched } with no label to close".to_string(),
+                        ),
+                    )),
+                },
+                '{' if !self.flag.disable_aliases => match self.src[offset..].find('}') {
+                    Some(end) => {
+                        let (name, value) =
+                            Parser::split_alias(&self.src[offset + 1..offset + end]);
+                        if name.is_empty() {
+                            errors.push((
+                                (offset, end + 1).into(),
+                                BFError::new(
+                                    BFErrors::SyntaxError,
+                                    "Alias name is empty".to_string(),
+                                ),
+                            ));
+                        }
+                        if let Some(value) = value {
+                            let result = match Parser::literal_text(value) {
+                                literal if literal.len() == value.len() => {
+                                    Parser::literal_value(value).map(|_| ())
+                                }
+                                _ => {
+                                    Err(format!("Expected a number or character, found {}", value))
+                                }
+                            };
+                            if let Err(message) = result {
+                                errors.push((
+                                    (offset, end + 1).into(),
+                                    BFError::new(BFErrors::SyntaxError, message),
+                                ));
+                            }
+                        }
+                        characters.find(|(_, character)| *character == '}');
+                    }
+                    None => {
+                        // Carry on from the next line, so the rest of the
+                        // source is still checked
+                        let end = self.src[offset..]
+                            .find('\n')
+                            .unwrap_or(self.src.len() - offset);
+                        errors.push((
+                            (offset, end).into(),
+                            BFError::new(
+                                BFErrors::SyntaxError,
+                                "Alias is never closed with }".to_string(),
+                            ),
+                        ));
+                        characters.find(|(_, character)| *character == '\n');
+                    }
+                },
+                '{' => {
+                    errors.push((
+                        (offset, 1).into(),
+                        BFError::new(
+                            BFErrors::SyntaxError,
+                            "Aliases are disabled but { was found".to_string(),
+                        ),
+                    ));
+                    // Skip the name so it isn't reported character by character
+                    characters.find(|(_, character)| *character == '}');
+                }
+                '#' => {
+                    // Comments run to the end of the line
+                    characters.find(|(_, character)| *character == '\n');
+                }
+                '@' => {
+                    let digits = Parser::seek_digits(&self.src[offset + 1..]);
+                    if digits.is_empty() {
+                        errors.push((
+                            (offset, 1).into(),
+                            BFError::new(
+                                BFErrors::SyntaxError,
+                                "Expected a cell index after @".to_string(),
+                            ),
+                        ));
+                    } else {
+                        if digits.parse::<u128>().is_err() {
+                            errors.push((
+                                (offset, digits.len() + 1).into(),
+                                BFError::new(
+                                    BFErrors::SyntaxError,
+                                    format!("Cell index {} is too large", digits),
+                                ),
+                            ));
+                        }
+                        characters.nth(digits.len() - 1);
+                    }
+                }
+                'd' if !self.flag.disable_aliases
+                    && open_loops.is_empty()
+                    && Parser::declaration_names(&self.src[offset..]).is_some() =>
+                {
+                    characters.find(|(_, character)| *character == '}');
+                }
+                character if character.is_whitespace() => (),
+                character => errors.push((
+                    (offset, character.len_utf8()).into(),
+                    BFError::new(
+                        BFErrors::SyntaxError,
+                        format!("Unrecognised character: {}", character),
+                    ),
+                )),
             }
-            '>' => {
-                self.index += 1;
-                Instruction::Right(1)
+        }
+
+        errors
+            .into_iter()
+            .map(|(span, error)| ((self.base + span.offset(), span.len()).into(), error))
+            .collect()
+    }
+
+    /// The name of a label at the start of `src`, which follows `{:` and runs
+    /// up to whitespace or the loop.
+    fn label_name(src: &str) -> &str {
+        let end = src
+            .find(|character: char| character.is_whitespace() || character == '[')
+            .unwrap_or(src.len());
+        &src[..end]
+    }
+
+    /// The literal at the start of `src`, following a `+`, `-` or the `=` of
+    /// an alias: decimal digits, `0x` and hex digits, or a character in
+    /// single quotes. Empty if there isn't one.
+    fn literal_text(src: &str) -> &str {
+        let end = if let Some(hex) = src.strip_prefix("0x") {
+            2 + hex
+                .find(|character: char| !character.is_ascii_hexdigit())
+                .unwrap_or(hex.len())
+        } else if let Some(quoted) = src.strip_prefix('\'') {
+            match quoted.chars().next() {
+                Some(character) if quoted[character.len_utf8()..].starts_with('\'') => {
+                    character.len_utf8() + 2
+                }
+                _ => 0,
             }
-            '<' => {
-                self.index += 1;
-                Instruction::Left(1)
+        } else {
+            Parser::seek_digits(src).len()
+        };
+        &src[..end]
+    }
+
+    /// The value of a literal found by `literal_text`, or why it doesn't fit
+    /// in a cell.
+    fn literal_value(literal: &str) -> Result<u8, String> {
+        let value = if let Some(hex) = literal.strip_prefix("0x") {
+            u128::from_str_radix(hex, 16).ok()
+        } else if let Some(quoted) = literal.strip_prefix('\'') {
+            quoted.chars().next().map(|character| character as u128)
+        } else {
+            literal.parse().ok()
+        };
+
+        match value {
+            Some(value) => u8::try_from(value)
+                .map_err(|_| format!("Literal {} doesn't fit in a cell (0 to 255)", literal)),
+            None => Err(format!("Invalid literal {}", literal)),
+        }
+    }
+
+    /// Split the text between an alias's braces into its name and, for
+    /// `{name=value}`, the value to set it to.
+    fn split_alias(text: &str) -> (&str, Option<&str>) {
+        match text.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim())),
+            None => (text.trim(), None),
+        }
+    }
+
+    /// The cell index at the start of `src`, following an `@`.
+    fn seek_digits(src: &str) -> &str {
+        let end = src
+            .find(|character: char| !character.is_ascii_digit())
+            .unwrap_or(src.len());
+        &src[..end]
+    }
+
+    /// If `src` starts with a `decl { a b c }` block, the names it declares
+    /// and how many bytes the block takes up.
+    fn declaration_names(src: &str) -> Option<(Vec<String>, usize)> {
+        let rest = src.strip_prefix("decl")?;
+        let body = rest.trim_start().strip_prefix('{')?;
+        let end = body.find('}')?;
+
+        let names = body[..end].split_whitespace().map(String::from).collect();
+        Some((names, src.len() - body.len() + end + 1))
+    }
+
+    /// Split the source into tokens, each with the span it covers.
+    /// Whitespace is skipped. Anything else that isn't a token is an error in
+    /// `strict` mode, returned with its offset, and skipped otherwise.
+    pub fn tokenize(&self, strict: bool) -> Result<Vec<(SourceSpan, Token)>, (usize, char)> {
+        self.tokenize_from(strict, 0)
+    }
+
+    /// [`Parser::tokenize`] for a chunk that starts `depth` loops deep.
+    fn tokenize_from(
+        &self,
+        strict: bool,
+        mut depth: usize,
+    ) -> Result<Vec<(SourceSpan, Token)>, (usize, char)> {
+        let mut tokens: Vec<(SourceSpan, Token)> = vec![];
+        let mut characters = self.src.char_indices().peekable();
+
+        while let Some((offset, character)) = characters.next() {
+            if character.is_whitespace() {
+                continue;
             }
-            '[' => {
-                self.index += 1;
-                let mut instructions: Vec<(SourceSpan, Instruction)> = vec![];
-                let mut character = self.src.chars().nth(self.index).unwrap();
 
-                // Keep going until we encounter close brackets
-                while character != ']' {
-                    let instruction = self.parse_one();
-                    instructions.push(instruction);
+            let declaration = match character {
+                'd' if !self.flag.disable_aliases && depth == 0 => {
+                    Parser::declaration_names(&self.src[offset..])
+                }
+                _ => None,
+            };
+            let (token, length) = match declaration {
+                Some((_, length)) => (Token::Declaration, length),
+                None => {
+                    let token = match Token::try_from(character) {
+                        Ok(Token::Alias | Token::LabelEnd) if self.flag.disable_aliases => {
+                            Err(character)
+                        }
+                        Ok(Token::Alias) if self.src[offset + 1..].starts_with(':') => {
+                            Ok(Token::LabelStart)
+                        }
+                        token => token,
+                    };
+                    let token = match token {
+                        Ok(token) => token,
+                        Err(character) if strict => return Err((self.base + offset, character)),
+                        Err(_) => continue,
+                    };
 
-                    character = self.src.chars().nth(self.index).unwrap();
+                    let rest = &self.src[offset..];
+                    let length = match token {
+                        Token::Alias => rest.find('}').map_or(rest.len(), |end| end + 1),
+                        Token::LabelStart => Parser::label_name(&rest[2..]).len() + 2,
+                        Token::Seek => Parser::seek_digits(&rest[1..]).len() + 1,
+                        Token::Add | Token::Subtract => Parser::literal_text(&rest[1..]).len() + 1,
+                        // Stop before a CRLF's \r too, so it isn't part of the comment
+                        Token::Comment => rest.find(['\r', '\n']).unwrap_or(rest.len()),
+                        Token::LoopStart => {
+                            depth += 1;
+                            1
+                        }
+                        Token::LoopEnd => {
+                            depth = depth.saturating_sub(1);
+                            1
+                        }
+                        _ => 1,
+                    };
+                    (token, length)
                 }
+            };
 
-                // Skip over end loop
-                self.index += 1;
+            while characters
+                .next_if(|(next, _)| *next < offset + length)
+                .is_some()
+            {}
+            tokens.push(((self.base + offset, length).into(), token));
+        }
 
-                Instruction::Loop(instructions)
+        Ok(tokens)
+    }
+
+    /// Register the names in the `decl { ... }` block at `offset`. Declared
+    /// aliases are allocated in the order given, before any that are only
+    /// used.
+    fn parse_declaration(&mut self, offset: usize) {
+        let (names, _) = Parser::declaration_names(&self.src[offset - self.base..])
+            .expect("Declaration token without a declaration");
+
+        self.declaration_offset.get_or_insert(offset);
+        for name in names {
+            if !self.declared.contains(&name) {
+                self.declared.push(name.clone());
             }
-            '.' => {
-                self.index += 1;
-                Instruction::Output
+            if !self.aliases.contains(&name) {
+                self.aliases.push(name.clone());
+                self.scope.push((name.clone(), name));
             }
-            ',' => {
-                self.index += 1;
-                Instruction::Input
+        }
+    }
+
+    /// The alias `name` refers to where it is used. Aliases are global unless
+    /// they are scoped, when a name that isn't in scope is a new alias, which
+    /// is given a `#N` suffix if the name has been used by another loop.
+    fn resolve_alias(&mut self, name: String) -> String {
+        if !self.parse_flags.scoped_aliases {
+            if !self.aliases.contains(&name) {
+                self.aliases.push(name.clone());
             }
-            '{' if !self.flag.disable_aliases => {
-                self.index += 1;
-                let mut name = String::new();
-                let mut character = self.src.chars().nth(self.index).unwrap();
+            return name;
+        }
 
-                // Keep going until we encounter close brackets
-                while character != '}' {
-                    name.push(character);
-                    self.index += 1;
-                    character = self.src.chars().nth(self.index).unwrap();
-                }
+        if let Some((_, alias)) = self.scope.iter().rev().find(|(used, _)| *used == name) {
+            return alias.clone();
+        }
 
-                // Skip over end loop
-                self.index += 1;
-                self.aliases.insert(name.clone());
-                Instruction::Goto(name)
+        let alias = (1..)
+            .map(|count| match count {
+                1 => name.clone(),
+                count => format!("{}#{}", name, count),
+            })
+            .find(|alias| !self.aliases.contains(alias))
+            .unwrap();
+        self.aliases.push(alias.clone());
+        self.scope.push((name, alias.clone()));
+        alias
+    }
+
+    /// The instruction for a single token other than a loop bracket,
+    /// comment or declaration, which `parse` deals with itself.
+    fn parse_token(&mut self, span: SourceSpan, token: Token) -> Instruction {
+        let text = self.text(span);
+        match token {
+            Token::Add if text.len() > 1 => {
+                Instruction::Add(Parser::literal_value(&text[1..]).unwrap())
             }
-            _ => panic!("Unrecognised character: {}", character),
-        };
+            Token::Subtract if text.len() > 1 => {
+                Instruction::Subtract(Parser::literal_value(&text[1..]).unwrap())
+            }
+            Token::Add => Instruction::Add(1),
+            Token::Subtract => Instruction::Subtract(1),
+            Token::Right => Instruction::Right(1),
+            Token::Left => Instruction::Left(1),
+            Token::Output => Instruction::Output(1),
+            Token::Input => Instruction::Input,
+            Token::DebugPrintCell => Instruction::DebugPrintCell,
+            Token::Seek => Instruction::Seek(text[1..].parse().unwrap()),
+            Token::Alias => {
+                // Whitespace around a name is ignored, so `{ counter }` is the
+                // same alias as `{counter}`. Whitespace inside a name is kept.
+                let (name, _) = Parser::split_alias(&text[1..text.len() - 1]);
+                Instruction::Goto(self.resolve_alias(name.to_string()))
+            }
+            token => panic!("{:?} can't be parsed on its own", token),
+        }
+    }
 
-        ((start_index, self.index - start_index).into(), instruction)
+    /// The source text a token covers.
+    fn text(&self, span: SourceSpan) -> &str {
+        let start = span.offset() - self.base;
+        &self.src[start..start + span.len()]
     }
 
     fn is_instruction_consecutive(instruction: &Instruction) -> bool {
-        match instruction {
+        !matches!(
+            instruction,
             Instruction::Goto(_)
-            | Instruction::Input
-            | Instruction::Output
-            | Instruction::Loop(_) => false,
-            _ => true,
-        }
+                | Instruction::Seek(_)
+                | Instruction::Comment(_)
+                | Instruction::DebugPrintCell
+                | Instruction::Input
+                | Instruction::Loop(_)
+                | Instruction::SetZero
+                | Instruction::Set(_)
+        )
     }
 
-    fn set_count(instruction: &Instruction, count: usize) -> Instruction {
+    /// How much a mergeable instruction does, such as the value an `Add`
+    /// adds.
+    fn amount(instruction: &Instruction) -> u128 {
         match instruction {
-            Instruction::Add(_) => Instruction::Add(count as u8),
-            Instruction::Subtract(_) => Instruction::Subtract(count as u8),
-            Instruction::Left(_) => Instruction::Left(count as u128),
-            Instruction::Right(_) => Instruction::Right(count as u128),
-            _ => instruction.clone(),
+            Instruction::Add(count) | Instruction::Subtract(count) => *count as u128,
+            Instruction::Left(count) | Instruction::Right(count) => *count,
+            Instruction::Output(count) => *count as u128,
+            _ => 1,
         }
     }
 
+    /// Instructions like `instruction` that together do it `count` times.
+    /// Pointer moves fit any count in one instruction. `Add` and `Subtract`
+    /// are split into steps of at most 255 rather than wrapped, as whether a
+    /// cell wraps, saturates or panics depends on the cell mode, which the
+    /// parser doesn't know. `Output` is split at `u64::MAX` in the same way.
+    fn set_count(instruction: &Instruction, count: u128) -> Vec<Instruction> {
+        let max = match instruction {
+            Instruction::Add(_) | Instruction::Subtract(_) => u8::MAX as u128,
+            Instruction::Output(_) => u64::MAX as u128,
+            Instruction::Left(_) | Instruction::Right(_) => u128::MAX,
+            _ => return vec![instruction.clone()],
+        };
+
+        let mut instructions = vec![];
+        let mut left = count;
+        while left > 0 {
+            let step = left.min(max);
+            instructions.push(match instruction {
+                Instruction::Add(_) => Instruction::Add(step as u8),
+                Instruction::Subtract(_) => Instruction::Subtract(step as u8),
+                Instruction::Left(_) => Instruction::Left(step),
+                Instruction::Right(_) => Instruction::Right(step),
+                // Nothing can sit between two merged `.`s, so the cell can't change
+                _ => Instruction::Output(step as u64),
+            });
+            left -= step;
+        }
+
+        instructions
+    }
+
     pub fn is_consecutive_okay(left: &Instruction, right: &Instruction) -> bool {
         Parser::is_instruction_consecutive(left)
             && Parser::is_instruction_consecutive(right)
             && std::mem::discriminant(left) == std::mem::discriminant(right)
     }
 
+    /// Merge runs of the same instruction into one with a count. A merged
+    /// instruction's span runs from the start of the first to the end of the
+    /// last, and every other instruction keeps the span it was parsed with.
+    /// `Add` and `Subtract` are only merged if `merge_arithmetic` is set,
+    /// and `Output` if `merge_output` is.
     pub fn optimise_consecutive(
-        instructions: &mut Vec<(SourceSpan, Instruction)>,
+        instructions: &mut [(SourceSpan, Instruction)],
+        merge_arithmetic: bool,
+        merge_output: bool,
     ) -> Vec<(SourceSpan, Instruction)> {
-        let mut index = 0 as usize;
+        let mut index = 0;
         let mut optimised: Vec<(SourceSpan, Instruction)> = vec![];
 
-        // Must be -1 as we need to not attempt to stretch past the last one
         while index < instructions.len() {
             let mut count = 1;
 
             let (start_span, start_instruction) = instructions[index].clone();
+            let mut amount = Parser::amount(&start_instruction);
             if let Instruction::Loop(mut inner_instructions) = start_instruction {
                 optimised.push((
-                    (start_span.offset(), inner_instructions.len()).into(),
-                    Instruction::Loop(Parser::optimise_consecutive(&mut inner_instructions)),
-                ));
-
-                index += count;
-            } else if let Instruction::Goto(key) = start_instruction {
-                optimised.push((
-                    (start_span.offset(), key.len() + 2).into(),
-                    Instruction::Goto(key),
+                    start_span,
+                    Instruction::Loop(Parser::optimise_consecutive(
+                        &mut inner_instructions,
+                        merge_arithmetic,
+                        merge_output,
+                    )),
                 ));
-
-                index += count;
             } else {
-                while (index + count) < instructions.len() -1
-                {
-                    let (_end_span, mut end_instruction) = instructions[index + count].clone();
-                    if !Parser::is_consecutive_okay(&start_instruction, &end_instruction) {
+                let mergeable = match start_instruction {
+                    Instruction::Add(_) | Instruction::Subtract(_) => merge_arithmetic,
+                    Instruction::Output(_) => merge_output,
+                    _ => true,
+                };
+                while mergeable && (index + count) < instructions.len() {
+                    let (_end_span, end_instruction) = &instructions[index + count];
+                    if !Parser::is_consecutive_okay(&start_instruction, end_instruction) {
                         break;
                     }
+                    amount += Parser::amount(end_instruction);
                     count += 1;
-                    let (_new_end_span, new_end_instruction) = instructions[index + count].clone();
+                }
 
-                    end_instruction = new_end_instruction;
+                let (end_span, _) = instructions[index + count - 1];
+                let end = end_span.offset() + end_span.len();
+                let span: SourceSpan = (start_span.offset(), end - start_span.offset()).into();
+                for instruction in Parser::set_count(&start_instruction, amount) {
+                    optimised.push((span, instruction));
                 }
+            }
 
-                optimised.push((
-                    (start_span.offset(), count).into(),
-                    Parser::set_count(&start_instruction, count),
-                ));
+            index += count;
+        }
+
+        optimised
+    }
+
+    /// Replace `[-]` loops with a single `SetZero`.
+    pub fn optimise_clear_loops(
+        instructions: Vec<(SourceSpan, Instruction)>,
+    ) -> Vec<(SourceSpan, Instruction)> {
+        instructions
+            .into_iter()
+            .map(|(span, instruction)| match instruction {
+                Instruction::Loop(inner_instructions) => {
+                    if let [(_, Instruction::Subtract(1))] = inner_instructions.as_slice() {
+                        (span, Instruction::SetZero)
+                    } else {
+                        (
+                            span,
+                            Instruction::Loop(Parser::optimise_clear_loops(inner_instructions)),
+                        )
+                    }
+                }
+                instruction => (span, instruction),
+            })
+            .collect()
+    }
 
-                index += count;
+    /// Remove loops that can never be entered, because the instruction before
+    /// them guarantees the current cell is zero. This is deliberately
+    /// conservative and only looks at `SetZero` with no pointer move between.
+    pub fn eliminate_dead_loops(
+        instructions: Vec<(SourceSpan, Instruction)>,
+    ) -> Vec<(SourceSpan, Instruction)> {
+        let mut optimised: Vec<(SourceSpan, Instruction)> = vec![];
+
+        for (span, instruction) in instructions {
+            match instruction {
+                Instruction::Loop(_)
+                    if matches!(optimised.last(), Some((_, Instruction::SetZero))) =>
+                {
+                    continue
+                }
+                Instruction::Loop(inner_instructions) => optimised.push((
+                    span,
+                    Instruction::Loop(Parser::eliminate_dead_loops(inner_instructions)),
+                )),
+                instruction => optimised.push((span, instruction)),
+            }
+        }
+
+        optimised
+    }
+
+    /// Replace each `SetZero` and the adds and subtracts straight after it
+    /// with a single `Set` of the value they leave the cell with. Folding
+    /// stops before anything that would take the cell below 0 or above 255,
+    /// as what that does depends on the cell mode.
+    pub fn fold_constants(
+        instructions: Vec<(SourceSpan, Instruction)>,
+    ) -> Vec<(SourceSpan, Instruction)> {
+        let mut optimised: Vec<(SourceSpan, Instruction)> = vec![];
+        let mut instructions = instructions.into_iter().peekable();
+
+        while let Some((span, instruction)) = instructions.next() {
+            match instruction {
+                Instruction::SetZero => {
+                    let mut value: u8 = 0;
+                    let mut end = span.offset() + span.len();
+                    while let Some((next_span, next)) = instructions.peek() {
+                        let folded = match next {
+                            Instruction::Add(count) => value.checked_add(*count),
+                            Instruction::Subtract(count) => value.checked_sub(*count),
+                            _ => None,
+                        };
+                        let Some(folded) = folded else {
+                            break;
+                        };
+                        value = folded;
+                        end = next_span.offset() + next_span.len();
+                        instructions.next();
+                    }
+
+                    let instruction = match value {
+                        0 => Instruction::SetZero,
+                        value => Instruction::Set(value),
+                    };
+                    optimised.push(((span.offset(), end - span.offset()).into(), instruction));
+                }
+                Instruction::Loop(inner_instructions) => optimised.push((
+                    span,
+                    Instruction::Loop(Parser::fold_constants(inner_instructions)),
+                )),
+                instruction => optimised.push((span, instruction)),
+            }
+        }
+
+        optimised
+    }
+
+    /// If `body` is a loop body that only ever changes the cell the loop
+    /// tests by taking 1 from it, either with a single `-` or with adds that
+    /// wrap round to the same thing, and leaves the pointer where it found
+    /// it, so the loop runs exactly as many times as that cell's value.
+    /// Bodies that both add to and subtract from the cell, like `--+`, aren't
+    /// counted.
+    fn is_countdown(body: &[(SourceSpan, Instruction)]) -> bool {
+        let mut offset: i128 = 0;
+        let mut added: u128 = 0;
+        let mut subtracted: u128 = 0;
+        for (_, instruction) in body {
+            match instruction {
+                Instruction::Left(count) => offset -= *count as i128,
+                Instruction::Right(count) => offset += *count as i128,
+                Instruction::Add(count) if offset == 0 => added += *count as u128,
+                Instruction::Subtract(count) if offset == 0 => subtracted += *count as u128,
+                Instruction::SetZero | Instruction::Set(_) | Instruction::Input if offset == 0 => {
+                    return false
+                }
+                Instruction::Loop(_) | Instruction::Goto(_) | Instruction::Seek(_) => return false,
+                _ => (),
+            }
+        }
+
+        offset == 0
+            && match (added, subtracted) {
+                (0, subtracted) => subtracted == 1,
+                (added, 0) => added % 256 == 255,
+                _ => false,
+            }
+    }
+
+    /// Replace loops that count a circular cell with a known small value
+    /// down to zero with that many copies of their body. The value is only
+    /// known after `SetZero`, `Set` or a loop (which always leaves the cell
+    /// at 0) and any adds or subtracts straight after, or at the start of the
+    /// program if `start` gives the value every cell starts with. If
+    /// `keep_io`, loops that do input or output are left alone.
+    pub fn unroll_loops(
+        instructions: Vec<(SourceSpan, Instruction)>,
+        start: Option<u8>,
+        keep_io: bool,
+    ) -> Vec<(SourceSpan, Instruction)> {
+        let mut optimised: Vec<(SourceSpan, Instruction)> = vec![];
+        // The value of the cell under the pointer, if it is known
+        let mut known = start;
+
+        for (span, instruction) in instructions {
+            match instruction {
+                Instruction::Add(count) => {
+                    known = known.map(|value| value.wrapping_add(count));
+                    optimised.push((span, instruction));
+                }
+                Instruction::Subtract(count) => {
+                    known = known.map(|value| value.wrapping_sub(count));
+                    optimised.push((span, instruction));
+                }
+                Instruction::SetZero => {
+                    known = Some(0);
+                    optimised.push((span, instruction));
+                }
+                Instruction::Set(value) => {
+                    known = Some(value);
+                    optimised.push((span, instruction));
+                }
+                Instruction::Loop(body) => {
+                    let trips = known.unwrap_or(0);
+                    if trips > 0
+                        && trips <= UNROLL_MAX_TRIPS
+                        && body.len() * trips as usize <= UNROLL_MAX_INSTRUCTIONS
+                        && Parser::is_countdown(&body)
+                        && (!keep_io || IoEvent::list(&body).is_empty())
+                    {
+                        for _ in 0..trips {
+                            optimised.extend(body.iter().cloned());
+                        }
+                    } else {
+                        optimised.push((
+                            span,
+                            Instruction::Loop(Parser::unroll_loops(body, None, keep_io)),
+                        ));
+                    }
+                    // Loops only finish once the cell is 0
+                    known = Some(0);
+                }
+                Instruction::Output(_) | Instruction::DebugPrintCell | Instruction::Comment(_) => {
+                    optimised.push((span, instruction));
+                }
+                instruction => {
+                    known = None;
+                    optimised.push((span, instruction));
+                }
             }
         }
 
@@ -177,17 +1224,798 @@ impl Parser {
     }
 
     pub fn parse(&mut self) -> Vec<(SourceSpan, Instruction)> {
-        let mut instructions: Vec<(SourceSpan, Instruction)> = vec![];
+        self.parse_chunk();
+        self.finish()
+    }
+
+    /// Parse `src` as the next chunk of the program, adding to the
+    /// instructions and open loops left by earlier chunks.
+    fn parse_chunk(&mut self) {
+        let tokens = self
+            .tokenize_from(false, self.open_loops.len())
+            .expect("Tokenizing can't fail outside strict mode");
+        for (span, token) in tokens {
+            match token {
+                Token::LoopStart => {
+                    if let Some(label) = self.label.take() {
+                        self.loop_labels.push((span.offset(), label));
+                    }
+                    let outer = std::mem::take(&mut self.instructions);
+                    self.open_loops.push((span.offset(), outer));
+                    self.scope_starts.push(self.scope.len());
+                }
+                Token::LabelStart => {
+                    self.label = Some(self.text(span)[2..].to_string());
+                }
+                // Labels only name their loop, so where they end doesn't matter
+                Token::LabelEnd => (),
+                Token::LoopEnd => {
+                    let (start_offset, outer) = self.open_loops.pop().expect("Unmatched ]");
+                    // Aliases first used in the loop go out of scope with it
+                    let scope_start = self.scope_starts.pop().expect("Unmatched ]");
+                    self.scope.truncate(scope_start);
+                    let body = std::mem::replace(&mut self.instructions, outer);
+                    self.instructions.push((
+                        (start_offset, span.offset() + 1 - start_offset).into(),
+                        Instruction::Loop(body),
+                    ));
+                }
+                Token::Alias => {
+                    let instruction = self.parse_token(span, token);
+                    self.instructions.push((span, instruction));
 
-        while self.index < self.src.len() {
-            let instruction = self.parse_one();
-            instructions.push(instruction);
+                    // `{name=value}` sets the cell as well as moving to it
+                    let text = self.text(span);
+                    if let (_, Some(value)) = Parser::split_alias(&text[1..text.len() - 1]) {
+                        let value = Parser::literal_value(value).unwrap();
+                        self.instructions.push((span, Instruction::SetZero));
+                        if value != 0 {
+                            self.instructions.push((span, Instruction::Add(value)));
+                        }
+                    }
+                }
+                Token::Declaration => self.parse_declaration(span.offset()),
+                Token::Comment => {
+                    if self.parse_flags.keep_comments {
+                        let text = self.text(span)[1..].trim().to_string();
+                        self.instructions.push((span, Instruction::Comment(text)));
+                    }
+                }
+                token => {
+                    let instruction = self.parse_token(span, token);
+                    self.instructions.push((span, instruction));
+                }
+            }
         }
+    }
 
-        if !self.flag.disable_optimise {
-            instructions = Parser::optimise_consecutive(&mut instructions);
+    /// Run the optimisation passes over everything parsed, once the last
+    /// chunk is in.
+    fn finish(&mut self) -> Vec<(SourceSpan, Instruction)> {
+        let mut instructions = std::mem::take(&mut self.instructions);
+        for pass in self.passes() {
+            instructions = self.run_pass(pass, instructions);
         }
 
         instructions
     }
+
+    /// Run `pass` over `instructions` for the tape the program runs on.
+    fn run_pass(
+        &self,
+        pass: Pass,
+        instructions: Vec<(SourceSpan, Instruction)>,
+    ) -> Vec<(SourceSpan, Instruction)> {
+        let keep_io = self.flag.disable_io_reorder;
+        self.apply_pass(pass.name(), instructions, |instructions| {
+            pass.run(instructions, self.cell_mode, self.fill, keep_io)
+        })
+    }
+
+    /// Run `optimise`, the pass called `name`, over `instructions`. In debug
+    /// builds the order of input and output is checked afterwards, and a
+    /// pass that changed it panics. With `--no-optimize-io-order` passes
+    /// leave input and output alone, so there is nothing to check.
+    pub fn apply_pass(
+        &self,
+        name: &str,
+        instructions: Vec<(SourceSpan, Instruction)>,
+        optimise: impl FnOnce(Vec<(SourceSpan, Instruction)>) -> Vec<(SourceSpan, Instruction)>,
+    ) -> Vec<(SourceSpan, Instruction)> {
+        if self.flag.disable_io_reorder || !cfg!(debug_assertions) {
+            return optimise(instructions);
+        }
+
+        let optimised = optimise(instructions.clone());
+        if let Err(message) = check_io_order(name, &instructions, &optimised) {
+            panic!("{}", message);
+        }
+        optimised
+    }
+
+    /// Parse the source again, keeping the tree before any optimisation and
+    /// after each pass the flags ask for. The first snapshot, with no pass,
+    /// is the unoptimised tree.
+    pub fn pass_snapshots(&self) -> Vec<PassSnapshot> {
+        let mut parser = Parser::new(self.src.clone(), self.flag, self.parse_flags);
+        parser.cell_mode = self.cell_mode;
+        parser.fill = self.fill;
+        parser.parse_chunk();
+
+        let mut snapshots = vec![PassSnapshot {
+            pass: None,
+            instructions: std::mem::take(&mut parser.instructions),
+        }];
+        for pass in self.passes() {
+            let before = snapshots.last().unwrap().instructions.clone();
+            snapshots.push(PassSnapshot {
+                pass: Some(pass),
+                instructions: self.run_pass(pass, before),
+            });
+        }
+
+        snapshots
+    }
+
+    /// Whether `src` can be parsed as a chunk without cutting a token in
+    /// two: it mustn't end inside an alias or `decl` block, or between a
+    /// label and the loop it names.
+    fn chunk_is_complete(&self) -> bool {
+        if self.src.trim_end().ends_with("decl") {
+            return false;
+        }
+
+        let tokens = self
+            .tokenize_from(false, self.open_loops.len())
+            .expect("Tokenizing can't fail outside strict mode");
+        match tokens.last() {
+            Some((_, Token::LabelStart)) => false,
+            Some((span, Token::Alias)) => self.text(*span).ends_with('}'),
+            _ => true,
+        }
+    }
+
+    /// Read a program from `reader` a chunk of lines at a time, validating
+    /// and parsing each chunk as it arrives rather than holding all of the
+    /// source at once. This takes the place of the source given to
+    /// [`Parser::new`], and spans are still offsets into the whole program.
+    /// Like [`Parser::validate`], every problem found is returned, including
+    /// any read error. If there are none, [`Parser::parse`] then gives the
+    /// instructions.
+    pub fn parse_stream(&mut self, mut reader: impl BufRead) -> Vec<(SourceSpan, BFError)> {
+        let mut state = ValidateState::default();
+        let mut errors: Vec<(SourceSpan, BFError)> = vec![];
+        self.src.clear();
+
+        loop {
+            let read = match reader.read_line(&mut self.src) {
+                Ok(read) => read,
+                Err(error) => {
+                    errors.push(((self.base + self.src.len(), 0).into(), error.into()));
+                    return errors;
+                }
+            };
+            let finished = read == 0;
+            if !finished && (self.src.len() < STREAM_CHUNK_SIZE || !self.chunk_is_complete()) {
+                continue;
+            }
+
+            errors.extend(self.validate_chunk(&mut state));
+            // Parsing assumes the source is valid, so once there are errors
+            // the rest is only validated
+            if errors.is_empty() {
+                self.parse_chunk();
+            }
+            self.base += self.src.len();
+            self.src.clear();
+
+            if finished {
+                break;
+            }
+        }
+
+        errors.extend(state.finish());
+        errors.sort_by_key(|(span, _)| span.offset());
+        errors
+    }
+
+    /// The optimisation passes the flags ask for, in the order they run.
+    pub fn passes(&self) -> Vec<Pass> {
+        if self.flag.disable_optimise {
+            return vec![];
+        }
+
+        debug_assert_eq!(Pass::check_order(&Pass::ALL), Ok(()));
+        Pass::ALL
+            .into_iter()
+            .filter(|pass| self.flag.passes.is_none_or(|passes| passes.contains(*pass)))
+            .filter(|pass| !(self.flag.disable_merge && *pass == Pass::RunLength))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `instructions` without their spans, which are zeroed inside loops.
+    fn strip(instructions: Vec<(SourceSpan, Instruction)>) -> Vec<Instruction> {
+        instructions
+            .into_iter()
+            .map(|(_, instruction)| match instruction {
+                Instruction::Loop(body) => looped(strip(body)),
+                instruction => instruction,
+            })
+            .collect()
+    }
+
+    /// A loop of `body`, with spans zeroed like `strip` leaves them.
+    fn looped(body: Vec<Instruction>) -> Instruction {
+        Instruction::Loop(
+            body.into_iter()
+                .map(|instruction| ((0, 0).into(), instruction))
+                .collect(),
+        )
+    }
+
+    fn parse_with(src: &str, flag: DisableFlags) -> Vec<Instruction> {
+        let mut parser = Parser::new(src.to_string(), flag, ParseFlags::default());
+        let errors = parser.validate();
+        assert!(
+            errors.is_empty(),
+            "{} failed to validate: {:?}",
+            src,
+            errors
+        );
+        strip(parser.parse())
+    }
+
+    fn parse(src: &str) -> Vec<Instruction> {
+        parse_with(src, DisableFlags::default())
+    }
+
+    #[test]
+    fn loops_after_a_clear_are_dropped() {
+        assert_eq!(parse("[-][>+<]"), vec![Instruction::SetZero]);
+    }
+
+    #[test]
+    fn loops_after_a_move_are_kept() {
+        assert_eq!(
+            parse("[-]>[-<+>]"),
+            vec![
+                Instruction::SetZero,
+                Instruction::Right(1),
+                looped(vec![
+                    Instruction::Subtract(1),
+                    Instruction::Left(1),
+                    Instruction::Add(1),
+                    Instruction::Right(1),
+                ]),
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_reports_every_error() {
+        let parser = Parser::new(
+            "+]\n{a\n[".to_string(),
+            DisableFlags::default(),
+            ParseFlags::default(),
+        );
+        let messages: Vec<String> = parser
+            .validate()
+            .into_iter()
+            .map(|(_, error)| error.message)
+            .collect();
+
+        assert_eq!(
+            messages,
+            [
+                "Unmatched ] with no loop to close",
+                "Alias is never closed with }",
+                "Loop is never closed with ]",
+            ]
+        );
+    }
+
+    #[test]
+    fn deep_nesting_reports_the_first_bracket_past_the_limit() {
+        let src = format!("{}{}", "[".repeat(100_000), "]".repeat(100_000));
+        let parser = Parser::new(src, DisableFlags::default(), ParseFlags::default());
+        let errors = parser.validate();
+
+        assert_eq!(errors.len(), 1);
+        let (span, error) = &errors[0];
+        assert_eq!(span.offset(), 1000);
+        assert_eq!(error.message, "Loops are nested more than 1000 deep");
+    }
+
+    #[test]
+    fn many_sibling_loops_keep_their_nesting() {
+        let instructions = parse(&",[>[<]]".repeat(2_000));
+        let expected = [
+            Instruction::Input,
+            looped(vec![
+                Instruction::Right(1),
+                looped(vec![Instruction::Left(1)]),
+            ]),
+        ];
+
+        assert_eq!(instructions.len(), 4_000);
+        for pair in instructions.chunks(2) {
+            assert_eq!(pair, expected);
+        }
+    }
+
+    #[test]
+    fn outputs_only_merge_while_the_cell_is_unchanged() {
+        assert_eq!(
+            parse(".....+.."),
+            vec![
+                Instruction::Output(5),
+                Instruction::Add(1),
+                Instruction::Output(2),
+            ]
+        );
+    }
+
+    #[test]
+    fn disable_merge_keeps_runs_but_still_clears() {
+        let flag = DisableFlags {
+            disable_merge: true,
+            ..DisableFlags::default()
+        };
+
+        assert_eq!(
+            parse_with("+++>>[-]", flag),
+            vec![
+                Instruction::Add(1),
+                Instruction::Add(1),
+                Instruction::Add(1),
+                Instruction::Right(1),
+                Instruction::Right(1),
+                Instruction::SetZero,
+            ]
+        );
+    }
+
+    #[test]
+    fn characters_map_to_tokens() {
+        let expected = [
+            ('+', Token::Add),
+            ('-', Token::Subtract),
+            ('>', Token::Right),
+            ('<', Token::Left),
+            ('.', Token::Output),
+            (',', Token::Input),
+            ('?', Token::DebugPrintCell),
+            ('[', Token::LoopStart),
+            (']', Token::LoopEnd),
+            ('{', Token::Alias),
+            ('}', Token::LabelEnd),
+            ('@', Token::Seek),
+            ('#', Token::Comment),
+        ];
+        for (character, token) in expected {
+            assert_eq!(Token::try_from(character), Ok(token));
+        }
+        assert_eq!(Token::try_from('x'), Err('x'));
+    }
+
+    #[test]
+    fn tokens_cover_their_text() {
+        let parser = Parser::new(
+            "+ {a}@12 # hi\nx]".to_string(),
+            DisableFlags::default(),
+            ParseFlags::default(),
+        );
+        let spans = |tokens: Vec<(SourceSpan, Token)>| -> Vec<(usize, usize, Token)> {
+            tokens
+                .into_iter()
+                .map(|(span, token)| (span.offset(), span.len(), token))
+                .collect()
+        };
+
+        assert_eq!(
+            spans(parser.tokenize(false).unwrap()),
+            [
+                (0, 1, Token::Add),
+                (2, 3, Token::Alias),
+                (5, 3, Token::Seek),
+                (9, 4, Token::Comment),
+                (15, 1, Token::LoopEnd),
+            ]
+        );
+        assert_eq!(parser.tokenize(true).unwrap_err(), (14, 'x'));
+    }
+
+    #[test]
+    fn alias_braces_are_an_error_when_aliases_are_disabled() {
+        let flag = DisableFlags {
+            disable_aliases: true,
+            ..DisableFlags::default()
+        };
+        let parser = Parser::new("+{x}+".to_string(), flag, ParseFlags::default());
+        let errors = parser.validate();
+
+        assert_eq!(errors.len(), 1);
+        let (span, error) = &errors[0];
+        assert_eq!(span.offset(), 1);
+        assert_eq!(error.message, "Aliases are disabled but { was found");
+    }
+
+    #[test]
+    fn crlf_line_endings_stay_out_of_comments_and_aliases() {
+        let parse_flags = ParseFlags {
+            keep_comments: true,
+            ..ParseFlags::default()
+        };
+        let mut parser = Parser::new(
+            "+ # hi\r\n{ name\r\n}+\r\n".to_string(),
+            DisableFlags::default(),
+            parse_flags,
+        );
+        assert!(parser.validate().is_empty());
+        let instructions: Vec<(usize, usize, Instruction)> = parser
+            .parse()
+            .into_iter()
+            .map(|(span, instruction)| (span.offset(), span.len(), instruction))
+            .collect();
+
+        assert_eq!(
+            instructions,
+            [
+                (0, 1, Instruction::Add(1)),
+                (2, 4, Instruction::Comment("hi".to_string())),
+                (8, 9, Instruction::Goto("name".to_string())),
+                (17, 1, Instruction::Add(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn only_the_chosen_passes_run() {
+        let flag = DisableFlags {
+            passes: Some("clearloop".parse().unwrap()),
+            ..DisableFlags::default()
+        };
+
+        assert_eq!(
+            parse_with("++[-]>>[-][-]", flag),
+            vec![
+                Instruction::Add(1),
+                Instruction::Add(1),
+                Instruction::SetZero,
+                Instruction::Right(1),
+                Instruction::Right(1),
+                Instruction::SetZero,
+                Instruction::SetZero,
+            ]
+        );
+        assert_eq!(
+            "clearloop, runlength".parse::<Passes>().unwrap(),
+            Passes::all()
+                .without(Pass::DeadLoop)
+                .without(Pass::ConstFold)
+                .without(Pass::Unroll)
+        );
+        assert!("clearloop,fold".parse::<Passes>().is_err());
+    }
+
+    #[test]
+    fn optimised_spans_cover_their_source() {
+        let src = ",[ ->\n+< ]{counter}++ +";
+        let mut parser = Parser::new(
+            src.to_string(),
+            DisableFlags::default(),
+            ParseFlags::default(),
+        );
+        assert!(parser.validate().is_empty());
+        let spans: Vec<&str> = parser
+            .parse()
+            .iter()
+            .map(|(span, _)| &src[span.offset()..span.offset() + span.len()])
+            .collect();
+
+        assert_eq!(spans, [",", "[ ->\n+< ]", "{counter}", "++ +"]);
+    }
+
+    #[test]
+    fn plain_brainfuck_blanks_everything_but_commands() {
+        assert_eq!(plain_brainfuck("a{b}+\n#é."), "    +\n   .");
+    }
+
+    #[test]
+    fn set_count_splits_at_each_variants_limit() {
+        use Instruction::*;
+
+        assert!(Parser::set_count(&Add(1), 0).is_empty());
+        assert_eq!(Parser::set_count(&Add(1), 255), [Add(255)]);
+        assert_eq!(Parser::set_count(&Add(1), 256), [Add(255), Add(1)]);
+        assert_eq!(
+            Parser::set_count(&Add(1), 511),
+            [Add(255), Add(255), Add(1)]
+        );
+        assert!(Parser::set_count(&Subtract(1), 0).is_empty());
+        assert_eq!(Parser::set_count(&Subtract(1), 255), [Subtract(255)]);
+        assert_eq!(
+            Parser::set_count(&Subtract(1), 256),
+            [Subtract(255), Subtract(1)]
+        );
+
+        for (count, split) in [(0, vec![]), (255, vec![255]), (256, vec![256])] {
+            let lefts: Vec<_> = split.iter().map(|step| Left(*step)).collect();
+            let rights: Vec<_> = split.iter().map(|step| Right(*step)).collect();
+            assert_eq!(Parser::set_count(&Left(1), count), lefts);
+            assert_eq!(Parser::set_count(&Right(1), count), rights);
+        }
+        assert_eq!(Parser::set_count(&Left(1), u128::MAX), [Left(u128::MAX)]);
+        assert_eq!(Parser::set_count(&Right(1), u128::MAX), [Right(u128::MAX)]);
+
+        assert!(Parser::set_count(&Output(1), 0).is_empty());
+        assert_eq!(Parser::set_count(&Output(1), 256), [Output(256)]);
+        assert_eq!(
+            Parser::set_count(&Output(1), u64::MAX as u128),
+            [Output(u64::MAX)]
+        );
+        assert_eq!(
+            Parser::set_count(&Output(1), u64::MAX as u128 + 1),
+            [Output(u64::MAX), Output(1)]
+        );
+    }
+
+    #[test]
+    fn streaming_matches_parsing_in_memory() {
+        // Loops and aliases span lines, and the whole is several chunks long
+        let line = "{total}+[\n->{x}++<]{x}-.\n";
+        let src = line.repeat(STREAM_CHUNK_SIZE * 3 / line.len());
+
+        let mut parser = Parser::new(src.clone(), DisableFlags::default(), ParseFlags::default());
+        assert!(parser.validate().is_empty());
+        let whole = parser.parse();
+
+        let mut parser = Parser::new(
+            String::new(),
+            DisableFlags::default(),
+            ParseFlags::default(),
+        );
+        let errors = parser.parse_stream(src.as_bytes());
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(parser.parse(), whole);
+
+        let mut parser = Parser::new(
+            String::new(),
+            DisableFlags::default(),
+            ParseFlags::default(),
+        );
+        let errors = parser.parse_stream(format!("{}[", src).as_bytes());
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0.offset(), src.len());
+    }
+
+    #[test]
+    fn validation_carries_on_after_an_unclosed_alias() {
+        let parser = Parser::new(
+            "{a+\n]\n{b".to_string(),
+            DisableFlags::default(),
+            ParseFlags::default(),
+        );
+        let errors: Vec<(usize, usize, String)> = parser
+            .validate()
+            .into_iter()
+            .map(|(span, error)| (span.offset(), span.len(), error.message))
+            .collect();
+
+        assert_eq!(
+            errors,
+            [
+                (0, 3, "Alias is never closed with }".to_string()),
+                (4, 1, "Unmatched ] with no loop to close".to_string()),
+                (6, 2, "Alias is never closed with }".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn loops_with_a_known_trip_count_are_unrolled() {
+        use Instruction::*;
+
+        let unrolled = |src: &str, cell_mode: CellMode, fill: Option<u8>| {
+            let mut parser = Parser::new(
+                src.to_string(),
+                DisableFlags::default(),
+                ParseFlags::default(),
+            );
+            parser.set_cell_mode(cell_mode);
+            if let Some(fill) = fill {
+                parser.set_fill(fill);
+            }
+            let instructions = strip(parser.parse());
+            !instructions
+                .iter()
+                .any(|instruction| matches!(instruction, Instruction::Loop(_)))
+        };
+
+        let mut parser = Parser::new(
+            "+++[>+<-]".to_string(),
+            DisableFlags::default(),
+            ParseFlags::default(),
+        );
+        parser.set_fill(0);
+        let body = [Right(1), Add(1), Left(1), Subtract(1)];
+        assert_eq!(
+            strip(parser.parse()),
+            [&[Add(3)][..], &body, &body, &body].concat()
+        );
+
+        // The starting cells are only known if the parser is told
+        assert!(!unrolled("+++[>+<-]", CellMode::Circular, None));
+        assert!(unrolled("[-]+++[>+<-]", CellMode::Circular, None));
+        assert!(unrolled(",[-]++[>+<-]", CellMode::Circular, None));
+        let adds = format!(",[-]++[>+<{}]", "+".repeat(255));
+        assert!(unrolled(&adds, CellMode::Circular, None));
+
+        // Bodies have to count the cell down by exactly one each time
+        assert!(!unrolled("[-]++[>+<--+]", CellMode::Circular, None));
+        assert!(!unrolled("[-]++[>+<--]", CellMode::Circular, None));
+        assert!(!unrolled("[-]++[>+<-,]", CellMode::Circular, None));
+
+        for cell_mode in [CellMode::Nothing, CellMode::Saturate, CellMode::Panic] {
+            assert!(!unrolled("[-]+++[>+<-]", cell_mode, Some(0)));
+        }
+    }
+
+    #[test]
+    fn clears_and_the_adds_after_them_fold_into_a_set() {
+        use Instruction::*;
+
+        assert_eq!(parse(",[-]+++."), [Input, Set(3), Output(1)]);
+        assert_eq!(parse(",[-]+++-.[-]+-"), [Input, Set(2), Output(1), SetZero]);
+        assert_eq!(parse(",[[-]++>]"), [Input, looped(vec![Set(2), Right(1)])]);
+        // Going past 255 or below 0 depends on the cell mode, so it is left
+        let adds = format!(",[-]{}", "+".repeat(256));
+        assert_eq!(parse(&adds), [Input, Set(255), Add(1)]);
+        assert_eq!(parse(",[-]-"), [Input, SetZero, Subtract(1)]);
+
+        let flag = DisableFlags {
+            passes: Some("runlength,clearloop".parse().unwrap()),
+            ..DisableFlags::default()
+        };
+        assert_eq!(parse_with(",[-]+++", flag), [Input, SetZero, Add(3)]);
+    }
+
+    #[test]
+    fn passes_that_reorder_io_are_caught() {
+        use Instruction::*;
+
+        let instructions = |src: &str| {
+            let flag = DisableFlags {
+                disable_optimise: true,
+                ..DisableFlags::default()
+            };
+            Parser::new(src.to_string(), flag, ParseFlags::default()).parse()
+        };
+
+        let pass = Pass::RunLength;
+        assert_eq!(
+            pass.check_io_order(&instructions(",."), &instructions(",.")),
+            Ok(())
+        );
+        assert_eq!(
+            pass.check_io_order(&instructions(".."), &instructions(".")),
+            Err("The runlength pass changed the order of input and output".to_string())
+        );
+        assert_eq!(
+            pass.check_io_order(&instructions(",."), &instructions(".,")),
+            Err("The runlength pass changed the order of input and output".to_string())
+        );
+        // Loops may be dropped or copied out, but not moved past other I/O
+        assert_eq!(
+            pass.check_io_order(&instructions(",[.-]"), &instructions(",")),
+            Ok(())
+        );
+        assert_eq!(
+            pass.check_io_order(&instructions(",[.-]"), &instructions(",..")),
+            Ok(())
+        );
+        assert!(pass
+            .check_io_order(&instructions(",[.-]"), &instructions(".,"))
+            .is_err());
+
+        // Long runs of plain I/O are checked without running out of stack
+        let src = ",[>[<]]".repeat(20_000);
+        assert_eq!(
+            pass.check_io_order(&instructions(&src), &instructions(&src)),
+            Ok(())
+        );
+        assert_eq!(parse(&src).len(), 40_000);
+        assert_eq!(parse(&".".repeat(100_000)), [Output(100_000)]);
+    }
+
+    /// Swap the first two instructions, moving a `,` past a `.`.
+    fn swap(mut instructions: Vec<(SourceSpan, Instruction)>) -> Vec<(SourceSpan, Instruction)> {
+        instructions.swap(0, 1);
+        instructions
+    }
+
+    #[test]
+    #[should_panic(expected = "The swap pass changed the order of input and output")]
+    fn custom_passes_that_reorder_io_panic() {
+        let parser = Parser::new(
+            ",.".to_string(),
+            DisableFlags::default(),
+            ParseFlags::default(),
+        );
+        let flag = DisableFlags {
+            disable_optimise: true,
+            ..DisableFlags::default()
+        };
+        let instructions = Parser::new(",.".to_string(), flag, ParseFlags::default()).parse();
+        parser.apply_pass("swap", instructions, swap);
+    }
+
+    #[test]
+    fn no_optimize_io_order_leaves_io_alone_unchecked() {
+        use Instruction::*;
+
+        let flag = DisableFlags {
+            disable_io_reorder: true,
+            ..DisableFlags::default()
+        };
+        let parser = Parser::new(",.".to_string(), flag, ParseFlags::default());
+        let instructions = Parser::new(
+            ",.".to_string(),
+            DisableFlags {
+                disable_optimise: true,
+                ..flag
+            },
+            ParseFlags::default(),
+        )
+        .parse();
+        assert_eq!(
+            strip(parser.apply_pass("swap", instructions, swap)),
+            [Output(1), Input]
+        );
+
+        assert_eq!(parse_with("..", flag), [Output(1), Output(1)]);
+        assert_eq!(parse("[-]++[>.<-]").len(), 9);
+        assert!(matches!(
+            parse_with("[-]++[>.<-]", flag)[..],
+            [Set(2), Loop(_)]
+        ));
+    }
+
+    #[test]
+    fn scoped_aliases_are_freed_when_their_loop_ends() {
+        let aliases = |src: &str, scoped_aliases: bool| {
+            let parse_flags = ParseFlags {
+                scoped_aliases,
+                ..ParseFlags::default()
+            };
+            let mut parser = Parser::new(src.to_string(), DisableFlags::default(), parse_flags);
+            let gotos: Vec<Instruction> = strip(parser.parse())
+                .into_iter()
+                .flat_map(|instruction| match instruction {
+                    Instruction::Loop(body) => body.into_iter().map(|(_, body)| body).collect(),
+                    instruction => vec![instruction],
+                })
+                .filter(|instruction| matches!(instruction, Instruction::Goto(_)))
+                .collect();
+            (parser.get_aliases().clone(), gotos)
+        };
+        let goto = |name: &str| Instruction::Goto(name.to_string());
+
+        let (names, gotos) = aliases(",[{t}+{t}-],[{t}.]{t}", true);
+        assert_eq!(names, ["t", "t#2", "t#3"]);
+        assert_eq!(gotos, [goto("t"), goto("t"), goto("t#2"), goto("t#3")]);
+
+        // An alias used before the loops is the same one inside them
+        let (names, _) = aliases("{t},[{t}+],[{t}-]", true);
+        assert_eq!(names, ["t"]);
+
+        let (names, gotos) = aliases(",[{t}+{t}-],[{t}.]{t}", false);
+        assert_eq!(names, ["t"]);
+        assert_eq!(gotos, vec![goto("t"); 4]);
+    }
 }
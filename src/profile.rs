@@ -0,0 +1,71 @@
+use std::collections::{BTreeMap, HashMap};
+
+use miette::SourceSpan;
+
+use crate::{errors::line_col, program::Instruction};
+
+/// Counts of what a run spent its steps on.
+#[derive(Clone, Default)]
+pub struct Profile {
+    /// How many times each kind of instruction ran
+    instructions: BTreeMap<&'static str, u64>,
+    /// How many times each loop's body ran, keyed by the loop's span
+    loops: HashMap<(usize, usize), u64>,
+}
+
+impl Profile {
+    pub fn clear(&mut self) {
+        self.instructions.clear();
+        self.loops.clear();
+    }
+
+    pub fn record_instruction(&mut self, instruction: &Instruction) {
+        *self.instructions.entry(instruction.kind()).or_insert(0) += 1;
+    }
+
+    pub fn record_iteration(&mut self, span: SourceSpan) {
+        *self.loops.entry((span.offset(), span.len())).or_insert(0) += 1;
+    }
+
+    /// How many times each kind of instruction ran, by name.
+    pub fn instruction_counts(&self) -> &BTreeMap<&'static str, u64> {
+        &self.instructions
+    }
+
+    /// Every loop that ran at least once, most iterations first. Loops with
+    /// the same count are in source order.
+    pub fn hot_loops(&self) -> Vec<(SourceSpan, u64)> {
+        let mut loops: Vec<(SourceSpan, u64)> = self
+            .loops
+            .iter()
+            .map(|(span, count)| ((*span).into(), *count))
+            .collect();
+        loops.sort_by_key(|(span, count)| (std::cmp::Reverse(*count), span.offset()));
+
+        loops
+    }
+
+    /// A table of instruction counts, then loops ranked by iterations with
+    /// where they are in `src`.
+    pub fn report(&self, src: &str) -> String {
+        let mut out = String::from("Instruction  Count\n");
+        for (kind, count) in &self.instructions {
+            out.push_str(&format!("{:<11}  {}\n", kind, count));
+        }
+
+        let hot_loops = self.hot_loops();
+        if !hot_loops.is_empty() {
+            out.push_str("\nLoop       Iterations\n");
+            for (span, count) in hot_loops {
+                let (line, column) = line_col(src, span);
+                out.push_str(&format!(
+                    "{:<9}  {}\n",
+                    format!("{}:{}", line, column),
+                    count
+                ));
+            }
+        }
+
+        out
+    }
+}
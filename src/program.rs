@@ -1,28 +1,239 @@
-use std::{fs, path::PathBuf, process};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Display,
+    fs::{self, File},
+    io::{self, BufRead, BufWriter, Write},
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use crate::{
-    errors::{fmt_report, BFError, BFErrors},
+    artifact,
+    errors::{
+        fmt_report, json_string, line_col, render_error, render_error_json, render_warning,
+        render_warning_json, BFError, BFErrors, ErrorFormat,
+    },
+    input::{EofPolicy, Input, InputMode, InputPattern},
     parser::Parser,
-    tape::Tape,
-    DisableFlags,
+    profile::Profile,
+    tape::{CellMode, DumpFormat, DumpStyle, SearchDirection, Tape, TapeMode},
+    DisableFlags, ParseFlags,
 };
 use bimap::BiMap;
-use getch::Getch;
 use miette::{miette, LabeledSpan, NamedSource, SourceSpan};
 
 /// All instructions with optimisations for count
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Instruction {
     Add(u8),
     Subtract(u8),
     Loop(Vec<(SourceSpan, Instruction)>),
     Left(u128),
     Right(u128),
+    /// Read a byte into the current cell. Cells are always 8 bits wide, so
+    /// the byte is stored as it is whatever the cell mode, unless the input
+    /// mode adds it to the cell instead.
     Input,
-    Output,
+    /// Write the current cell this many times
+    Output(u64),
+    /// An optimised `[-]`
+    SetZero,
+    /// An optimised `[-]` followed by adds, setting the cell to this value
+    Set(u8),
+    /// Print the pointer and current cell to stderr, written `?`
+    DebugPrintCell,
 
     // For aliases
     Goto(String),
+    /// Move to an absolute cell index, written `@N`
+    Seek(u128),
+    /// A `#` comment, only kept when parsing for reformatting. Does nothing
+    /// at runtime.
+    Comment(String),
+}
+
+impl Instruction {
+    /// The name of the variant, without its contents.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Instruction::Add(_) => "Add",
+            Instruction::Subtract(_) => "Subtract",
+            Instruction::Loop(_) => "Loop",
+            Instruction::Left(_) => "Left",
+            Instruction::Right(_) => "Right",
+            Instruction::Input => "Input",
+            Instruction::Output(_) => "Output",
+            Instruction::SetZero => "SetZero",
+            Instruction::Set(_) => "Set",
+            Instruction::DebugPrintCell => "DebugPrintCell",
+            Instruction::Goto(_) => "Goto",
+            Instruction::Seek(_) => "Seek",
+            Instruction::Comment(_) => "Comment",
+        }
+    }
+
+    /// How many steps running this instruction takes, or `None` if that
+    /// depends on the data, as it does for loops.
+    pub fn cost(&self) -> Option<u64> {
+        match self {
+            Instruction::Loop(_) => None,
+            _ => Some(1),
+        }
+    }
+}
+
+impl Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Instruction::Add(value) => write!(f, "Add {}", value),
+            Instruction::Subtract(value) => write!(f, "Subtract {}", value),
+            Instruction::Loop(instructions) => write!(f, "loop ({} instrs)", instructions.len()),
+            Instruction::Left(value) => write!(f, "Move left {} spaces", value),
+            Instruction::Right(value) => write!(f, "Move right {} spaces", value),
+            Instruction::Input => write!(f, "Take input"),
+            Instruction::Output(count) => write!(f, "Write output {} times", count),
+            Instruction::SetZero => write!(f, "Set to zero"),
+            Instruction::Set(value) => write!(f, "Set to {}", value),
+            Instruction::DebugPrintCell => write!(f, "Print current cell"),
+            Instruction::Goto(name) => write!(f, "Go to alias {}", name),
+            Instruction::Seek(index) => write!(f, "Seek to cell {}", index),
+            Instruction::Comment(text) => write!(f, "Comment: {}", text),
+        }
+    }
+}
+
+/// An instruction of a program flattened for stepping through, where loops
+/// are replaced by jumps so the position is a single index.
+#[derive(Clone, Debug)]
+pub enum FlatInstruction {
+    /// Any instruction other than a loop
+    Simple(Instruction),
+    /// `[`: jump past the matching `LoopEnd` at this index if the cell is zero
+    LoopStart(usize),
+    /// `]`: jump back past the matching `LoopStart` at this index if the cell
+    /// isn't zero
+    LoopEnd(usize),
+}
+
+impl Display for FlatInstruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FlatInstruction::Simple(instruction) => write!(f, "{}", instruction),
+            FlatInstruction::LoopStart(_) => write!(f, "Start loop"),
+            FlatInstruction::LoopEnd(_) => write!(f, "End loop"),
+        }
+    }
+}
+
+/// Iterator over every instruction with its nesting depth, from
+/// [`Program::instructions_flat`].
+pub struct InstructionsFlat<'a> {
+    /// The layers being walked, innermost last
+    stack: Vec<std::slice::Iter<'a, (SourceSpan, Instruction)>>,
+}
+
+impl<'a> Iterator for InstructionsFlat<'a> {
+    type Item = (usize, SourceSpan, &'a Instruction);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let depth = self.stack.len().checked_sub(1)?;
+            match self.stack[depth].next() {
+                Some((span, instruction)) => {
+                    if let Instruction::Loop(layer_instructions) = instruction {
+                        self.stack.push(layer_instructions.iter());
+                    }
+                    return Some((depth, *span, instruction));
+                }
+                None => {
+                    self.stack.pop();
+                }
+            }
+        }
+    }
+}
+
+/// What happened in a single call to [`Program::step`].
+#[derive(Clone, Debug)]
+pub struct StepOutcome {
+    /// Whether there is nothing left to run
+    pub finished: bool,
+    /// The instruction run by this step, if there was one left
+    pub executed: Option<(SourceSpan, FlatInstruction)>,
+    /// Where the pointer is after the step, and the value of that cell
+    pub cell: (u128, u8),
+}
+
+/// How many steps run between checks of the time limit.
+const TIME_CHECK_INTERVAL: u64 = 4096;
+
+/// Resource bounds for running untrusted programs. `None` means unlimited.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Limits {
+    /// Most instructions (including loop checks) that may be executed
+    pub max_steps: Option<u64>,
+    /// Most cells the tape may grow to
+    pub max_tape: Option<u128>,
+    /// Most bytes that may be written by `.`
+    pub max_output: Option<u64>,
+    /// Longest a run may take, by the clock
+    pub max_time: Option<Duration>,
+}
+
+impl Limits {
+    /// Bounds used by `--sandbox` for any limit not set explicitly.
+    pub fn sandbox() -> Self {
+        Self {
+            max_steps: Some(100_000_000),
+            max_tape: Some(1_000_000),
+            max_output: Some(1_000_000),
+            max_time: None,
+        }
+    }
+}
+
+/// How [`Program::emit_source`] indents each level of loop nesting.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Indent {
+    Tabs,
+    Spaces(usize),
+}
+
+impl Default for Indent {
+    fn default() -> Self {
+        Indent::Spaces(4)
+    }
+}
+
+impl Indent {
+    /// The text for one level of nesting.
+    pub fn unit(&self) -> String {
+        match self {
+            Indent::Tabs => "\t".to_string(),
+            Indent::Spaces(count) => " ".repeat(*count),
+        }
+    }
+}
+
+impl FromStr for Indent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "tabs" => Ok(Indent::Tabs),
+            Some(("spaces", count)) => count
+                .parse()
+                .map(Indent::Spaces)
+                .map_err(|_| format!("Invalid number of spaces {}", count)),
+            _ => Err(format!("Unknown indent {} (expected tabs or spaces:N)", s)),
+        }
+    }
 }
 
 /// A core program. This contains no special features, and is the result of
@@ -37,11 +248,120 @@ pub struct Program {
     pub tape: Tape,
     /// Disabled flags
     flag: DisableFlags,
-    getch: Getch,
+    /// Source of bytes for `,`
+    input: Input,
+    /// Print bytes read by `,` as they are read
+    echo_input: bool,
+    input_mode: InputMode,
+    /// What `,` does once the input has ended
+    eof_policy: EofPolicy,
+    /// End the output with a newline if it doesn't already have one
+    ensure_newline: bool,
+    last_output: Option<u8>,
+    /// Where `.` writes to. Echoed input shares this so the two interleave.
+    output: Box<dyn Write>,
+    /// Write bytes as they are, rather than as characters for a terminal
+    raw_output: bool,
+    /// Record which cells the pointer moves over during a run
+    track_pointer: bool,
+    /// Write each byte as a decimal number followed by this, instead
+    numeric_separator: Option<String>,
+    /// What each cell value is written as by `.`, if it isn't written as is
+    charset: Option<Vec<u8>>,
+    /// How `emit_source` indents loop bodies
+    indent: Indent,
+    /// Keep a copy of every byte written by `.`
+    log_output: bool,
+    output_log: Vec<u8>,
+    /// Resource bounds, and how much of each has been used this run
+    limits: Limits,
+    steps: u64,
+    output_bytes: u64,
     /// Aliases
     aliases: BiMap<String, u128>,
     /// Parser
     parser: Option<Parser>,
+    /// Deepest loop nesting allowed while running
+    max_depth: usize,
+    depth: usize,
+    /// The instruction being run, for reporting where errors happen
+    current_span: SourceSpan,
+    /// Set from elsewhere (such as a Ctrl-C handler) to stop the run
+    interrupt: Option<Arc<AtomicBool>>,
+    /// Print the tape and aliases alongside any runtime error
+    dump_on_error: bool,
+    /// How cell values are written when the tape is dumped
+    dump_format: DumpFormat,
+    /// Aliases to allocate when there is no parser, for loaded artifacts
+    loaded_aliases: Vec<String>,
+    /// Loaded from a stripped artifact, so spans don't point at anything
+    stripped: bool,
+    /// What the last run spent its steps on, if profiling
+    profile: Option<Profile>,
+    /// How errors are written to stderr
+    error_format: ErrorFormat,
+    /// The program flattened for `step`, built on the first step
+    flat_instructions: Option<Vec<(SourceSpan, FlatInstruction)>>,
+    /// The index of the next flat instruction `step` will run
+    pc: usize,
+    /// When the current run began, for the time limit
+    started: Option<Instant>,
+    /// Run on the tape as it is, rather than clearing it first
+    preserve_initial_state: bool,
+    /// The alias whose cell the pointer starts on, instead of cell 0
+    entry: Option<String>,
+}
+
+/// A copy of the whole program, partway through a run or not, for exploring
+/// what would happen next without disturbing the original. The copy's `.`
+/// output is thrown away, as the writer can't be copied, though its output
+/// log is kept; use [`Program::set_capture_output`] on it to see what it
+/// writes.
+impl Clone for Program {
+    fn clone(&self) -> Self {
+        Self {
+            path: self.path.clone(),
+            src: self.src.clone(),
+            instructions: self.instructions.clone(),
+            tape: self.tape.clone(),
+            flag: self.flag,
+            input: self.input.clone(),
+            echo_input: self.echo_input,
+            input_mode: self.input_mode,
+            eof_policy: self.eof_policy,
+            ensure_newline: self.ensure_newline,
+            last_output: self.last_output,
+            // Writers can't be copied, so only the original writes out
+            output: Box::new(io::sink()),
+            raw_output: self.raw_output,
+            track_pointer: self.track_pointer,
+            numeric_separator: self.numeric_separator.clone(),
+            charset: self.charset.clone(),
+            indent: self.indent,
+            log_output: self.log_output,
+            output_log: self.output_log.clone(),
+            limits: self.limits,
+            steps: self.steps,
+            output_bytes: self.output_bytes,
+            aliases: self.aliases.clone(),
+            parser: self.parser.clone(),
+            max_depth: self.max_depth,
+            depth: self.depth,
+            current_span: self.current_span,
+            interrupt: self.interrupt.clone(),
+            dump_on_error: self.dump_on_error,
+            dump_format: self.dump_format,
+            loaded_aliases: self.loaded_aliases.clone(),
+            stripped: self.stripped,
+            profile: self.profile.clone(),
+            error_format: self.error_format,
+            flat_instructions: self.flat_instructions.clone(),
+            pc: self.pc,
+            started: self.started,
+            preserve_initial_state: self.preserve_initial_state,
+            entry: self.entry.clone(),
+        }
+    }
 }
 
 impl Program {
@@ -53,68 +373,686 @@ impl Program {
         flag: DisableFlags,
         parser: Option<Parser>,
     ) -> Self {
-        let getch = Getch::new();
         Self {
             path,
             src,
             instructions,
             tape,
             flag,
-            getch,
+            input: Input::terminal(),
+            echo_input: false,
+            input_mode: InputMode::Replace,
+            eof_policy: EofPolicy::Zero,
+            ensure_newline: false,
+            last_output: None,
+            output: Box::new(io::stdout()),
+            raw_output: false,
+            numeric_separator: None,
+            track_pointer: false,
+            charset: None,
+            indent: Indent::default(),
+            log_output: false,
+            output_log: vec![],
+            limits: Limits::default(),
+            steps: 0,
+            output_bytes: 0,
             aliases: BiMap::new(),
             parser,
+            max_depth: ParseFlags::default().max_depth,
+            depth: 0,
+            current_span: (0, 0).into(),
+            interrupt: None,
+            dump_on_error: false,
+            dump_format: DumpFormat::Dec,
+            loaded_aliases: vec![],
+            stripped: false,
+            profile: None,
+            error_format: ErrorFormat::Human,
+            flat_instructions: None,
+            pc: 0,
+            started: None,
+            preserve_initial_state: false,
+            entry: None,
         }
     }
 
-    pub fn read_file(path: PathBuf, tape: Tape, flag: DisableFlags) -> Self {
-        let file = fs::read_to_string(path.clone()).expect("File not found");
+    /// Parse the program in the file at `path`. A file that can't be read
+    /// gives a single error, with an empty span at the start.
+    pub fn read_file(
+        path: PathBuf,
+        tape: Tape,
+        flag: DisableFlags,
+        parse_flags: ParseFlags,
+    ) -> Result<Self, Vec<(SourceSpan, BFError)>> {
+        let file =
+            fs::read_to_string(&path).map_err(|error| vec![((0, 0).into(), error.into())])?;
 
-        Program::parse(path, file, tape, flag)
+        Program::parse(path, file, tape, flag, parse_flags)
     }
 
-    pub fn parse(path: PathBuf, src: String, tape: Tape, flag: DisableFlags) -> Self {
+    /// Parse and optimise `src`. If `tape` still holds nothing but its fill,
+    /// loops at the start are unrolled knowing that, so anything seeded or
+    /// loaded onto it has to be there before parsing.
+    pub fn parse(
+        path: PathBuf,
+        src: String,
+        tape: Tape,
+        flag: DisableFlags,
+        parse_flags: ParseFlags,
+    ) -> Result<Self, Vec<(SourceSpan, BFError)>> {
         // Use parser to parse it
-        let mut parser = Parser::new(src.clone(), flag);
+        let mut parser = Parser::new(src.clone(), flag, parse_flags);
+        parser.set_cell_mode(tape.get_cell_mode());
+        if tape.is_fresh() {
+            parser.set_fill(tape.fill());
+        }
+        let errors = parser.validate();
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
         let instructions = parser.parse();
-        Self::new(path, src, instructions, tape, flag, Some(parser))
+        let mut program = Self::new(path, src, instructions, tape, flag, Some(parser));
+        program.max_depth = parse_flags.max_depth;
+        Ok(program)
     }
 
-    pub fn get_instructions(&self) -> &Vec<(SourceSpan, Instruction)> {
-        &self.instructions
+    /// Parse a program from `reader` a chunk at a time with
+    /// [`Parser::parse_stream`]. The source isn't kept, so, as for a
+    /// stripped artifact, errors are reported without it.
+    pub fn parse_stream(
+        path: PathBuf,
+        reader: impl BufRead,
+        tape: Tape,
+        flag: DisableFlags,
+        parse_flags: ParseFlags,
+    ) -> Result<Self, Vec<(SourceSpan, BFError)>> {
+        let mut parser = Parser::new(String::new(), flag, parse_flags);
+        parser.set_cell_mode(tape.get_cell_mode());
+        if tape.is_fresh() {
+            parser.set_fill(tape.fill());
+        }
+        let errors = parser.parse_stream(reader);
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let instructions = parser.parse();
+        let mut program = Self::new(path, String::new(), instructions, tape, flag, Some(parser));
+        program.max_depth = parse_flags.max_depth;
+        program.stripped = true;
+        Ok(program)
     }
 
-    pub fn setup(&mut self) {
-        if let Some(parser) = &self.parser {
-            if !self.flag.disable_alloc {
-                self.run_prealloc(
-                    parser
-                        .get_aliases()
-                        .iter()
-                        .map(|f| f.to_owned())
-                        .collect::<Vec<_>>(),
+    /// Load a program compiled by [`Program::to_artifact`]. The source is read
+    /// again for diagnostics if it was recorded and still exists.
+    pub fn from_artifact(
+        path: PathBuf,
+        text: &str,
+        tape: Tape,
+        flag: DisableFlags,
+        parse_flags: ParseFlags,
+    ) -> Result<Self, BFError> {
+        let artifact = artifact::read(text)?;
+        let src = artifact
+            .source
+            .as_ref()
+            .and_then(|source| fs::read_to_string(source).ok());
+
+        let mut program = Self::new(
+            artifact.source.unwrap_or(path),
+            src.clone().unwrap_or_default(),
+            artifact.instructions,
+            tape,
+            flag,
+            None,
+        );
+        program.max_depth = parse_flags.max_depth;
+        program.loaded_aliases = artifact.aliases;
+        program.stripped = artifact.stripped || src.is_none();
+        Ok(program)
+    }
+
+    /// Compile the program into an artifact, optionally without spans.
+    pub fn to_artifact(&self, strip: bool) -> String {
+        artifact::write(
+            &self.instructions,
+            &self.alias_names(),
+            self.path.to_str(),
+            strip,
+        )
+    }
+
+    /// Read input from the given pattern instead of the terminal.
+    pub fn set_input_pattern(&mut self, pattern: InputPattern) {
+        self.input = Input::pattern(pattern);
+    }
+
+    /// Read input from `bytes` instead of the terminal.
+    pub fn set_input_bytes(&mut self, bytes: &[u8]) {
+        self.input = Input::bytes(bytes);
+    }
+
+    /// Choose whether `,` replaces the current cell or adds to it.
+    pub fn set_input_mode(&mut self, input_mode: InputMode) {
+        self.input_mode = input_mode;
+    }
+
+    /// Choose what `,` does to the current cell once the input has ended,
+    /// whatever the input mode.
+    pub fn set_eof_policy(&mut self, eof_policy: EofPolicy) {
+        self.eof_policy = eof_policy;
+    }
+
+    pub fn set_echo_input(&mut self, echo_input: bool) {
+        self.echo_input = echo_input;
+    }
+
+    /// Write output bytes, untranslated, to the file at `path`. Nothing else
+    /// goes in it: numeric output, `ensure_newline` and any charset are
+    /// ignored, and echoed input goes to stderr.
+    pub fn set_output_file(&mut self, path: &Path) -> Result<(), BFError> {
+        self.output = Box::new(BufWriter::new(File::create(path)?));
+        self.raw_output = true;
+        Ok(())
+    }
+
+    /// Throw away the bytes output by `.`, such as when only the output log
+    /// is wanted.
+    pub fn discard_output(&mut self) {
+        self.output = Box::new(io::sink());
+    }
+
+    /// Stop the run at the next instruction once `interrupt` is set.
+    pub fn set_interrupt_flag(&mut self, interrupt: Arc<AtomicBool>) {
+        self.interrupt = Some(interrupt);
+    }
+
+    /// Write each byte output by `.` as a number followed by `separator`,
+    /// rather than as a character.
+    pub fn set_numeric_output(&mut self, separator: Option<String>) {
+        self.numeric_separator = separator;
+    }
+
+    /// Indent loop bodies with `indent` in [`Program::emit_source`].
+    pub fn set_indent(&mut self, indent: Indent) {
+        self.indent = indent;
+    }
+
+    /// Translate each value written by `.` through `table`, which holds the
+    /// byte to write for each of the 256 cell values.
+    pub fn set_charset(&mut self, table: &[u8]) -> Result<(), BFError> {
+        if table.len() != 256 {
+            return Err(BFError::new(
+                BFErrors::RuntimeError,
+                format!(
+                    "A charset needs 256 entries, but {} were given",
+                    table.len()
+                ),
+            ));
+        }
+
+        self.charset = Some(table.to_vec());
+        Ok(())
+    }
+
+    pub fn set_ensure_newline(&mut self, ensure_newline: bool) {
+        self.ensure_newline = ensure_newline;
+    }
+
+    /// Record every cell the pointer moves over while running, for
+    /// [`Program::pointer_report`].
+    pub fn set_track_pointer(&mut self, track_pointer: bool) {
+        self.track_pointer = track_pointer;
+    }
+
+    /// How far the pointer went during the last run, if it was tracked.
+    pub fn pointer_report(&self) -> Option<String> {
+        let history = self.tape.pointer_history()?;
+        let cells: u128 = history
+            .iter()
+            .map(|range| range.end() - range.start() + 1)
+            .sum();
+        Some(format!(
+            "Pointer reached cells {} to {}, {} distinct\n",
+            history.first()?.start(),
+            history.last()?.end(),
+            cells
+        ))
+    }
+
+    /// Count instructions and loop iterations while running.
+    pub fn set_profile(&mut self, profile: bool) {
+        self.profile = profile.then(Profile::default);
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// The profile of the last run, with loop positions in this source.
+    pub fn profile_report(&self) -> Option<String> {
+        self.profile
+            .as_ref()
+            .map(|profile| profile.report(&self.src))
+    }
+
+    pub fn set_error_format(&mut self, error_format: ErrorFormat) {
+        self.error_format = error_format;
+    }
+
+    /// Stop `run` from clearing the tape and moving the pointer back to the
+    /// start, so it runs on a tape that was filled in beforehand.
+    pub fn set_preserve_initial_state(&mut self, preserve_initial_state: bool) {
+        self.preserve_initial_state = preserve_initial_state;
+    }
+
+    /// Start the pointer on the cell of the alias `name` rather than on
+    /// cell 0. `setup` must be run first, so the alias has a cell.
+    pub fn set_entry(&mut self, name: &str) -> Result<(), BFError> {
+        let address = match self.aliases.get_by_left(name) {
+            Some(address) => *address,
+            None if self.alias_names().iter().any(|alias| alias == name) => {
+                return Err(BFError::new(
+                    BFErrors::RuntimeError,
+                    format!(
+                        "Alias {} has no cell yet, so the program can't start on it",
+                        name
+                    ),
+                ))
+            }
+            None => {
+                return Err(BFError::new(
+                    BFErrors::RuntimeError,
+                    format!("There is no alias named {} to start on", name),
+                ))
+            }
+        };
+
+        self.entry = Some(name.to_string());
+        self.tape.set_pointer(self.tape.physical_index(address));
+        Ok(())
+    }
+
+    pub fn set_dump_on_error(&mut self, dump_on_error: bool) {
+        self.dump_on_error = dump_on_error;
+    }
+
+    pub fn set_dump_format(&mut self, dump_format: DumpFormat) {
+        self.dump_format = dump_format;
+    }
+
+    pub fn set_log_output(&mut self, log_output: bool) {
+        self.log_output = log_output;
+    }
+
+    /// Keep the bytes written by `.` for [`Program::output_bytes`] instead of
+    /// writing them to stdout, for running a program from other code.
+    pub fn set_capture_output(&mut self) {
+        self.log_output = true;
+        self.discard_output();
+    }
+
+    /// The bytes written by `.` during the last run, once
+    /// [`Program::set_capture_output`] or `set_log_output` is on. This is
+    /// the [output log](Program::output_log) by the name embedders look for.
+    pub fn output_bytes(&self) -> &[u8] {
+        self.output_log()
+    }
+
+    /// Every byte written by `.` during the last run, if logging was enabled.
+    pub fn output_log(&self) -> &[u8] {
+        &self.output_log
+    }
+
+    /// The first way the last run of this program and of `other` differ,
+    /// comparing their logged output and then their tapes.
+    pub fn run_difference(&self, other: &Program) -> Option<String> {
+        let (ours, theirs) = (&self.output_log, &other.output_log);
+        if let Some(index) =
+            (0..ours.len().min(theirs.len())).find(|index| ours[*index] != theirs[*index])
+        {
+            return Some(format!(
+                "output byte {} is {} and {}",
+                index, ours[index], theirs[index]
+            ));
+        }
+        if ours.len() != theirs.len() {
+            return Some(format!(
+                "output is {} and {} bytes long",
+                ours.len(),
+                theirs.len()
+            ));
+        }
+
+        self.tape.difference(&other.tape)
+    }
+
+    /// A table of each logged output byte alongside its glyph and hex value.
+    pub fn output_log_report(&self) -> String {
+        self.output_log()
+            .iter()
+            .map(|value| {
+                format!(
+                    "{} -> '{}' (0x{:02x})\n",
+                    value,
+                    (*value as char).escape_default(),
+                    value
                 )
+            })
+            .collect()
+    }
+
+    /// The tape as the last run left it.
+    pub fn tape(&self) -> &Tape {
+        &self.tape
+    }
+
+    /// How many steps the last run took.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    fn count_step(&mut self) -> Result<(), BFError> {
+        self.steps += 1;
+        if let Some(max_steps) = self.limits.max_steps {
+            if self.steps > max_steps {
+                return Err(BFError::new(
+                    BFErrors::StepLimitExceeded,
+                    format!("Program exceeded the limit of {} steps", max_steps),
+                ));
+            }
+        }
+
+        // Reading the clock every step would slow everything down
+        if self.steps.is_multiple_of(TIME_CHECK_INTERVAL) {
+            self.check_time_limit()?;
+        }
+
+        Ok(())
+    }
+
+    fn check_time_limit(&self) -> Result<(), BFError> {
+        match (self.limits.max_time, self.started) {
+            (Some(max_time), Some(started)) if started.elapsed() > max_time => Err(BFError::new(
+                BFErrors::TimeLimitExceeded,
+                format!(
+                    "Program exceeded the time limit of {:.3} seconds",
+                    max_time.as_secs_f64()
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_tape_limit(&self) -> Result<(), BFError> {
+        match self.limits.max_tape {
+            Some(max_tape) if self.tape.size() > max_tape => Err(BFError::new(
+                BFErrors::TapeLimitExceeded,
+                format!(
+                    "Tape grew to {} cells, above the limit of {}",
+                    self.tape.size(),
+                    max_tape
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn write_output(&mut self, value: u8) -> Result<(), BFError> {
+        self.output_bytes += 1;
+        if let Some(max_output) = self.limits.max_output {
+            if self.output_bytes > max_output {
+                return Err(BFError::new(
+                    BFErrors::OutputLimitExceeded,
+                    format!("Program exceeded the limit of {} output bytes", max_output),
+                ));
             }
         }
+
+        self.last_output = Some(value);
+        let result = if self.raw_output {
+            self.output.write_all(&[value])
+        } else if let Some(separator) = &self.numeric_separator {
+            write!(self.output, "{}{}", value, separator)
+        } else {
+            write!(self.output, "{}", value as char)
+        };
+
+        result.map_err(|error| {
+            BFError::new(
+                BFErrors::IoError,
+                format!("Could not write output: {}", error),
+            )
+        })
+    }
+
+    /// Echo a byte read by `,`. It is written with the output, unless that
+    /// is going to a file, which only gets what `.` writes, so it goes to
+    /// stderr instead.
+    fn echo(&mut self, value: u8) -> Result<(), BFError> {
+        if !self.raw_output {
+            return self.write_output(value);
+        }
+
+        // Keep it in order with output written so far
+        let _ = self.output.flush();
+        io::stderr().write_all(&[value]).map_err(|error| {
+            BFError::new(
+                BFErrors::IoError,
+                format!("Could not echo input: {}", error),
+            )
+        })
+    }
+
+    pub fn get_instructions(&self) -> &Vec<(SourceSpan, Instruction)> {
+        &self.instructions
+    }
+
+    /// The cell address each alias was allocated, by `setup`. This is empty
+    /// until then; [`Program::alias_names`] lists the aliases declared.
+    pub fn aliases(&self) -> &BiMap<String, u128> {
+        &self.aliases
+    }
+
+    /// Every alias used, in the order they should be allocated.
+    pub fn alias_names(&self) -> Vec<String> {
+        match &self.parser {
+            Some(parser) => parser.get_aliases().clone(),
+            None => self.loaded_aliases.clone(),
+        }
+    }
+
+    pub fn setup(&mut self) -> Result<(), BFError> {
+        if !self.flag.disable_alloc {
+            self.validate_aliases()?;
+            self.run_prealloc(self.alias_names())?;
+        }
+
+        Ok(())
+    }
+
+    /// Check that there are enough free cells for every alias still to be
+    /// allocated, so a tape that is too small is reported up front rather
+    /// than part way through allocating.
+    pub fn validate_aliases(&self) -> Result<(), BFError> {
+        let shift = self.tape.shift;
+        let unallocated: Vec<String> = self
+            .alias_names()
+            .into_iter()
+            .filter(|name| !self.aliases.contains_left(name))
+            .collect();
+        let free = (shift..self.tape.size())
+            .filter(|index| {
+                self.tape
+                    .try_get_value_at_index(*index)
+                    .is_ok_and(|value| value == self.tape.fill())
+                    && !self.aliases.contains_right(&(index - shift))
+            })
+            .count();
+
+        if unallocated.len() > free {
+            return Err(BFError::new(
+                BFErrors::RuntimeError,
+                format!(
+                    "The tape has room for {} more aliases, but {} are used: {}",
+                    free,
+                    unallocated.len(),
+                    unallocated.join(", ")
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Put the program back into the state it was in after `setup`, without
+    /// parsing it again, so it can be run again.
+    pub fn reset(&mut self) -> Result<(), BFError> {
+        self.tape.clear();
+        self.tape.realign();
+        self.aliases = BiMap::new();
+        self.pc = 0;
+
+        self.setup()
     }
 
-    pub fn run_prealloc(&mut self, aliases: Vec<String>) {
+    pub fn run_prealloc(&mut self, aliases: Vec<String>) -> Result<(), BFError> {
         for alias in aliases {
-            self.assign_alias_address(alias);
+            if !self.aliases.contains_left(&alias) {
+                self.assign_alias_address(alias)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Allocate a free cell for `key`, returning its index in the cells.
+    /// Aliases remember the address from where the tape started, so they
+    /// still work after cells are added to the front.
+    fn assign_alias_address(&mut self, key: String) -> Result<u128, BFError> {
+        // Work backwards until we find an empty spot. Cells added to the
+        // front have no address, so they are never used.
+        let shift = self.tape.shift;
+        let index = self
+            .tape
+            .find_alias_free_run(1, SearchDirection::Backward, |index| {
+                index < shift || self.aliases.contains_right(&(index - shift))
+            })
+            .ok_or_else(|| {
+                BFError::new(
+                    BFErrors::RuntimeError,
+                    format!("No free cell available for alias {}", key),
+                )
+            })?;
+
+        self.aliases.insert(key.clone(), index - shift);
+        Ok(index)
+    }
+
+    /// A table of every alias and the cell it lives in, sorted by name.
+    /// Aliases that are declared but not allocated yet are shown as
+    /// unresolved.
+    pub fn alias_table(&self) -> String {
+        let mut names = self.alias_names();
+        for name in self.aliases.left_values() {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+        names.sort();
+
+        let width = names
+            .iter()
+            .map(|name| name.len())
+            .max()
+            .unwrap_or(0)
+            .max("Alias".len());
+
+        let mut out = format!("{:<width$}  Address\n", "Alias", width = width);
+        for name in names {
+            match self.aliases.get_by_left(&name) {
+                Some(address) => {
+                    out.push_str(&format!("{:<width$}  {}\n", name, address, width = width))
+                }
+                None => out.push_str(&format!("{:<width$}  unresolved\n", name, width = width)),
+            }
         }
+
+        out
     }
 
-    fn assign_alias_address(&mut self, key: String) -> u128 {
-        // Work backwards until we find an empty spot
-        let mut index = self.tape.size() - 1;
-        while self.tape.get_value_at_index(index) != 0 || self.aliases.contains_right(&index) {
-            index -= 1;
+    /// The value held by every allocated alias, sorted by name.
+    pub fn alias_values(&self) -> String {
+        let mut aliases: Vec<(&String, &u128)> = self.aliases.iter().collect();
+        aliases.sort();
+
+        let width = aliases
+            .iter()
+            .map(|(name, _)| name.len())
+            .max()
+            .unwrap_or(0)
+            .max("Alias".len());
+
+        let mut out = format!("{:<width$}  Address  Value\n", "Alias", width = width);
+        for (name, address) in aliases {
+            let value = match self
+                .tape
+                .try_get_value_at_index(self.tape.physical_index(*address))
+            {
+                Ok(value) => value.to_string(),
+                Err(_) => "missing".to_string(),
+            };
+            out.push_str(&format!(
+                "{:<width$}  {:<7}  {}\n",
+                name,
+                address,
+                value,
+                width = width
+            ));
         }
 
-        self.aliases.insert(key.clone(), index);
-        index
+        out
+    }
+
+    /// Print the tape around the pointer, and any aliases, to stderr.
+    fn dump_state(&self) {
+        let alias_cells: Vec<u128> = self
+            .aliases
+            .right_values()
+            .map(|address| self.tape.physical_index(*address))
+            .collect();
+        eprint!(
+            "{}",
+            self.tape
+                .dump(5, DumpStyle::detect(), self.dump_format, &alias_cells)
+        );
+        if !self.aliases.is_empty() {
+            eprint!("{}", self.alias_values());
+        }
     }
 
     fn run_one(&mut self, instruction: &Instruction) -> Result<(), BFError> {
+        if let Instruction::Comment(_) = instruction {
+            return Ok(());
+        }
+        if let Some(interrupt) = &self.interrupt {
+            if interrupt.load(Ordering::SeqCst) {
+                return Err(BFError::new(
+                    BFErrors::Interrupted,
+                    "Interrupted".to_string(),
+                ));
+            }
+        }
+        self.count_step()?;
+        if let Some(profile) = &mut self.profile {
+            profile.record_instruction(instruction);
+        }
+
         match instruction.clone() {
             Instruction::Add(count) => {
                 self.tape.add(count)?;
@@ -123,39 +1061,97 @@ impl Program {
                 self.tape.sub(count)?;
             }
             Instruction::Loop(instructions) => {
+                if self.depth >= self.max_depth {
+                    return Err(BFError::new(
+                        BFErrors::RuntimeError,
+                        format!("Loops are nested more than {} deep", self.max_depth),
+                    ));
+                }
+
+                debug_assert_eq!(self.tape.check_invariants(), Ok(()));
+
+                // Set by the caller, so this is the loop's own span
+                let loop_span = self.current_span;
+                self.depth += 1;
                 while self.tape.get_value() != 0 {
-                    for (_span, instruction) in &instructions {
+                    self.count_step()?;
+                    if let Some(profile) = &mut self.profile {
+                        profile.record_iteration(loop_span);
+                    }
+                    for (span, instruction) in &instructions {
+                        self.current_span = *span;
                         self.run_one(instruction)?;
                     }
                 }
+                self.depth -= 1;
             }
             Instruction::Left(count) => {
                 self.tape.left(count)?;
+                self.check_tape_limit()?;
             }
             Instruction::Right(count) => {
                 self.tape.right(count)?;
+                self.check_tape_limit()?;
             }
             Instruction::Input => {
-                let mut character: Option<u8> = None;
-                while character.is_none() {
-                    match self.getch.getch() {
-                        Ok(c) => character = Some(c),
-                        _ => (),
+                // Make sure any prompt is visible before blocking on input
+                let _ = self.output.flush();
+                let character = match self.input.read() {
+                    Some(character) => character,
+                    None => {
+                        match self.eof_policy {
+                            EofPolicy::Zero => self.tape.set_value(0),
+                            EofPolicy::Max => self.tape.set_value(u8::MAX),
+                            EofPolicy::Unchanged => (),
+                        }
+                        return Ok(());
                     }
+                };
+                if self.echo_input {
+                    self.echo(character)?;
                 }
 
-                self.tape.set_value(character.unwrap())
+                match self.input_mode {
+                    InputMode::Replace => self.tape.set_value(character),
+                    InputMode::Add => self.tape.add(character)?,
+                }
             }
-            Instruction::Output => {
-                print!("{}", self.tape.get_value() as char);
+            Instruction::Output(count) => {
+                let value = self.tape.get_value();
+                // Output files get the raw bytes, untranslated
+                let value = match &self.charset {
+                    Some(charset) if !self.raw_output => charset[value as usize],
+                    _ => value,
+                };
+                for _ in 0..count {
+                    if self.log_output {
+                        self.output_log.push(value);
+                    }
+                    self.write_output(value)?;
+                }
+            }
+            Instruction::SetZero => {
+                self.tape.set_value(0);
+            }
+            Instruction::Set(value) => {
+                self.tape.set_value(value);
+            }
+            Instruction::DebugPrintCell => {
+                // Keep it in order with output written so far
+                let _ = self.output.flush();
+                eprintln!(
+                    "pointer {}, value {}",
+                    self.tape.position(),
+                    self.tape.get_value()
+                );
             }
             Instruction::Goto(key) => {
                 let address = self.aliases.get_by_left(&key);
                 if let Some(address) = address {
-                    self.tape.set_pointer(*address);
+                    self.tape.set_pointer(self.tape.physical_index(*address));
                 } else if self.flag.disable_alloc {
                     // Alloc was disabled so we need to assign at runtime
-                    let index = self.assign_alias_address(key);
+                    let index = self.assign_alias_address(key)?;
                     self.tape.set_pointer(index);
                 } else {
                     return Err(BFError::new(
@@ -164,82 +1160,2602 @@ impl Program {
                     ));
                 }
             }
+            Instruction::Seek(index) => {
+                self.tape.seek(index)?;
+                self.check_tape_limit()?;
+            }
+            Instruction::Comment(_) => (),
         }
 
         Ok(())
     }
 
-    pub fn run(&mut self) {
-        // Iterate through instructions, catch error if possible
-        self.tape.clear();
-        self.tape.realign();
+    /// Print a report for the given error, highlighting `span` in the source
+    /// if it is known.
+    pub fn report_error(&self, error: &BFError, span: Option<SourceSpan>) {
+        if self.error_format == ErrorFormat::Json {
+            let span = if self.stripped { None } else { span };
+            eprintln!("{}", render_error_json(&self.path, error, span));
+            return;
+        }
+
+        if self.stripped {
+            let error = BFError::new(
+                error.error,
+                format!("{} (position unavailable)", error.message),
+            );
+            eprintln!("{}", render_error(&self.path, &self.src, &error, None));
+            return;
+        }
+
+        eprintln!("{}", render_error(&self.path, &self.src, error, span));
+    }
+
+    /// Print `warning` about the instruction at `span` to stderr, in the
+    /// same format as errors.
+    pub fn report_warning(&self, warning: &str, span: SourceSpan) {
+        if self.error_format == ErrorFormat::Json {
+            eprintln!("{}", render_warning_json(&self.path, warning, span));
+        } else if !self.stripped {
+            eprintln!("{}", render_warning(&self.path, &self.src, warning, span));
+        }
+    }
+
+    /// Run the program, reading `,` from `input` rather than the terminal.
+    pub fn run_with_input(&mut self, input: &[u8]) {
+        self.input = Input::bytes(input);
+        self.run();
+    }
+
+    /// Tidy up after a run, whether or not it succeeded: add the trailing
+    /// newline if asked for, flush the output and give the terminal back.
+    fn finish(&mut self) {
+        // An output file only gets the bytes `.` wrote
+        if self.ensure_newline
+            && !self.raw_output
+            && self.last_output.is_some_and(|value| value != b'\n')
+        {
+            let _ = self.write_output(b'\n');
+        }
+
+        let _ = self.output.flush();
+        self.input.release();
+    }
+
+    pub fn run(&mut self) {
+        // Iterate through instructions, catch error if possible
+        self.output_log.clear();
+        self.last_output = None;
+        self.depth = 0;
+        self.steps = 0;
+        self.output_bytes = 0;
+        self.started = Some(Instant::now());
+        if let Some(profile) = &mut self.profile {
+            profile.clear();
+        }
+        if !self.preserve_initial_state {
+            self.tape.clear();
+            self.tape.realign();
+        }
+        let entry = self
+            .entry
+            .as_ref()
+            .and_then(|name| self.aliases.get_by_left(name));
+        if let Some(address) = entry {
+            self.tape.set_pointer(self.tape.physical_index(*address));
+        }
+        if self.track_pointer {
+            self.tape.track_pointer();
+        }
         for (source_span, instruction) in self.instructions.clone() {
             let instruction = instruction.clone();
-            let source_span = source_span.clone();
+            self.current_span = source_span;
 
             match self.run_one(&instruction) {
                 Ok(()) => continue,
                 Err(error) => {
-                    let report = miette!(
-                        labels = vec![LabeledSpan::new_with_span(
-                            Some("error occurs here".to_string()),
-                            source_span
-                        )],
-                        "{}",
-                        error.message
-                    );
-                    println!(
-                        "{}",
-                        fmt_report((report).with_source_code(NamedSource::new(
-                            self.path.to_str().unwrap(),
-                            self.src.clone()
-                        )))
-                    );
+                    self.finish();
+                    self.report_error(&error, Some(self.current_span));
+                    if let BFErrors::Interrupted = error.error {
+                        self.dump_state();
+                        process::exit(130);
+                    }
+                    if self.dump_on_error {
+                        self.dump_state();
+                    }
                     process::exit(1);
                 }
             }
         }
+
+        self.finish();
+    }
+
+    /// Flatten `instructions` onto `out`, replacing each loop with a pair of
+    /// jumps that point at each other.
+    fn flatten_jumps(
+        instructions: &[(SourceSpan, Instruction)],
+        out: &mut Vec<(SourceSpan, FlatInstruction)>,
+    ) {
+        for (span, instruction) in instructions {
+            match instruction {
+                Instruction::Loop(layer_instructions) => {
+                    let start = out.len();
+                    // Filled in once the end is known
+                    out.push((*span, FlatInstruction::LoopStart(0)));
+                    Program::flatten_jumps(layer_instructions, out);
+                    let end = out.len();
+                    out.push((*span, FlatInstruction::LoopEnd(start)));
+                    out[start].1 = FlatInstruction::LoopStart(end);
+                }
+                instruction => out.push((*span, FlatInstruction::Simple(instruction.clone()))),
+            }
+        }
     }
 
-    fn produce_labeled_spans(instructions: &Vec<(SourceSpan, Instruction)>) -> Vec<LabeledSpan> {
-        let mut labeled_spans: Vec<LabeledSpan> = vec![];
-        for (source_span, instruction) in instructions {
-            let info = match instruction {
-                Instruction::Add(value) => Some(format!("Add {}", value)),
-                Instruction::Subtract(value) => Some(format!("Subtract {}", value)),
+    /// The instruction the next call to `step` will run, if any are left.
+    pub fn next_step(&mut self) -> Option<(SourceSpan, FlatInstruction)> {
+        let flat_instructions = self.flat_instructions.get_or_insert_with(|| {
+            let mut out = vec![];
+            Program::flatten_jumps(&self.instructions, &mut out);
+            out
+        });
+
+        flat_instructions.get(self.pc).cloned()
+    }
+
+    /// Where `span` starts in the source, as a 1-based (line, column) pair.
+    pub fn line_col(&self, span: SourceSpan) -> (usize, usize) {
+        line_col(&self.src, span)
+    }
+
+    /// Run a single instruction, for debuggers that want to drive the
+    /// program themselves. Unlike `run` this doesn't clear the tape first,
+    /// so call `reset` to start again. Loop brackets count as steps.
+    pub fn step(&mut self) -> Result<StepOutcome, BFError> {
+        let executed = self.next_step();
+        if let Some((span, instruction)) = &executed {
+            self.current_span = *span;
+            self.pc = match instruction {
+                FlatInstruction::Simple(instruction) => {
+                    self.run_one(instruction)?;
+                    self.pc + 1
+                }
+                FlatInstruction::LoopStart(end) => {
+                    self.count_step()?;
+                    if self.tape.get_value() == 0 {
+                        end + 1
+                    } else {
+                        self.pc + 1
+                    }
+                }
+                FlatInstruction::LoopEnd(start) => {
+                    self.count_step()?;
+                    if self.tape.get_value() != 0 {
+                        start + 1
+                    } else {
+                        self.pc + 1
+                    }
+                }
+            };
+        }
+
+        let finished = self.pc >= self.flat_instructions.as_ref().map_or(0, Vec::len);
+        if finished && executed.is_some() {
+            self.finish();
+        }
+
+        Ok(StepOutcome {
+            finished,
+            executed,
+            cell: (self.tape.get_pointer(), self.tape.get_value()),
+        })
+    }
+
+    /// Where the pointer ends up after `instructions` if it starts at
+    /// `pointer`, or `None` if that can't be known without running them.
+    fn track_pointer(
+        &self,
+        instructions: &[(SourceSpan, Instruction)],
+        pointer: Option<i128>,
+    ) -> Option<i128> {
+        let mut pointer = pointer;
+        for (_span, instruction) in instructions {
+            pointer = match instruction {
+                Instruction::Left(count) => pointer.map(|pointer| pointer - *count as i128),
+                Instruction::Right(count) => pointer.map(|pointer| pointer + *count as i128),
                 Instruction::Loop(layer_instructions) => {
-                    labeled_spans.append(&mut Program::produce_labeled_spans(layer_instructions));
+                    // A loop can run any number of times, so the position is
+                    // only known afterwards if each iteration ends where it began
+                    let end = self.track_pointer(layer_instructions, pointer);
+                    if end == pointer {
+                        pointer
+                    } else {
+                        None
+                    }
+                }
+                Instruction::Goto(name) => self
+                    .aliases
+                    .get_by_left(name)
+                    .map(|address| *address as i128),
+                Instruction::Seek(index) => Some(*index as i128),
+                _ => pointer,
+            };
+        }
+
+        pointer
+    }
 
-                    None
+    /// Walk `instructions` from `pointer`, adding every cell the pointer
+    /// stops on to `reach`. Fails with the span of the first loop whose iterations
+    /// don't end where they began, as how far it moves depends on the data.
+    fn estimate_reach_layer(
+        &self,
+        instructions: &[(SourceSpan, Instruction)],
+        pointer: i128,
+        reach: &mut BTreeSet<i128>,
+    ) -> Result<i128, SourceSpan> {
+        let mut pointer = pointer;
+        for (span, instruction) in instructions {
+            pointer = match instruction {
+                Instruction::Left(count) => pointer - *count as i128,
+                Instruction::Right(count) => pointer + *count as i128,
+                Instruction::Loop(layer_instructions) => {
+                    let end = self.estimate_reach_layer(layer_instructions, pointer, reach)?;
+                    if end != pointer {
+                        return Err(*span);
+                    }
+                    pointer
                 }
-                Instruction::Left(value) => Some(format!("Move left {} spaces", value)),
-                Instruction::Right(value) => Some(format!("Move right {} spaces", value)),
-                Instruction::Input => Some("Take input".to_string()),
-                Instruction::Output => Some("Write output".to_string()),
-                Instruction::Goto(name) => Some(format!("Go to alias {}", name)),
+                Instruction::Goto(name) => match self.aliases.get_by_left(name) {
+                    Some(address) => *address as i128,
+                    None => return Err(*span),
+                },
+                Instruction::Seek(index) => *index as i128,
+                _ => pointer,
             };
+            reach.insert(pointer);
+        }
+
+        Ok(pointer)
+    }
 
-            if let Some(info) = info {
-                labeled_spans.push(LabeledSpan::new_with_span(Some(info), source_span.clone()));
+    /// Whether `body`, run with the pointer on a nonzero cell at `pointer`,
+    /// certainly leaves that cell nonzero and the pointer where it was, so a
+    /// loop around it can never finish. Anything that could end the run with
+    /// an error, like leaving the tape or overflowing a panicking cell, counts
+    /// as a way out.
+    fn loop_cannot_exit(&self, body: &[(SourceSpan, Instruction)], pointer: i128) -> bool {
+        let cell_mode = self.tape.get_cell_mode();
+        let mut offset: i128 = 0;
+        for (_, instruction) in body {
+            match instruction {
+                Instruction::Left(count) => offset -= *count as i128,
+                Instruction::Right(count) => offset += *count as i128,
+                Instruction::Add(_) | Instruction::Subtract(_) if cell_mode == CellMode::Panic => {
+                    return false
+                }
+                // A cell that can't wrap can't be brought back to zero by adding
+                Instruction::Add(_)
+                    if offset != 0
+                        || matches!(cell_mode, CellMode::Nothing | CellMode::Saturate) => {}
+                Instruction::Subtract(_)
+                | Instruction::SetZero
+                | Instruction::Set(_)
+                | Instruction::Input
+                    if offset != 0 => {}
+                Instruction::Output(_) | Instruction::DebugPrintCell | Instruction::Comment(_) => {}
+                _ => return false,
+            }
+
+            if !(0..self.tape.size() as i128).contains(&(pointer + offset)) {
+                return false;
             }
         }
 
-        labeled_spans
+        offset == 0
     }
 
-    pub fn info(&mut self) {
-        let labeled_spans: Vec<LabeledSpan> =
-            Program::produce_labeled_spans(&self.instructions);
+    /// The index of the first top-level loop that can never finish once it
+    /// starts, such as `+[]`. This is conservative: a loop is only reported if
+    /// the cell it tests is known to be nonzero when it is reached, and
+    /// nothing in it can change that cell. Analysis stops at the first
+    /// instruction whose effect depends on the data.
+    fn first_infinite_loop(&self) -> Option<usize> {
+        let mut pointer = self.tape.get_pointer() as i128;
+        // Cells whose value is known to differ from the starting tape, or
+        // isn't known at all
+        let mut changed: HashMap<i128, Option<u8>> = HashMap::new();
+        // Whether cells not in `changed` still hold their starting value
+        let mut rest_known = true;
 
-        let report = miette!(labels = labeled_spans, "{}", "Your info sheet");
-        println!(
-            "{}",
-            fmt_report((report).with_source_code(NamedSource::new(
-                self.path.to_str().unwrap(),
-                self.src.clone()
-            )))
+        for (index, (_, instruction)) in self.instructions.iter().enumerate() {
+            let value = match changed.get(&pointer) {
+                Some(value) => *value,
+                // A cell an Append tape hasn't made yet will start as the fill
+                None if rest_known => Some(
+                    self.tape
+                        .try_get_value_at_index(pointer as u128)
+                        .unwrap_or(self.tape.fill()),
+                ),
+                None => None,
+            };
+
+            match instruction {
+                Instruction::Left(count) => pointer -= *count as i128,
+                Instruction::Right(count) => pointer += *count as i128,
+                Instruction::Add(count) | Instruction::Subtract(count) => {
+                    let subtract = matches!(instruction, Instruction::Subtract(_));
+                    let value = value.and_then(|value| match self.tape.get_cell_mode() {
+                        CellMode::Circular if subtract => Some(value.wrapping_sub(*count)),
+                        CellMode::Circular => Some(value.wrapping_add(*count)),
+                        CellMode::Nothing if subtract => value.checked_sub(*count).or(Some(value)),
+                        CellMode::Nothing => value.checked_add(*count).or(Some(value)),
+                        CellMode::Saturate if subtract => Some(value.saturating_sub(*count)),
+                        CellMode::Saturate => Some(value.saturating_add(*count)),
+                        CellMode::Panic if subtract => value.checked_sub(*count),
+                        CellMode::Panic => value.checked_add(*count),
+                    });
+                    changed.insert(pointer, value);
+                }
+                Instruction::SetZero => {
+                    changed.insert(pointer, Some(0));
+                }
+                Instruction::Set(value) => {
+                    changed.insert(pointer, Some(*value));
+                }
+                Instruction::Input => {
+                    changed.insert(pointer, None);
+                }
+                Instruction::Output(_) | Instruction::DebugPrintCell | Instruction::Comment(_) => {}
+                Instruction::Goto(name) => match self.aliases.get_by_left(name) {
+                    Some(address) => pointer = self.tape.physical_index(*address) as i128,
+                    None => break,
+                },
+                Instruction::Seek(index) => pointer = self.tape.seek_index(*index) as i128,
+                Instruction::Loop(body) => match value {
+                    Some(0) => {}
+                    Some(_) if self.loop_cannot_exit(body, pointer) => return Some(index),
+                    _ => {
+                        if self.track_pointer(body, Some(pointer)) != Some(pointer) {
+                            break;
+                        }
+                        changed.clear();
+                        rest_known = false;
+                        changed.insert(pointer, Some(0));
+                    }
+                },
+            }
+
+            // Append tapes make the cells past the end as they are reached
+            let in_tape = match self.tape.get_tape_mode() {
+                TapeMode::Append => pointer >= 0,
+                _ => (0..self.tape.size() as i128).contains(&pointer),
+            };
+            if !in_tape {
+                break;
+            }
+        }
+
+        None
+    }
+
+    /// The spans of top-level loops that can never finish once they start.
+    /// Only the first can be found, as nothing after it runs.
+    pub fn infinite_loops(&self) -> Vec<SourceSpan> {
+        self.first_infinite_loop()
+            .map(|index| self.instructions[index].0)
+            .into_iter()
+            .collect()
+    }
+
+    /// The span of the instructions after a loop that can never finish,
+    /// which can never run. Comments aren't counted.
+    pub fn unreachable_code(&self) -> Option<SourceSpan> {
+        let index = self.first_infinite_loop()?;
+        let mut after = self.instructions[index + 1..]
+            .iter()
+            .filter(|(_, instruction)| !matches!(instruction, Instruction::Comment(_)))
+            .map(|(span, _)| span);
+
+        let first = after.next()?;
+        let last = after.next_back().unwrap_or(first);
+        Some((first.offset(), last.offset() + last.len() - first.offset()).into())
+    }
+
+    /// Everything worth warning about that can be found without running the
+    /// program, with the span each warning is about.
+    pub fn warnings(&self) -> Vec<(SourceSpan, String)> {
+        let mut warnings: Vec<(SourceSpan, String)> = self
+            .infinite_loops()
+            .into_iter()
+            .map(|span| (span, "This loop can never finish".to_string()))
+            .collect();
+        if let Some(span) = self.unreachable_code() {
+            warnings.push((span, "This code can never run".to_string()));
+        }
+        if let Some(warning) = self.emptiness() {
+            warnings.push(warning);
+        }
+
+        warnings
+    }
+
+    /// Why the program does nothing, if it has no instructions to run: the
+    /// file is empty or only whitespace, or all there is are comments.
+    pub fn emptiness(&self) -> Option<(SourceSpan, String)> {
+        let runnable = self
+            .instructions
+            .iter()
+            .any(|(_, instruction)| !matches!(instruction, Instruction::Comment(_)));
+        if runnable {
+            return None;
+        }
+
+        let (length, warning) = if self.stripped {
+            (0, "The program has no instructions")
+        } else if self.src.trim().is_empty() {
+            (0, "The file is empty, so there is nothing to run")
+        } else {
+            (
+                self.src.len(),
+                "The program is only comments, so there is nothing to run",
+            )
+        };
+        Some(((0, length).into(), warning.to_string()))
+    }
+
+    /// Print every warning from [`Program::warnings`] to stderr, returning
+    /// how many there were.
+    pub fn report_warnings(&self) -> usize {
+        let warnings = self.warnings();
+        for (span, warning) in &warnings {
+            self.report_warning(warning, *span);
+        }
+
+        warnings.len()
+    }
+
+    /// How much one pass through `body` changes the cell the loop tests, or
+    /// `None` if that depends on the data, such as when it reads input into
+    /// the cell or contains another loop.
+    fn control_delta(body: &[(SourceSpan, Instruction)]) -> Option<i128> {
+        let mut offset: i128 = 0;
+        let mut delta: i128 = 0;
+        for (_, instruction) in body {
+            match instruction {
+                Instruction::Left(count) => offset -= *count as i128,
+                Instruction::Right(count) => offset += *count as i128,
+                Instruction::Add(count) if offset == 0 => delta += *count as i128,
+                Instruction::Subtract(count) if offset == 0 => delta -= *count as i128,
+                Instruction::SetZero | Instruction::Set(_) | Instruction::Input if offset == 0 => {
+                    return None
+                }
+                Instruction::Loop(_) | Instruction::Goto(_) | Instruction::Seek(_) => return None,
+                _ => (),
+            }
+        }
+
+        Some(delta)
+    }
+
+    /// The most steps a run can take, found by adding up the cost of each
+    /// instruction, or `None` if a loop makes it depend on the data.
+    pub fn step_estimate(&self) -> Option<u64> {
+        self.instructions
+            .iter()
+            .try_fold(0u64, |total, (_, instruction)| {
+                total.checked_add(instruction.cost()?)
+            })
+    }
+
+    /// A line describing [`Program::step_estimate`], naming the first loop
+    /// that stops the estimate if there is one.
+    pub fn cost_report(&self) -> String {
+        match self.step_estimate() {
+            Some(steps) => format!("Worst case: {} steps\n", steps),
+            None => {
+                let unbounded = self
+                    .instructions
+                    .iter()
+                    .find(|(_, instruction)| instruction.cost().is_none());
+                match unbounded {
+                    Some((span, _)) => {
+                        let (line, column) = line_col(&self.src, *span);
+                        format!(
+                            "Worst case: unbounded, the loop at {}:{} depends on the data\n",
+                            line, column
+                        )
+                    }
+                    None => "Worst case: unbounded\n".to_string(),
+                }
+            }
+        }
+    }
+
+    /// A table of every loop, saying whether each pass through it leaves the
+    /// pointer where it started and how much it changes the cell the loop
+    /// tests. Only balanced loops with a known change can be optimised as a
+    /// whole.
+    pub fn loop_balance(&self) -> String {
+        let rows: Vec<(String, &str, String)> = self
+            .instructions_flat()
+            .filter_map(|(_, span, instruction)| match instruction {
+                Instruction::Loop(body) => Some((span, body)),
+                _ => None,
+            })
+            .map(|(span, body)| {
+                let (line, column) = line_col(&self.src, span);
+                let balanced = match self.track_pointer(body, Some(0)) {
+                    Some(0) => "yes",
+                    Some(_) => "no",
+                    None => "unknown",
+                };
+                let delta = match Program::control_delta(body) {
+                    Some(delta) => format!("{:+}", delta),
+                    None => "unknown".to_string(),
+                };
+                (format!("{}:{}", line, column), balanced, delta)
+            })
+            .collect();
+
+        let width = rows
+            .iter()
+            .map(|(position, _, _)| position.len())
+            .max()
+            .unwrap_or(0)
+            .max("Loop".len());
+
+        let mut out = format!(
+            "{:<width$}  Balanced  Control delta\n",
+            "Loop",
+            width = width
         );
+        for (position, balanced, delta) in rows {
+            out.push_str(&format!(
+                "{:<width$}  {:<8}  {}\n",
+                position,
+                balanced,
+                delta,
+                width = width
+            ));
+        }
 
-        process::exit(0);
+        out
+    }
+
+    /// An estimate of how much of the tape the program uses, worked out from
+    /// the instructions without running them. Aliases must be allocated
+    /// first with `setup`.
+    pub fn tape_preview(&self) -> String {
+        // Positions are relative to the starting cell, so can be negative
+        let mut reach = BTreeSet::from([0]);
+        let mut out = match self.estimate_reach_layer(&self.instructions, 0, &mut reach) {
+            Ok(_) => format!(
+                "Pointer visits {} cells, between {} and {}\n",
+                reach.len(),
+                reach.first().unwrap(),
+                reach.last().unwrap()
+            ),
+            Err(span) => {
+                let (line, column) = line_col(&self.src, span);
+                format!(
+                    "Pointer reach is unbounded: the instruction at {}:{} moves it by a data-dependent amount\n",
+                    line, column
+                )
+            }
+        };
+
+        let mut addresses: Vec<&u128> = self.aliases.right_values().collect();
+        addresses.sort();
+        if !addresses.is_empty() {
+            let addresses: Vec<String> = addresses
+                .iter()
+                .map(|address| address.to_string())
+                .collect();
+            out.push_str(&format!("Aliases occupy cells {}\n", addresses.join(", ")));
+        }
+
+        out
+    }
+
+    /// The error for a move to `target`, such as an alias, that can't be
+    /// made relative because the pointer position isn't known at `span`.
+    fn unknown_position(span: SourceSpan, target: String) -> (SourceSpan, BFError) {
+        (
+            span,
+            BFError::new(
+                BFErrors::RuntimeError,
+                format!(
+                    "{} can't be lowered to a relative move as the pointer position isn't known here",
+                    target
+                ),
+            ),
+        )
+    }
+
+    fn lower_gotos_layer(
+        &self,
+        instructions: &[(SourceSpan, Instruction)],
+        pointer: Option<i128>,
+        unresolved: &mut Vec<(SourceSpan, BFError)>,
+    ) -> (Vec<(SourceSpan, Instruction)>, Option<i128>) {
+        let mut lowered: Vec<(SourceSpan, Instruction)> = vec![];
+        let mut pointer = pointer;
+
+        for (span, instruction) in instructions {
+            match instruction {
+                Instruction::Loop(layer_instructions) => {
+                    let end = self.track_pointer(layer_instructions, pointer);
+                    let entry = if end == pointer { pointer } else { None };
+
+                    let (layer_instructions, _) =
+                        self.lower_gotos_layer(layer_instructions, entry, unresolved);
+                    lowered.push((*span, Instruction::Loop(layer_instructions)));
+                    pointer = entry;
+                }
+                Instruction::Goto(name) => {
+                    let address = self
+                        .aliases
+                        .get_by_left(name)
+                        .map(|address| *address as i128);
+                    match (pointer, address) {
+                        (Some(from), Some(to)) if to >= from => {
+                            lowered.push((*span, Instruction::Right((to - from) as u128)))
+                        }
+                        (Some(from), Some(to)) => {
+                            lowered.push((*span, Instruction::Left((from - to) as u128)))
+                        }
+                        _ => {
+                            unresolved
+                                .push(Program::unknown_position(*span, format!("Alias {}", name)));
+                            lowered.push((*span, instruction.clone()));
+                        }
+                    }
+                    pointer = address;
+                }
+                instruction => {
+                    pointer = self.track_pointer(&[(*span, instruction.clone())], pointer);
+                    lowered.push((*span, instruction.clone()));
+                }
+            }
+        }
+
+        (lowered, pointer)
+    }
+
+    /// Replace every `Goto` with a relative `Left`/`Right`, by following the
+    /// pointer from the start of the program. `setup` must be run first.
+    ///
+    /// Any `Goto` where the pointer position can't be known statically (for
+    /// example after a loop that doesn't return to where it started) is left
+    /// in place and reported.
+    pub fn lower_gotos(&mut self) -> Vec<(SourceSpan, BFError)> {
+        let mut unresolved = vec![];
+        let (instructions, _) =
+            self.lower_gotos_layer(&self.instructions, Some(0), &mut unresolved);
+        self.instructions = instructions;
+
+        unresolved
+    }
+
+    /// Move the pointer from `from` to `to` using plain brainfuck moves.
+    fn emit_moves(out: &mut String, from: i128, to: i128) {
+        if to > from {
+            out.push_str(&">".repeat((to - from) as usize));
+        } else {
+            out.push_str(&"<".repeat((from - to) as usize));
+        }
+    }
+
+    /// Lower the given instructions into plain brainfuck, tracking where the
+    /// pointer is so that aliases and seeks can be replaced with relative
+    /// moves. Returns where the pointer ends up, if it can be known.
+    ///
+    /// Like [`Program::lower_gotos`], the position is only known after a loop
+    /// that ends where it began, and any `Goto` or `Seek` where it isn't is
+    /// reported rather than guessed.
+    fn emit_brainfuck_layer(
+        &self,
+        instructions: &[(SourceSpan, Instruction)],
+        out: &mut String,
+        pointer: Option<i128>,
+        unresolved: &mut Vec<(SourceSpan, BFError)>,
+    ) -> Option<i128> {
+        let mut pointer = pointer;
+        for (span, instruction) in instructions {
+            match instruction {
+                Instruction::Add(count) => out.push_str(&"+".repeat(*count as usize)),
+                Instruction::Subtract(count) => out.push_str(&"-".repeat(*count as usize)),
+                Instruction::Loop(layer_instructions) => {
+                    let end = self.track_pointer(layer_instructions, pointer);
+                    let entry = if end == pointer { pointer } else { None };
+
+                    out.push('[');
+                    self.emit_brainfuck_layer(layer_instructions, out, entry, unresolved);
+                    out.push(']');
+                    pointer = entry;
+                }
+                Instruction::Left(count) => {
+                    out.push_str(&"<".repeat(*count as usize));
+                    pointer = pointer.map(|pointer| pointer - *count as i128);
+                }
+                Instruction::Right(count) => {
+                    out.push_str(&">".repeat(*count as usize));
+                    pointer = pointer.map(|pointer| pointer + *count as i128);
+                }
+                Instruction::Input => out.push(','),
+                Instruction::Output(count) => out.push_str(&".".repeat(*count as usize)),
+                Instruction::SetZero => out.push_str("[-]"),
+                Instruction::Set(value) => {
+                    out.push_str("[-]");
+                    out.push_str(&"+".repeat(*value as usize));
+                }
+                // Plain brainfuck has nothing like this
+                Instruction::DebugPrintCell => (),
+                Instruction::Goto(name) => {
+                    if let Some(address) = self.aliases.get_by_left(name) {
+                        let address = *address as i128;
+                        match pointer {
+                            Some(from) => Program::emit_moves(out, from, address),
+                            None => unresolved
+                                .push(Program::unknown_position(*span, format!("Alias {}", name))),
+                        }
+                        pointer = Some(address);
+                    }
+                }
+                Instruction::Seek(index) => {
+                    let index = *index as i128;
+                    match pointer {
+                        Some(from) => Program::emit_moves(out, from, index),
+                        None => {
+                            unresolved.push(Program::unknown_position(*span, format!("@{}", index)))
+                        }
+                    }
+                    pointer = Some(index);
+                }
+                // Comment text could contain brainfuck commands
+                Instruction::Comment(_) => (),
+            }
+        }
+
+        pointer
+    }
+
+    /// Produce portable brainfuck source for this program. Aliases and seeks
+    /// are lowered into pointer moves, so `setup` must be run first. Those
+    /// the pointer position isn't known for are returned as errors.
+    pub fn emit_brainfuck(&self) -> Result<String, Vec<(SourceSpan, BFError)>> {
+        let mut out = String::new();
+        let mut unresolved = vec![];
+        self.emit_brainfuck_layer(&self.instructions, &mut out, Some(0), &mut unresolved);
+
+        if unresolved.is_empty() {
+            Ok(out)
+        } else {
+            Err(unresolved)
+        }
+    }
+
+    fn emit_rust_layer(
+        &self,
+        instructions: &[(SourceSpan, Instruction)],
+        out: &mut String,
+        depth: usize,
+    ) {
+        let indent = "    ".repeat(depth);
+        let size = self.tape.size();
+        // Cells follow the cell mode, as the interpreter's would
+        let (add, sub) = match self.tape.get_cell_mode() {
+            CellMode::Circular => ("wrapping_add({})", "wrapping_sub({})"),
+            CellMode::Nothing => (
+                "checked_add({}).unwrap_or(arr[ptr])",
+                "checked_sub({}).unwrap_or(arr[ptr])",
+            ),
+            CellMode::Saturate => ("saturating_add({})", "saturating_sub({})"),
+            CellMode::Panic => (
+                "checked_add({}).expect(\"cell went above 255\")",
+                "checked_sub({}).expect(\"cell went below 0\")",
+            ),
+        };
+
+        for (_span, instruction) in instructions {
+            let line = match instruction {
+                Instruction::Add(count) => {
+                    format!(
+                        "arr[ptr] = arr[ptr].{};",
+                        add.replace("{}", &count.to_string())
+                    )
+                }
+                Instruction::Subtract(count) => {
+                    format!(
+                        "arr[ptr] = arr[ptr].{};",
+                        sub.replace("{}", &count.to_string())
+                    )
+                }
+                Instruction::Loop(layer_instructions) => {
+                    out.push_str(&format!("{}while arr[ptr] != 0 {{\n", indent));
+                    self.emit_rust_layer(layer_instructions, out, depth + 1);
+                    out.push_str(&format!("{}}}\n", indent));
+                    continue;
+                }
+                Instruction::Left(count) => {
+                    format!("ptr = (ptr + {}) % {};", size - count % size, size)
+                }
+                Instruction::Right(count) => format!("ptr = (ptr + {}) % {};", count % size, size),
+                Instruction::Input => "arr[ptr] = read_byte(&mut input);".to_string(),
+                Instruction::Output(1) => "output.write_all(&[arr[ptr]]).unwrap();".to_string(),
+                Instruction::Output(count) => {
+                    format!("output.write_all(&[arr[ptr]; {}]).unwrap();", count)
+                }
+                Instruction::SetZero => "arr[ptr] = 0;".to_string(),
+                Instruction::Set(value) => format!("arr[ptr] = {};", value),
+                Instruction::DebugPrintCell => {
+                    "eprintln!(\"pointer {}, value {}\", ptr, arr[ptr]);".to_string()
+                }
+                Instruction::Goto(name) => match self.aliases.get_by_left(name) {
+                    Some(address) => format!(
+                        "ptr = {}; // {{{}}}",
+                        self.tape.physical_index(*address),
+                        name
+                    ),
+                    None => format!("// {{{}}} was never allocated", name),
+                },
+                Instruction::Seek(index) => format!("ptr = {};", index % size),
+                Instruction::Comment(text) => format!("// {}", text),
+            };
+            out.push_str(&format!("{}{}\n", indent, line));
+        }
+    }
+
+    /// Produce a standalone Rust program that does what this one does, for
+    /// building into a native binary with no dependencies. The tape is a
+    /// fixed array that wraps round at the ends, and aliases become
+    /// assignments to the pointer, so `setup` must be run first.
+    pub fn emit_rust(&self) -> String {
+        let mut body = String::new();
+        self.emit_rust_layer(&self.instructions, &mut body, 1);
+
+        format!(
+            "// Generated by bfem from {}\n\
+             #![allow(unused)]\n\
+             \n\
+             use std::io::{{Read, Write}};\n\
+             \n\
+             fn read_byte(input: &mut impl Read) -> u8 {{\n\
+             \x20   let mut byte = [0];\n\
+             \x20   match input.read(&mut byte) {{\n\
+             \x20       Ok(1) => byte[0],\n\
+             \x20       _ => 0,\n\
+             \x20   }}\n\
+             }}\n\
+             \n\
+             fn main() {{\n\
+             \x20   let mut arr = vec![{}u8; {}];\n\
+             \x20   let mut ptr: usize = 0;\n\
+             \x20   let mut input = std::io::stdin();\n\
+             \x20   let mut output = std::io::stdout();\n\
+             \n\
+             {}\
+             \x20   output.flush().unwrap();\n\
+             }}\n",
+            self.path.display(),
+            self.tape.fill(),
+            self.tape.size(),
+            body
+        )
+    }
+
+    /// Add the nodes and edges for `instructions` to a control-flow graph,
+    /// starting from node `from` with an edge labelled `label`. Runs of
+    /// simple instructions become one box, and each loop a diamond that
+    /// tests the cell. Returns the node control leaves from, and the label
+    /// for the edge out of it.
+    fn graph_layer(
+        &self,
+        instructions: &[(SourceSpan, Instruction)],
+        mut from: usize,
+        mut label: &'static str,
+        nodes: &mut Vec<String>,
+        edges: &mut Vec<String>,
+    ) -> (usize, &'static str) {
+        let escape = |text: &str| text.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut block: Vec<String> = Vec::new();
+
+        let flush = |block: &mut Vec<String>,
+                     from: &mut usize,
+                     label: &mut &'static str,
+                     nodes: &mut Vec<String>,
+                     edges: &mut Vec<String>| {
+            if block.is_empty() {
+                return;
+            }
+            let node = nodes.len();
+            let text: String = block.drain(..).map(|line| line + "\\l").collect();
+            nodes.push(format!("  n{} [shape=box, label=\"{}\"];", node, text));
+            edges.push(format!("  n{} -> n{} [label=\"{}\"];", from, node, label));
+            *from = node;
+            *label = "";
+        };
+
+        for (span, instruction) in instructions {
+            match instruction {
+                Instruction::Loop(body) => {
+                    flush(&mut block, &mut from, &mut label, nodes, edges);
+
+                    let (line, column) = line_col(&self.src, *span);
+                    let name = match self.loop_label(*span) {
+                        Some(name) => format!("loop {} at {}:{}", name, line, column),
+                        None => format!("loop at {}:{}", line, column),
+                    };
+                    let header = nodes.len();
+                    nodes.push(format!(
+                        "  n{} [shape=diamond, label=\"{}\"];",
+                        header,
+                        escape(&name)
+                    ));
+                    edges.push(format!("  n{} -> n{} [label=\"{}\"];", from, header, label));
+
+                    let (end, end_label) = self.graph_layer(body, header, "nonzero", nodes, edges);
+                    edges.push(format!(
+                        "  n{} -> n{} [label=\"{}\", style=dashed];",
+                        end,
+                        header,
+                        if end_label.is_empty() {
+                            "repeat"
+                        } else {
+                            end_label
+                        }
+                    ));
+
+                    from = header;
+                    label = "zero";
+                }
+                Instruction::Comment(_) => (),
+                _ => block.push(escape(&instruction.to_string())),
+            }
+        }
+        flush(&mut block, &mut from, &mut label, nodes, edges);
+
+        (from, label)
+    }
+
+    /// The program's control flow as a Graphviz DOT graph. Loops have a
+    /// dashed back-edge from the end of their body to their test, and an
+    /// edge labelled `zero` to whatever runs after them.
+    pub fn control_flow_dot(&self) -> String {
+        let mut nodes = vec!["  n0 [shape=oval, label=\"start\"];".to_string()];
+        let mut edges = Vec::new();
+
+        let (last, label) = self.graph_layer(&self.instructions, 0, "", &mut nodes, &mut edges);
+        let end = nodes.len();
+        nodes.push(format!("  n{} [shape=oval, label=\"end\"];", end));
+        edges.push(format!("  n{} -> n{} [label=\"{}\"];", last, end, label));
+
+        format!(
+            "digraph program {{\n{}\n{}\n}}\n",
+            nodes.join("\n"),
+            edges.join("\n")
+        )
+    }
+
+    /// Whether the comment at `span` follows code on the same line of the
+    /// source, rather than being on a line of its own.
+    fn is_inline_comment(&self, span: SourceSpan) -> bool {
+        let before = &self.src[..span.offset().min(self.src.len())];
+        let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+        !before[line_start..].trim().is_empty()
+    }
+
+    /// Write `instructions` as BFEM source. Runs of simple instructions
+    /// share a line, while loops and comments on their own line get lines of
+    /// their own, with loop bodies indented one level. Comments that follow
+    /// code stay at the end of its line.
+    fn emit_source_layer(
+        &self,
+        instructions: &[(SourceSpan, Instruction)],
+        depth: usize,
+        out: &mut String,
+    ) {
+        let indent = self.indent.unit().repeat(depth);
+        let mut line = String::new();
+        let flush = |line: &mut String, out: &mut String| {
+            if !line.is_empty() {
+                out.push_str(&format!("{}{}\n", indent, line));
+                line.clear();
+            }
+        };
+
+        for (span, instruction) in instructions {
+            match instruction {
+                Instruction::Add(1) => line.push('+'),
+                Instruction::Subtract(1) => line.push('-'),
+                Instruction::Add(count) => line.push_str(&format!("+{}", count)),
+                Instruction::Subtract(count) => line.push_str(&format!("-{}", count)),
+                Instruction::Left(count) => line.push_str(&"<".repeat(*count as usize)),
+                Instruction::Right(count) => line.push_str(&">".repeat(*count as usize)),
+                Instruction::Input => line.push(','),
+                Instruction::Output(count) => line.push_str(&".".repeat(*count as usize)),
+                Instruction::SetZero => line.push_str("[-]"),
+                Instruction::Set(value) => line.push_str(&format!("[-]+{}", value)),
+                Instruction::DebugPrintCell => line.push('?'),
+                Instruction::Goto(name) => line.push_str(&format!("{{{}}}", name)),
+                Instruction::Seek(index) => line.push_str(&format!("@{}", index)),
+                Instruction::Loop(layer_instructions) => {
+                    flush(&mut line, out);
+                    match self.loop_label(*span) {
+                        Some(label) => out.push_str(&format!("{}{{:{} [\n", indent, label)),
+                        None => out.push_str(&format!("{}[\n", indent)),
+                    }
+                    self.emit_source_layer(layer_instructions, depth + 1, out);
+                    match self.loop_label(*span) {
+                        Some(_) => out.push_str(&format!("{}]}}\n", indent)),
+                        None => out.push_str(&format!("{}]\n", indent)),
+                    }
+                }
+                Instruction::Comment(text) if self.is_inline_comment(*span) => {
+                    // Keep it at the end of the line it followed, which may
+                    // already be written out if it closed or opened a loop
+                    if line.is_empty() && !out.is_empty() {
+                        out.pop();
+                        out.push_str(&format!(" # {}\n", text));
+                    } else {
+                        line.push_str(&format!(" # {}", text));
+                        flush(&mut line, out);
+                    }
+                }
+                Instruction::Comment(text) => {
+                    flush(&mut line, out);
+                    out.push_str(&format!("{}# {}\n", indent, text));
+                }
+            }
+        }
+        flush(&mut line, out);
+    }
+
+    /// Produce BFEM source for this program, keeping aliases, `decl` blocks
+    /// and (if they were parsed) comments.
+    pub fn emit_source(&self) -> String {
+        let mut out = String::new();
+        let mut rest: &[(SourceSpan, Instruction)] = &self.instructions;
+        if let Some(parser) = &self.parser {
+            if let Some(offset) = parser.get_declaration_offset() {
+                // Put the declarations back where they were, after anything
+                // (such as comments) that came before them
+                let before = rest.partition_point(|(span, _)| span.offset() < offset);
+                self.emit_source_layer(&rest[..before], 0, &mut out);
+                out.push_str(&format!("decl {{ {} }}\n", parser.get_declared().join(" ")));
+                rest = &rest[before..];
+            }
+        }
+
+        self.emit_source_layer(rest, 0, &mut out);
+        out
+    }
+
+    /// Write `instructions` as compactly as BFEM allows, adding aliases to
+    /// `used` in the order they first appear.
+    fn minify_layer(
+        &self,
+        instructions: &[(SourceSpan, Instruction)],
+        out: &mut String,
+        used: &mut Vec<String>,
+    ) {
+        // A literal only saves space once it is shorter than the run
+        let arithmetic = |symbol: char, count: u8| {
+            let literal = format!("{}{}", symbol, count);
+            let run = symbol.to_string().repeat(count as usize);
+            if literal.len() < run.len() {
+                literal
+            } else {
+                run
+            }
+        };
+
+        let mut index = 0;
+        while index < instructions.len() {
+            let (_, instruction) = &instructions[index];
+            index += 1;
+            match instruction {
+                Instruction::Add(count) => out.push_str(&arithmetic('+', *count)),
+                Instruction::Subtract(count) => out.push_str(&arithmetic('-', *count)),
+                Instruction::Left(count) => out.push_str(&"<".repeat(*count as usize)),
+                Instruction::Right(count) => out.push_str(&">".repeat(*count as usize)),
+                Instruction::Input => out.push(','),
+                Instruction::Output(count) => out.push_str(&".".repeat(*count as usize)),
+                Instruction::SetZero => out.push_str("[-]"),
+                Instruction::Set(value) => {
+                    out.push_str("[-]");
+                    out.push_str(&arithmetic('+', *value));
+                }
+                Instruction::DebugPrintCell => out.push('?'),
+                Instruction::Goto(name) => {
+                    if !used.contains(name) {
+                        used.push(name.clone());
+                    }
+                    // `{name}[-]+N` is what `{name=N}` parses to, and what it
+                    // folds into
+                    match &instructions[index..] {
+                        [(_, Instruction::SetZero), (_, Instruction::Add(value)), ..] => {
+                            out.push_str(&format!("{{{}={}}}", name, value));
+                            index += 2;
+                        }
+                        [(_, Instruction::Set(value)), ..] => {
+                            out.push_str(&format!("{{{}={}}}", name, value));
+                            index += 1;
+                        }
+                        _ => out.push_str(&format!("{{{}}}", name)),
+                    }
+                }
+                Instruction::Seek(index) => out.push_str(&format!("@{}", index)),
+                Instruction::Loop(layer_instructions) => {
+                    out.push('[');
+                    self.minify_layer(layer_instructions, out, used);
+                    out.push(']');
+                }
+                Instruction::Comment(_) => (),
+            }
+        }
+    }
+
+    /// The shortest BFEM source this module can produce that runs the same
+    /// way: the optimised instructions with no comments, whitespace or
+    /// labels, and literals where they are shorter than runs. Aliases are
+    /// only declared when that is needed to keep them in the same cells.
+    pub fn emit_minified(&self) -> String {
+        let mut body = String::new();
+        let mut used = vec![];
+        self.minify_layer(&self.instructions, &mut body, &mut used);
+
+        let names = self.alias_names();
+        if used == names {
+            body
+        } else {
+            format!("decl{{{}}}{}", names.join(" "), body)
+        }
+    }
+
+    /// Every instruction in order with how deeply it is nested, where loop
+    /// bodies follow their loop one level deeper.
+    pub fn instructions_flat(&self) -> InstructionsFlat<'_> {
+        InstructionsFlat {
+            stack: vec![self.instructions.iter()],
+        }
+    }
+
+    /// The label given to the loop at `span` with `{:label [ ... ]}`.
+    pub fn loop_label(&self, span: SourceSpan) -> Option<&String> {
+        self.parser
+            .as_ref()
+            .and_then(|parser| parser.get_loop_label(span.offset()))
+    }
+
+    /// Labels for the info sheet. Loops are only shown if they are labelled,
+    /// and then just on their opening bracket.
+    fn produce_labeled_spans(&self) -> Vec<LabeledSpan> {
+        self.instructions_flat()
+            .filter_map(|(_depth, source_span, instruction)| match instruction {
+                Instruction::Loop(_) => self.loop_label(source_span).map(|label| {
+                    LabeledSpan::new_with_span(
+                        Some(format!("loop {}", label)),
+                        (source_span.offset(), 1),
+                    )
+                }),
+                instruction => Some(LabeledSpan::new_with_span(
+                    Some(instruction.to_string()),
+                    source_span,
+                )),
+            })
+            .collect()
+    }
+
+    /// (offset, length, description) for every instruction, including those
+    /// inside loops, for editor tooling.
+    pub fn source_map(&self) -> Vec<(usize, usize, String)> {
+        self.instructions_flat()
+            .map(|(_depth, source_span, instruction)| {
+                (
+                    source_span.offset(),
+                    source_span.len(),
+                    instruction.to_string(),
+                )
+            })
+            .collect()
+    }
+
+    /// The source map as a JSON array of `{offset, length, description}`.
+    pub fn source_map_json(&self) -> String {
+        let entries: Vec<String> = self
+            .source_map()
+            .into_iter()
+            .map(|(offset, length, description)| {
+                format!(
+                    "{{\"offset\":{},\"length\":{},\"description\":{}}}",
+                    offset,
+                    length,
+                    json_string(&description)
+                )
+            })
+            .collect();
+
+        format!("[{}]", entries.join(","))
+    }
+
+    /// How many of each kind of instruction `instructions` holds, counting
+    /// those inside loops, in the order each kind first appears.
+    fn kind_counts(instructions: &[(SourceSpan, Instruction)]) -> Vec<(&'static str, usize)> {
+        let flat = InstructionsFlat {
+            stack: vec![instructions.iter()],
+        };
+        let mut counts: Vec<(&'static str, usize)> = vec![];
+        for (_, _, instruction) in flat {
+            match counts
+                .iter_mut()
+                .find(|(kind, _)| *kind == instruction.kind())
+            {
+                Some((_, count)) => *count += 1,
+                None => counts.push((instruction.kind(), 1)),
+            }
+        }
+
+        counts
+    }
+
+    /// Walk through the optimisation passes one at a time, giving the
+    /// instruction count after each and which instructions it replaced.
+    pub fn optimisation_report(&self) -> String {
+        let Some(parser) = &self.parser else {
+            return "No source to optimise, so there is nothing to show\n".to_string();
+        };
+
+        let snapshots = parser.pass_snapshots();
+        let total = |counts: &[(&str, usize)]| counts.iter().map(|(_, count)| count).sum::<usize>();
+        let mut before = Program::kind_counts(&snapshots[0].instructions);
+        let mut report = format!("unoptimised: {} instructions\n", total(&before));
+        if snapshots.len() == 1 {
+            report.push_str("No passes are enabled\n");
+        }
+
+        for snapshot in &snapshots[1..] {
+            let after = Program::kind_counts(&snapshot.instructions);
+            let count = |counts: &[(&str, usize)], kind: &str| {
+                counts
+                    .iter()
+                    .find(|(other, _)| *other == kind)
+                    .map_or(0, |(_, count)| *count)
+            };
+            let mut kinds: Vec<&str> = before.iter().map(|(kind, _)| *kind).collect();
+            kinds.extend(
+                after
+                    .iter()
+                    .map(|(kind, _)| *kind)
+                    .filter(|kind| count(&before, kind) == 0),
+            );
+            let changed: Vec<(&str, usize, usize)> = kinds
+                .into_iter()
+                .map(|kind| (kind, count(&before, kind), count(&after, kind)))
+                .filter(|(_, old, new)| old != new)
+                .collect();
+            let describe = |counts: Vec<(&str, usize)>| {
+                counts
+                    .into_iter()
+                    .filter(|(_, count)| *count != 0)
+                    .map(|(kind, count)| format!("{} {}", count, kind))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            };
+            let removed = describe(changed.iter().map(|(kind, old, _)| (*kind, *old)).collect());
+            let added = describe(changed.iter().map(|(kind, _, new)| (*kind, *new)).collect());
+            let summary = match (removed.is_empty(), added.is_empty()) {
+                (true, true) => "no changes".to_string(),
+                (false, true) => format!("removed {}", removed),
+                (true, false) => format!("added {}", added),
+                (false, false) => format!("replaced {} with {}", removed, added),
+            };
+
+            report.push_str(&format!(
+                "{}: {} -> {} instructions, {}\n",
+                snapshot
+                    .pass
+                    .expect("Only the first snapshot has no pass")
+                    .name(),
+                total(&before),
+                total(&after),
+                summary
+            ));
+            before = after;
+        }
+
+        report
+    }
+
+    /// The whole source with each line numbered, in the same style as the
+    /// info sheet, so lines its labels point at are easy to find.
+    pub fn numbered_source(&self) -> String {
+        let width = self.src.lines().count().max(1).to_string().len();
+        self.src
+            .lines()
+            .enumerate()
+            .map(|(index, line)| format!("{:>width$} │ {}\n", index + 1, line, width = width))
+            .collect()
+    }
+
+    pub fn info(&self) {
+        let labeled_spans: Vec<LabeledSpan> = self.produce_labeled_spans();
+
+        let report = miette!(labels = labeled_spans, "{}", "Your info sheet");
+        println!(
+            "{}",
+            fmt_report((report).with_source_code(NamedSource::new(
+                self.path.to_str().unwrap(),
+                self.src.clone()
+            )))
+        );
+
+        process::exit(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{Pass, Passes};
+
+    fn parse_with(src: &str, tape: Tape, flag: DisableFlags) -> Program {
+        Program::parse(
+            PathBuf::from("test.bfem"),
+            src.to_string(),
+            tape,
+            flag,
+            ParseFlags::default(),
+        )
+        .unwrap_or_else(|errors| panic!("{} failed to parse: {:?}", src, errors))
+    }
+
+    fn parse(src: &str) -> Program {
+        parse_with(src, Tape::default(), DisableFlags::default())
+    }
+
+    /// Run `program` with its output captured, returning what it wrote.
+    fn run(program: &mut Program) -> Vec<u8> {
+        program.set_capture_output();
+        program.setup().unwrap();
+        program.run();
+        program.output_bytes().to_vec()
+    }
+
+    /// Step through `program` until it fails, returning the error.
+    fn run_error(program: &mut Program) -> BFError {
+        program.discard_output();
+        program.setup().unwrap();
+        loop {
+            match program.step() {
+                Ok(outcome) if outcome.finished => panic!("program finished without an error"),
+                Ok(_) => {}
+                Err(error) => return error,
+            }
+        }
+    }
+
+    #[test]
+    fn runs_with_the_same_seed_write_the_same_output() {
+        let outputs: Vec<Vec<u8>> = (0..2)
+            .map(|_| {
+                let mut program = parse(",.,.,.[-],.");
+                program.set_input_pattern(InputPattern::Seed(1234));
+                run(&mut program)
+            })
+            .collect();
+
+        assert_eq!(outputs[0], outputs[1]);
+        assert_eq!(outputs[0].len(), 4);
+    }
+
+    #[test]
+    fn emit_brainfuck_lowers_gotos_into_moves_from_the_start() {
+        let mut program = parse_with(
+            "{a}+{b}++{a}.",
+            Tape::builder().size(10).build(),
+            DisableFlags::default(),
+        );
+        program.setup().unwrap();
+
+        // `a` is the last cell and `b` the one before it
+        assert_eq!(program.emit_brainfuck().unwrap(), ">>>>>>>>>+<++>.");
+    }
+
+    #[test]
+    fn emit_brainfuck_rejects_moves_from_an_unknown_position() {
+        let unresolved = |src: &str| {
+            let mut program = parse_with(
+                src,
+                Tape::builder().size(10).build(),
+                DisableFlags::default(),
+            );
+            program.setup().unwrap();
+            program.emit_brainfuck().map_err(|errors| {
+                errors
+                    .into_iter()
+                    .map(|(span, error)| (span.offset(), error.message))
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        assert_eq!(
+            unresolved("{a},[>]{b}+"),
+            Err(vec![(
+                7,
+                "Alias b can't be lowered to a relative move as the pointer position isn't known here"
+                    .to_string()
+            )])
+        );
+        assert_eq!(
+            unresolved(",[>]@2+"),
+            Err(vec![(
+                4,
+                "@2 can't be lowered to a relative move as the pointer position isn't known here"
+                    .to_string()
+            )])
+        );
+        // Once the position is known again, later moves are fine
+        assert_eq!(
+            unresolved(",[>]@2{a}+").map_err(|errors| errors.len()),
+            Err(1)
+        );
+        assert_eq!(
+            unresolved("{a},[>+<-]{b}+"),
+            Ok(">>>>>>>>>,[>+<-]<+".to_string())
+        );
+    }
+
+    #[test]
+    fn emit_brainfuck_expands_counts() {
+        let mut program = parse("+++>>--<.");
+        program.setup().unwrap();
+
+        assert_eq!(program.emit_brainfuck().unwrap(), "+++>>--<.");
+
+        let mut program = parse(",[-]+++.");
+        program.setup().unwrap();
+        assert_eq!(program.emit_brainfuck().unwrap(), ",[-]+++.");
+    }
+
+    #[test]
+    fn running_out_of_cells_for_aliases_is_an_error() {
+        let mut program = parse_with(
+            "{a}{b}{c}",
+            Tape::builder().size(2).build(),
+            DisableFlags::default(),
+        );
+
+        let error = program.setup().unwrap_err();
+        assert!(error
+            .message
+            .contains("room for 2 more aliases, but 3 are used"));
+    }
+
+    #[test]
+    fn running_out_of_cells_while_running_is_an_error() {
+        // Without preallocation, aliases are given cells as they are reached
+        let flag = DisableFlags {
+            disable_alloc: true,
+            ..DisableFlags::default()
+        };
+        let mut program = parse_with("{a}{b}{c}", Tape::builder().size(2).build(), flag);
+        program.setup().unwrap();
+
+        assert!(program.step().is_ok());
+        assert!(program.step().is_ok());
+        let error = program.step().unwrap_err();
+        assert_eq!(error.message, "No free cell available for alias c");
+    }
+
+    #[test]
+    fn alias_table_shows_where_each_alias_landed() {
+        let mut program = parse_with(
+            "{beta}+{alpha}+",
+            Tape::builder().size(10).build(),
+            DisableFlags::default(),
+        );
+        program.setup().unwrap();
+
+        // Sorted by name, with cells given out from the end in order of use
+        assert_eq!(
+            program.alias_table(),
+            "Alias  Address\nalpha  8\nbeta   9\n"
+        );
+    }
+
+    #[test]
+    fn instructions_display_for_users() {
+        let body = vec![((0, 1).into(), Instruction::Add(1)); 2];
+        let expected = [
+            (Instruction::Add(3), "Add 3"),
+            (Instruction::Subtract(2), "Subtract 2"),
+            (Instruction::Loop(body), "loop (2 instrs)"),
+            (Instruction::Left(4), "Move left 4 spaces"),
+            (Instruction::Right(5), "Move right 5 spaces"),
+            (Instruction::Input, "Take input"),
+            (Instruction::Output(6), "Write output 6 times"),
+            (Instruction::SetZero, "Set to zero"),
+            (Instruction::DebugPrintCell, "Print current cell"),
+            (
+                Instruction::Goto("counter".to_string()),
+                "Go to alias counter",
+            ),
+            (Instruction::Seek(7), "Seek to cell 7"),
+            (Instruction::Comment("note".to_string()), "Comment: note"),
+        ];
+        for (instruction, text) in expected {
+            assert_eq!(instruction.to_string(), text);
+        }
+    }
+
+    #[test]
+    fn empty_append_tape_runs() {
+        let tape = Tape::builder().size(0).tape_mode(TapeMode::Append).build();
+        let mut program = parse_with("+.>++.", tape, DisableFlags::default());
+
+        assert_eq!(run(&mut program), [1, 2]);
+    }
+
+    #[test]
+    fn output_log_maps_bytes_to_glyphs() {
+        let mut program = parse(&format!("{}.", "+".repeat(65)));
+        run(&mut program);
+
+        assert_eq!(program.output_log(), &[65]);
+        assert_eq!(program.output_log_report(), "65 -> 'A' (0x41)\n");
+    }
+
+    #[test]
+    fn each_limit_gives_its_own_error() {
+        let limited = |src: &str, tape: Tape, limits: Limits| {
+            let mut program = parse_with(src, tape, DisableFlags::default());
+            program.set_limits(limits);
+            run_error(&mut program)
+        };
+
+        let error = limited(
+            "+[]",
+            Tape::default(),
+            Limits {
+                max_steps: Some(100),
+                ..Limits::default()
+            },
+        );
+        assert!(matches!(error.error, BFErrors::StepLimitExceeded));
+
+        let error = limited(
+            "+[>+]",
+            Tape::builder().size(1).tape_mode(TapeMode::Append).build(),
+            Limits {
+                max_tape: Some(4),
+                ..Limits::default()
+            },
+        );
+        assert!(matches!(error.error, BFErrors::TapeLimitExceeded));
+        assert_eq!(error.message, "Tape grew to 5 cells, above the limit of 4");
+
+        let error = limited(
+            "+[.]",
+            Tape::default(),
+            Limits {
+                max_output: Some(3),
+                ..Limits::default()
+            },
+        );
+        assert!(matches!(error.error, BFErrors::OutputLimitExceeded));
+    }
+
+    #[test]
+    fn nonzero_cells_lists_what_a_run_left_behind() {
+        let mut program = parse_with(
+            "++>>+++>>--<<<<--",
+            Tape::builder().size(5).build(),
+            DisableFlags::default(),
+        );
+        run(&mut program);
+
+        let cells: Vec<(u128, u8)> = program.tape().nonzero_cells().collect();
+        assert_eq!(cells, [(2, 3), (4, 254)]);
+    }
+
+    #[test]
+    fn source_map_covers_instructions_inside_loops() {
+        let program = parse(",[>.<-]");
+        let entry = |offset: usize, length: usize, description: &str| {
+            (offset, length, description.to_string())
+        };
+
+        assert_eq!(
+            program.source_map(),
+            [
+                entry(0, 1, "Take input"),
+                entry(1, 6, "loop (4 instrs)"),
+                entry(2, 1, "Move right 1 spaces"),
+                entry(3, 1, "Write output 1 times"),
+                entry(4, 1, "Move left 1 spaces"),
+                entry(5, 1, "Subtract 1"),
+            ]
+        );
+    }
+
+    #[test]
+    fn decl_blocks_reserve_cells_in_order() {
+        // Use order would otherwise give `b` the first cell
+        let mut program = parse_with(
+            "decl { a b c }\n{b}+",
+            Tape::builder().size(10).build(),
+            DisableFlags::default(),
+        );
+        run(&mut program);
+
+        assert_eq!(
+            program.alias_table(),
+            "Alias  Address\na      9\nb      8\nc      7\n"
+        );
+        assert_eq!(program.tape().nonzero_cells().collect::<Vec<_>>(), [(8, 1)]);
+    }
+
+    #[test]
+    fn gotos_after_fixed_moves_lower_to_deltas() {
+        let mut program = parse_with(
+            "{a}<<{b}+",
+            Tape::builder().size(10).build(),
+            DisableFlags::default(),
+        );
+        program.setup().unwrap();
+
+        assert!(program.lower_gotos().is_empty());
+        let instructions: Vec<Instruction> = program
+            .instructions
+            .iter()
+            .map(|(_, instruction)| instruction.clone())
+            .collect();
+        assert_eq!(
+            instructions,
+            [
+                Instruction::Right(9),
+                Instruction::Left(2),
+                Instruction::Right(1),
+                Instruction::Add(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn gotos_after_unbounded_loops_are_not_lowered() {
+        let mut program = parse_with(
+            "{a},[>]{b}",
+            Tape::builder().size(10).build(),
+            DisableFlags::default(),
+        );
+        program.setup().unwrap();
+
+        let unresolved = program.lower_gotos();
+        assert_eq!(unresolved.len(), 1);
+        let (span, error) = &unresolved[0];
+        assert_eq!(span.offset(), 7);
+        assert_eq!(
+            error.message,
+            "Alias b can't be lowered to a relative move as the pointer position isn't known here"
+        );
+    }
+
+    #[test]
+    fn alias_names_are_trimmed() {
+        let mut program = parse_with(
+            "{ counter }+{counter}+{\n\tcounter\n}+",
+            Tape::builder().size(10).build(),
+            DisableFlags::default(),
+        );
+        run(&mut program);
+
+        assert_eq!(program.alias_table(), "Alias    Address\ncounter  9\n");
+        assert_eq!(program.tape().nonzero_cells().collect::<Vec<_>>(), [(9, 3)]);
+    }
+
+    #[test]
+    fn reset_programs_run_independently() {
+        let mut program = parse(",[.,]>+++");
+        program.set_capture_output();
+        program.setup().unwrap();
+
+        program.run_with_input(b"abc");
+        assert_eq!(program.output_bytes(), b"abc");
+
+        program.reset().unwrap();
+        program.run_with_input(b"xy");
+        assert_eq!(program.output_bytes(), b"xy");
+        assert_eq!(program.tape().nonzero_cells().collect::<Vec<_>>(), [(1, 3)]);
+    }
+
+    #[test]
+    fn tapes_can_be_configured_without_the_cli() {
+        let tape = |tape_mode: &str| {
+            Tape::builder()
+                .size(3)
+                .tape_mode(tape_mode.parse().unwrap())
+                .cell_mode("Saturate".parse().unwrap())
+                .build()
+        };
+
+        let mut program = parse_with(">>-+.", tape("panic"), DisableFlags::default());
+        assert_eq!(run(&mut program), [1]);
+
+        let mut program = parse_with(">>>", tape("panic"), DisableFlags::default());
+        assert!(matches!(
+            run_error(&mut program).error,
+            BFErrors::RuntimeError
+        ));
+
+        assert!(matches!(
+            "sideways".parse::<TapeMode>(),
+            Err(message) if message == "Unknown tape mode: sideways"
+        ));
+    }
+
+    #[test]
+    fn interrupts_stop_at_the_next_instruction() {
+        let interrupt = Arc::new(AtomicBool::new(false));
+        let mut program = parse("+>++>+++");
+        program.set_interrupt_flag(interrupt.clone());
+        program.setup().unwrap();
+
+        for _ in 0..3 {
+            program.step().unwrap();
+        }
+        interrupt.store(true, Ordering::SeqCst);
+        let error = program.step().unwrap_err();
+
+        assert!(matches!(error.error, BFErrors::Interrupted));
+        assert_eq!(program.current_span.offset(), 4);
+        assert_eq!(program.tape().get_pointer(), 1);
+        assert_eq!(
+            program.tape().nonzero_cells().collect::<Vec<_>>(),
+            [(0, 1), (1, 2)]
+        );
+    }
+
+    #[test]
+    fn repeated_output_prints_every_byte() {
+        let mut program = parse(&format!("{}.....", "+".repeat(65)));
+
+        assert_eq!(run(&mut program), b"AAAAA");
+    }
+
+    #[test]
+    fn stripped_artifacts_run_without_spans() {
+        let src = "{a}+++[->+++<]>.{b}+++++.";
+        let tape = || Tape::builder().size(10).build();
+        let mut program = parse_with(src, tape(), DisableFlags::default());
+        let artifact = program.to_artifact(true);
+        let expected = run(&mut program);
+
+        let mut loaded = Program::from_artifact(
+            PathBuf::from("test.bfc"),
+            &artifact,
+            tape(),
+            DisableFlags::default(),
+            ParseFlags::default(),
+        )
+        .unwrap();
+
+        assert!(loaded.stripped);
+        assert!(loaded
+            .source_map()
+            .iter()
+            .all(|(offset, length, _)| (*offset, *length) == (0, 0)));
+        assert_eq!(run(&mut loaded), expected);
+        assert_eq!(expected, [9, 5]);
+    }
+
+    #[test]
+    fn profiles_rank_loops_by_iterations() {
+        let mut program = parse(",[->+<]\n>>,[-<+>]");
+        program.set_profile(true);
+        program.discard_output();
+        program.setup().unwrap();
+        program.run_with_input(&[3, 5]);
+
+        let profile = program.profile().unwrap();
+        let hot_loops: Vec<(usize, u64)> = profile
+            .hot_loops()
+            .iter()
+            .map(|(span, count)| (span.offset(), *count))
+            .collect();
+        assert_eq!(hot_loops, [(11, 5), (1, 3)]);
+        assert_eq!(profile.instruction_counts()["Input"], 2);
+
+        let report = program.profile_report().unwrap();
+        assert!(
+            report.ends_with("\nLoop       Iterations\n2:4        5\n1:2        3\n"),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn tape_preview_is_exact_for_straight_line_programs() {
+        let mut program = parse_with(
+            "+>>-<<<.{a}+",
+            Tape::builder().size(10).build(),
+            DisableFlags::default(),
+        );
+        program.setup().unwrap();
+
+        assert_eq!(
+            program.tape_preview(),
+            "Pointer visits 4 cells, between -1 and 9\nAliases occupy cells 9\n"
+        );
+    }
+
+    #[test]
+    fn tape_preview_is_unbounded_after_data_dependent_loops() {
+        let mut program = parse("+\n,[>]");
+        program.setup().unwrap();
+
+        assert_eq!(
+            program.tape_preview(),
+            "Pointer reach is unbounded: the instruction at 2:2 moves it by a data-dependent amount\n"
+        );
+    }
+
+    #[test]
+    fn seek_moves_to_an_absolute_cell() {
+        let tape = || Tape::builder().size(10).tape_mode(TapeMode::Panic).build();
+
+        let mut program = parse_with(">>@5+", tape(), DisableFlags::default());
+        run(&mut program);
+        assert_eq!(program.tape().get_pointer(), 5);
+        assert_eq!(program.tape().nonzero_cells().collect::<Vec<_>>(), [(5, 1)]);
+
+        let mut program = parse_with("@999999", tape(), DisableFlags::default());
+        let error = run_error(&mut program);
+        assert_eq!(
+            error.message,
+            "Tape pointer would be above 9 if moved to cell 999999"
+        );
+    }
+
+    #[test]
+    fn fmt_keeps_comments_in_place() {
+        let src =
+            "# own line\n+ # inline\n[ # opening\n    - # inside\n    # alone\n] # closing\n.\n";
+        let flag = DisableFlags {
+            disable_optimise: true,
+            ..DisableFlags::default()
+        };
+        let parse_flags = ParseFlags {
+            keep_comments: true,
+            ..ParseFlags::default()
+        };
+        let program = Program::parse(
+            PathBuf::from("test.bfem"),
+            src.to_string(),
+            Tape::default(),
+            flag,
+            parse_flags,
+        )
+        .unwrap();
+
+        assert_eq!(program.emit_source(), src);
+    }
+
+    #[test]
+    fn programs_leave_the_expected_tape() {
+        let tape = || Tape::builder().size(5).build();
+        let mut program = parse_with("++>+++++[-<+>]>>-", tape(), DisableFlags::default());
+        run(&mut program);
+
+        let mut expected = tape();
+        expected.load(&[7, 0, 0, 255]).unwrap();
+        expected.set_pointer(3);
+        crate::assert_tape_eq!(program.tape(), expected);
+    }
+
+    #[test]
+    #[should_panic(expected = "tapes differ: cell 2 holds 1 and 0")]
+    fn tape_assertions_report_the_first_different_cell() {
+        let tape = || Tape::builder().size(5).build();
+        let mut program = parse_with(">>+>+<<<", tape(), DisableFlags::default());
+        run(&mut program);
+
+        crate::assert_tape_eq!(program.tape(), tape());
+    }
+
+    #[test]
+    fn stepping_runs_one_instruction_at_a_time() {
+        let flag = DisableFlags {
+            disable_merge: true,
+            ..DisableFlags::default()
+        };
+        let mut program = parse_with("++.", Tape::default(), flag);
+        program.set_capture_output();
+        program.setup().unwrap();
+
+        let outcomes: Vec<StepOutcome> = (0..3).map(|_| program.step().unwrap()).collect();
+        let finished: Vec<bool> = outcomes.iter().map(|outcome| outcome.finished).collect();
+        let cells: Vec<(u128, u8)> = outcomes.iter().map(|outcome| outcome.cell).collect();
+
+        assert_eq!(finished, [false, false, true]);
+        assert_eq!(cells, [(0, 1), (0, 2), (0, 2)]);
+        assert_eq!(program.output_bytes(), b"\x02");
+    }
+
+    #[test]
+    fn input_follows_the_cell_mode() {
+        let program = |cell_mode: CellMode, input_mode: InputMode| {
+            let tape = Tape::builder().cell_mode(cell_mode).build();
+            let mut program = parse_with(
+                &format!("{},", "+".repeat(100)),
+                tape,
+                DisableFlags::default(),
+            );
+            program.set_input_mode(input_mode);
+            program.input = Input::bytes(&[200]);
+            program.discard_output();
+            program.setup().unwrap();
+            program
+        };
+        let modes = [
+            (CellMode::Circular, Some(44)),
+            (CellMode::Saturate, Some(255)),
+            (CellMode::Nothing, Some(100)),
+            (CellMode::Panic, None),
+        ];
+
+        for (cell_mode, added) in modes {
+            // Replacing the cell always stores the byte as it is
+            let mut replaced = program(cell_mode, InputMode::Replace);
+            while !replaced.step().unwrap().finished {}
+            assert_eq!(replaced.tape().get_value(), 200);
+
+            let mut program = program(cell_mode, InputMode::Add);
+            let mut outcome = program.step();
+            while matches!(&outcome, Ok(outcome) if !outcome.finished) {
+                outcome = program.step();
+            }
+            match added {
+                Some(value) => assert_eq!(outcome.unwrap().cell, (0, value)),
+                None => assert!(outcome.is_err()),
+            }
+        }
+    }
+
+    #[test]
+    fn time_limits_stop_spinning_programs() {
+        let mut program = parse("+[]");
+        program.set_limits(Limits {
+            max_time: Some(Duration::from_millis(50)),
+            ..Limits::default()
+        });
+        program.started = Some(Instant::now());
+
+        let error = run_error(&mut program);
+        assert!(matches!(error.error, BFErrors::TimeLimitExceeded));
+        assert_eq!(
+            error.message,
+            "Program exceeded the time limit of 0.050 seconds"
+        );
+    }
+
+    #[test]
+    fn loops_read_the_right_cell_after_appends_at_either_end() {
+        let tape = Tape::builder().size(1).tape_mode(TapeMode::Append).build();
+        // Grow the front, then move that cell's value past the back inside a loop
+        let mut program = parse_with("+++<++[->>+<<]<+[-<+>]>>.>.", tape, DisableFlags::default());
+
+        assert_eq!(run(&mut program), [3, 2]);
+        assert_eq!(program.tape().cells(), [1, 0, 0, 3, 2]);
+    }
+
+    #[test]
+    fn preserved_tapes_keep_their_starting_values() {
+        let preloaded = || {
+            let mut tape = Tape::default();
+            tape.load(&[5]).unwrap();
+            tape
+        };
+
+        let mut program = parse_with(".", preloaded(), DisableFlags::default());
+        program.set_preserve_initial_state(true);
+        assert_eq!(run(&mut program), [5]);
+
+        let mut program = parse_with(".", preloaded(), DisableFlags::default());
+        assert_eq!(run(&mut program), [0]);
+    }
+
+    #[test]
+    fn labelled_loops_are_shown_by_name() {
+        let program = parse(",{:countdown [-.]},[.]");
+        let labels: Vec<(usize, String)> = program
+            .produce_labeled_spans()
+            .iter()
+            .map(|span| (span.offset(), span.label().unwrap().to_string()))
+            .collect();
+
+        assert_eq!(
+            labels,
+            [
+                (0, "Take input".to_string()),
+                (13, "loop countdown".to_string()),
+                (14, "Subtract 1".to_string()),
+                (15, "Write output 1 times".to_string()),
+                (18, "Take input".to_string()),
+                (20, "Write output 1 times".to_string()),
+            ]
+        );
+        assert!(program
+            .control_flow_dot()
+            .contains("[shape=diamond, label=\"loop countdown at 1:14\"]"));
+    }
+
+    #[test]
+    fn flat_instructions_give_their_depth() {
+        let flag = DisableFlags {
+            disable_optimise: true,
+            ..DisableFlags::default()
+        };
+        let program = parse_with("+[+[+]]", Tape::default(), flag);
+        let flat: Vec<(usize, usize, &'static str)> = program
+            .instructions_flat()
+            .map(|(depth, span, instruction)| (depth, span.offset(), instruction.kind()))
+            .collect();
+
+        assert_eq!(
+            flat,
+            [
+                (0, 0, "Add"),
+                (0, 1, "Loop"),
+                (1, 2, "Add"),
+                (1, 3, "Loop"),
+                (2, 4, "Add"),
+            ]
+        );
+    }
+
+    #[test]
+    fn alias_table_shows_unresolved_aliases_before_setup() {
+        let mut program = parse_with(
+            "{x}+{y}+",
+            Tape::builder().size(10).build(),
+            DisableFlags::default(),
+        );
+
+        assert!(program.aliases().is_empty());
+        assert_eq!(
+            program.alias_table(),
+            "Alias  Address\nx      unresolved\ny      unresolved\n"
+        );
+
+        program.setup().unwrap();
+        assert_eq!(program.aliases().get_by_left("y"), Some(&8));
+        assert_eq!(
+            program.alias_table(),
+            "Alias  Address\nx      9\ny      8\n"
+        );
+    }
+
+    #[test]
+    fn infinite_loops_are_found_without_false_positives() {
+        let spans = |src: &str| -> Vec<usize> {
+            parse(src)
+                .infinite_loops()
+                .iter()
+                .map(|span| span.offset())
+                .collect()
+        };
+
+        assert_eq!(spans("+[]"), [1]);
+        assert_eq!(spans(",[-]>+[<>]"), [6]);
+        assert!(spans(",[.,]").is_empty());
+        assert!(spans("+[+]").is_empty());
+    }
+
+    #[test]
+    fn infinite_loop_checks_handle_an_empty_append_tape() {
+        let tape = || Tape::builder().size(0).tape_mode(TapeMode::Append).build();
+
+        let program = parse_with("+[]", tape(), DisableFlags::default());
+        assert_eq!(program.infinite_loops().len(), 1);
+        let program = parse_with(",[.,]>>+[-]", tape(), DisableFlags::default());
+        assert!(program.infinite_loops().is_empty());
+    }
+
+    #[test]
+    fn code_after_an_infinite_loop_is_unreachable() {
+        let unreachable = |src: &str| {
+            parse(src)
+                .unreachable_code()
+                .map(|span| (span.offset(), span.len()))
+        };
+
+        assert_eq!(unreachable("+[]>.<#note\n-"), Some((3, 10)));
+        assert_eq!(unreachable("+[] # just a note"), None);
+        assert_eq!(unreachable("+[-]>."), None);
+        assert_eq!(unreachable(",[.,]>."), None);
+    }
+
+    #[test]
+    fn seeks_and_aliases_agree_after_a_front_append() {
+        let tape = Tape::builder().size(10).tape_mode(TapeMode::Append).build();
+        let mut program = parse_with(
+            "{a}+++@0<<@9.+{a}.@1+@0<<@1.",
+            tape,
+            DisableFlags::default(),
+        );
+        assert_eq!(run(&mut program), [3, 4, 1]);
+    }
+
+    #[test]
+    fn output_after_a_front_append_reads_the_same_cell() {
+        let tape = Tape::builder().size(3).tape_mode(TapeMode::Append).build();
+        let mut program = parse_with(">+++.<<<>>>.", tape, DisableFlags::default());
+
+        assert_eq!(run(&mut program), [3, 3]);
+        assert_eq!(program.tape.shift, 2);
+    }
+
+    #[test]
+    fn empty_programs_say_why_nothing_runs() {
+        let emptiness = |src: &str| {
+            parse(src)
+                .emptiness()
+                .map(|(span, warning)| (span.offset(), span.len(), warning))
+        };
+
+        let empty = "The file is empty, so there is nothing to run".to_string();
+        assert_eq!(emptiness(""), Some((0, 0, empty.clone())));
+        assert_eq!(emptiness(" \n\t"), Some((0, 0, empty)));
+        let comments = "The program is only comments, so there is nothing to run".to_string();
+        assert_eq!(emptiness("# a\n# b"), Some((0, 7, comments)));
+        assert_eq!(emptiness("#a\n+"), None);
+
+        let mut program = parse("");
+        assert!(program
+            .warnings()
+            .iter()
+            .any(|(_, warning)| warning.contains("empty")));
+        assert_eq!(run(&mut program), b"");
+        assert_eq!(program.steps(), 0);
+    }
+
+    #[test]
+    fn optimised_and_naive_runs_reach_the_same_checksum() {
+        let src = "++++[->++++<]>[-<+>]<[>+<-]++.>>,[->+<]";
+        let unoptimised = DisableFlags {
+            disable_optimise: true,
+            ..DisableFlags::default()
+        };
+        let checksums: Vec<u64> = [DisableFlags::default(), unoptimised]
+            .into_iter()
+            .map(|flag| {
+                let mut program = parse_with(src, Tape::default(), flag);
+                program.set_capture_output();
+                program.setup().unwrap();
+                program.run_with_input(b"x");
+                program.tape().checksum()
+            })
+            .collect();
+
+        assert_eq!(checksums[0], checksums[1]);
+        assert_ne!(checksums[0], Tape::default().checksum());
+    }
+
+    #[test]
+    fn verify_finds_where_a_broken_optimisation_differs() {
+        let src = ",[->++<]>.";
+        let unoptimised = DisableFlags {
+            disable_optimise: true,
+            ..DisableFlags::default()
+        };
+        let run_on = |program: &mut Program| {
+            program.set_capture_output();
+            program.setup().unwrap();
+            program.run_with_input(&[3]);
+        };
+
+        let mut naive = parse_with(src, Tape::default(), unoptimised);
+        let mut optimised = parse(src);
+        run_on(&mut naive);
+        run_on(&mut optimised);
+        assert_eq!(optimised.run_difference(&naive), None);
+
+        // Break the optimised program as a bad pass might, adding 3 instead of 2
+        let (span, Instruction::Loop(body)) = &optimised.instructions[1] else {
+            panic!("expected a loop");
+        };
+        let mut body = body.clone();
+        body[2].1 = Instruction::Add(3);
+        optimised.instructions[1] = (*span, Instruction::Loop(body));
+        optimised.flat_instructions = None;
+        optimised.reset().unwrap();
+        run_on(&mut optimised);
+
+        assert_eq!(
+            optimised.run_difference(&naive).as_deref(),
+            Some("output byte 0 is 9 and 6")
+        );
+    }
+
+    #[test]
+    fn literals_can_be_hex_or_characters() {
+        let mut program = parse_with(
+            "{c='A'}.{d=0x10}+0x0A.@0+'a'.>-12.",
+            Tape::builder().size(10).build(),
+            DisableFlags::default(),
+        );
+
+        assert_eq!(run(&mut program), [65, 26, 97, 244]);
+    }
+
+    #[test]
+    fn captured_output_holds_what_was_written() {
+        let src = include_str!("../examples/simple_hello_world.bfem");
+        let mut program = parse(src);
+        program.set_capture_output();
+        program.setup().unwrap();
+        program.run();
+
+        assert_eq!(program.output_bytes(), b"Hello, World!");
+        assert_eq!(program.output_log(), program.output_bytes());
+    }
+
+    #[test]
+    fn loop_balance_reports_pointer_balance_and_control_delta() {
+        let program = parse(",[->+<]\n,[->+]");
+
+        assert_eq!(
+            program.loop_balance(),
+            concat!(
+                "Loop  Balanced  Control delta\n",
+                "1:2   yes       -1\n",
+                "2:2   no        -1\n",
+            )
+        );
+    }
+
+    #[test]
+    fn add_mode_input_adds_to_the_cell() {
+        let mut added = parse("+10,.");
+        added.set_input_mode(InputMode::Add);
+        added.set_input_bytes(&[5]);
+        let mut replaced = parse("+10,.");
+        replaced.set_input_bytes(&[5]);
+
+        assert_eq!(run(&mut added), [15]);
+        assert_eq!(run(&mut replaced), [5]);
+    }
+
+    #[test]
+    fn missing_files_are_errors_rather_than_panics() {
+        let path = std::env::temp_dir().join("bfem_missing_file.bfem");
+        let Err(errors) = Program::read_file(
+            path,
+            Tape::default(),
+            DisableFlags::default(),
+            ParseFlags::default(),
+        ) else {
+            panic!("a missing file was read");
+        };
+
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0].1.error, BFErrors::IoError));
+        assert!(errors[0].1.message.contains("No such file"));
+    }
+
+    #[test]
+    fn output_files_only_get_the_bytes_output() {
+        let path = std::env::temp_dir().join("bfem_output_file_only_gets_output.bin");
+        let mut program = parse(",.+.");
+        program.set_output_file(&path).unwrap();
+        program.set_echo_input(true);
+        program.set_ensure_newline(true);
+        program.set_numeric_output(Some(" ".to_string()));
+        let mut shifted: Vec<u8> = (0..=255).collect();
+        shifted.rotate_left(1);
+        program.set_charset(&shifted).unwrap();
+        program.set_log_output(true);
+        program.input = Input::bytes(b"A");
+        program.setup().unwrap();
+        program.run();
+
+        assert_eq!(fs::read(&path).unwrap(), b"AB");
+        assert_eq!(program.output_log(), b"AB");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn charsets_translate_output() {
+        let identity: Vec<u8> = (0..=255).collect();
+        let mut program = parse("++++++++[>++++++++<-]>+.+.");
+        program.set_charset(&identity).unwrap();
+        assert_eq!(run(&mut program), b"AB");
+
+        let mut shifted = identity.clone();
+        shifted[65] = 66;
+        let mut program = parse("++++++++[>++++++++<-]>+.+.");
+        program.set_charset(&shifted).unwrap();
+        assert_eq!(run(&mut program), b"BB");
+
+        assert!(program.set_charset(&identity[..255]).is_err());
+    }
+
+    #[test]
+    fn pointer_reports_give_the_cells_reached() {
+        let mut program = parse(">>>>>>>>>><<<<<");
+        assert!(program.pointer_report().is_none());
+        program.set_track_pointer(true);
+        run(&mut program);
+        assert_eq!(
+            program.pointer_report().unwrap(),
+            "Pointer reached cells 0 to 10, 11 distinct\n"
+        );
+    }
+
+    #[test]
+    fn emitted_rust_loops_on_the_current_cell() {
+        let mut program = parse(",[-{x}+]{x}.");
+        program.setup().unwrap();
+        let rust = program.emit_rust();
+
+        assert!(rust.contains("fn main() {"));
+        assert!(
+            rust.contains(
+                "    arr[ptr] = read_byte(&mut input);\n    \
+             while arr[ptr] != 0 {\n        \
+             arr[ptr] = arr[ptr].wrapping_sub(1);\n        \
+             ptr = 29999; // {x}\n"
+            ),
+            "{}",
+            rust
+        );
+        assert!(rust.contains("output.write_all(&[arr[ptr]]).unwrap();"));
+    }
+
+    #[test]
+    fn aliases_that_cannot_fit_fail_before_running() {
+        let tape = Tape::builder().size(2).tape_mode(TapeMode::Panic).build();
+        let mut program = parse_with("{a}+{b}+{c}+", tape, DisableFlags::default());
+
+        let error = program.setup().unwrap_err();
+        assert_eq!(
+            error.message,
+            "The tape has room for 2 more aliases, but 3 are used: a, b, c"
+        );
+        assert!(program.started.is_none());
+        assert_eq!(program.tape().cells(), [0, 0]);
+    }
+
+    #[test]
+    fn step_estimates_are_exact_without_loops() {
+        let program = parse("+++>+.");
+        assert_eq!(program.step_estimate(), Some(4));
+        assert_eq!(program.cost_report(), "Worst case: 4 steps\n");
+
+        let program = parse("+\n,[.-]");
+        assert_eq!(program.step_estimate(), None);
+        assert_eq!(
+            program.cost_report(),
+            "Worst case: unbounded, the loop at 2:2 depends on the data\n"
+        );
+    }
+
+    #[test]
+    fn control_flow_graphs_have_loop_back_edges() {
+        let dot = parse("+\n,[>.<-]>.").control_flow_dot();
+
+        assert!(dot.starts_with("digraph program {\n"));
+        assert!(dot.contains("  n2 [shape=diamond, label=\"loop at 2:2\"];\n"));
+        assert!(dot.contains("  n2 -> n3 [label=\"nonzero\"];\n"), "{}", dot);
+        assert!(dot.contains("  n3 -> n2 [label=\"repeat\", style=dashed];\n"));
+        assert!(dot.contains("  n2 -> n4 [label=\"zero\"];\n"));
+        assert!(dot.contains("  n4 -> n5 [label=\"\"];\n"));
+    }
+
+    #[test]
+    fn fmt_indents_nested_loops_as_chosen() {
+        let flag = DisableFlags {
+            disable_optimise: true,
+            ..DisableFlags::default()
+        };
+        let formatted = |indent: Indent| {
+            let mut program = parse_with("+[>[-]<-]", Tape::default(), flag);
+            program.set_indent(indent);
+            program.emit_source()
+        };
+
+        let spaces = formatted(Indent::Spaces(2));
+        assert_eq!(spaces, "+\n[\n  >\n  [\n    -\n  ]\n  <-\n]\n");
+        let tabs = formatted(Indent::Tabs);
+        assert_eq!(tabs, "+\n[\n\t>\n\t[\n\t\t-\n\t]\n\t<-\n]\n");
+
+        // Formatting the output again changes nothing
+        for (indent, text) in [(Indent::Spaces(2), &spaces), (Indent::Tabs, &tabs)] {
+            let mut program = parse_with(text, Tape::default(), flag);
+            program.set_indent(indent);
+            assert_eq!(&program.emit_source(), text);
+        }
+
+        assert_eq!("spaces:3".parse(), Ok(Indent::Spaces(3)));
+        assert_eq!("tabs".parse(), Ok(Indent::Tabs));
+        assert!("spaces".parse::<Indent>().is_err());
+    }
+
+    #[test]
+    fn clones_run_on_without_the_original() {
+        let flag = DisableFlags {
+            disable_optimise: true,
+            ..DisableFlags::default()
+        };
+        let mut program = parse_with("++>+++", Tape::default(), flag);
+        program.discard_output();
+        program.setup().unwrap();
+        program.step().unwrap();
+
+        let mut fork = program.clone();
+        while !fork.step().unwrap().finished {}
+        assert_eq!(&fork.tape().cells()[..2], [2, 3]);
+        assert_eq!(&program.tape().cells()[..2], [1, 0]);
+
+        program.step().unwrap();
+        assert_eq!(&program.tape().cells()[..2], [2, 0]);
+    }
+
+    #[test]
+    fn numbered_source_pads_line_numbers() {
+        let src = "+\n".repeat(9) + "[-]";
+        assert_eq!(
+            parse(&src).numbered_source().lines().collect::<Vec<_>>()[8..],
+            [" 9 │ +", "10 │ [-]"]
+        );
+    }
+
+    #[test]
+    fn nothing_cells_take_each_add_on_its_own() {
+        // Merged into one +10, the add would overflow and be dropped
+        let tape = Tape::builder().cell_mode(CellMode::Nothing).build();
+        let mut program = parse_with(",++++++++++.", tape, DisableFlags::default());
+        program.input = Input::bytes(&[250]);
+        assert_eq!(run(&mut program), [255]);
+    }
+
+    #[test]
+    fn entries_start_the_pointer_on_an_alias() {
+        let tape = Tape::builder().size(10).build();
+        let mut program = parse_with("+++{a}{b}", tape, DisableFlags::default());
+        program.setup().unwrap();
+        let address = *program.aliases().get_by_left("b").unwrap();
+        program.set_entry("b").unwrap();
+        assert_eq!(program.tape().get_pointer(), address);
+
+        let error = program.set_entry("c").unwrap_err();
+        assert_eq!(error.message, "There is no alias named c to start on");
+
+        run(&mut program);
+        assert_eq!(program.tape().get_value_at_index(address), 3);
+        assert_eq!(program.tape().get_value_at_index(0), 0);
+    }
+
+    #[test]
+    fn minified_source_is_shorter_and_runs_the_same() {
+        let src = "# say hi\n++++ ++++ [ > ++++ ++++ < - ] # loop\n> +.\n";
+        let flag = DisableFlags {
+            passes: Some(Passes::all().without(Pass::Unroll)),
+            ..DisableFlags::default()
+        };
+        let minified = parse_with(src, Tape::default(), flag).emit_minified();
+
+        assert_eq!(minified, "+8[>+8<-]>+.");
+        assert_eq!(run(&mut parse(&minified)), run(&mut parse(src)));
+    }
+
+    #[test]
+    fn input_past_the_end_follows_the_eof_policy() {
+        let policies = [
+            (EofPolicy::Zero, 0),
+            (EofPolicy::Max, 255),
+            (EofPolicy::Unchanged, 3),
+        ];
+
+        for (policy, expected) in policies {
+            let mut program = parse("+++,.");
+            program.set_eof_policy(policy);
+            program.input = Input::bytes(&[]);
+            assert_eq!(run(&mut program), [expected]);
+        }
+    }
+
+    #[test]
+    fn unrolling_assumes_nothing_about_a_preserved_tape() {
+        let mut tape = Tape::default();
+        tape.load(&[3]).unwrap();
+        let mut program = parse_with("++[>.+<-]", tape, DisableFlags::default());
+        assert!(matches!(program.instructions[1].1, Instruction::Loop(_)));
+
+        program.set_preserve_initial_state(true);
+        assert_eq!(run(&mut program), [0, 1, 2, 3, 4]);
+
+        // A fresh tape is known to hold its fill, so the loop unrolls
+        let tape = Tape::builder().fill(1).build();
+        let mut program = parse_with("+[>.+<-]", tape, DisableFlags::default());
+        assert!(!program
+            .instructions
+            .iter()
+            .any(|(_, instruction)| matches!(instruction, Instruction::Loop(_))));
+        assert_eq!(run(&mut program), [1, 2]);
+    }
+
+    #[test]
+    fn optimisation_reports_show_each_pass() {
+        assert_eq!(
+            parse("[-]+++").optimisation_report(),
+            "unoptimised: 5 instructions\n\
+             runlength: 5 -> 3 instructions, replaced 3 Add with 1 Add\n\
+             clearloop: 3 -> 2 instructions, replaced 1 Loop, 1 Subtract with 1 SetZero\n\
+             deadloop: 2 -> 2 instructions, no changes\n\
+             constfold: 2 -> 1 instructions, replaced 1 SetZero, 1 Add with 1 Set\n\
+             unroll: 1 -> 1 instructions, no changes\n"
+        );
+
+        let flag = DisableFlags {
+            disable_optimise: true,
+            ..DisableFlags::default()
+        };
+        let program = parse_with("[-]+++", Tape::default(), flag);
+        assert_eq!(
+            program.optimisation_report(),
+            "unoptimised: 5 instructions\nNo passes are enabled\n"
+        );
+    }
+
+    #[test]
+    fn folded_sets_run_and_round_trip() {
+        let mut program = parse(",[-]+++.>{x=7}.");
+        program.input = Input::bytes(&[9]);
+        assert!(program
+            .instructions
+            .iter()
+            .any(|(_, instruction)| *instruction == Instruction::Set(3)));
+        assert!(program.emit_minified().ends_with("{x=7}."));
+        let artifact = program.to_artifact(false);
+        assert!(artifact.contains(" set 3\n"));
+        assert_eq!(run(&mut program), [3, 7]);
+
+        let mut loaded = Program::from_artifact(
+            PathBuf::from("test.bfc"),
+            &artifact,
+            Tape::default(),
+            DisableFlags::default(),
+            ParseFlags::default(),
+        )
+        .unwrap();
+        loaded.input = Input::bytes(&[9]);
+        assert_eq!(run(&mut loaded), [3, 7]);
+    }
+
+    #[test]
+    fn scoped_aliases_get_cells_of_their_own() {
+        let parse_flags = ParseFlags {
+            scoped_aliases: true,
+            ..ParseFlags::default()
+        };
+        let mut program = Program::parse(
+            PathBuf::from("test.bfem"),
+            "+[{t}+++@0-]+[{t}+@0-]".to_string(),
+            Tape::builder().size(10).build(),
+            DisableFlags::default(),
+            parse_flags,
+        )
+        .unwrap();
+        run(&mut program);
+
+        let first = *program.aliases().get_by_left("t").unwrap();
+        let second = *program.aliases().get_by_left("t#2").unwrap();
+        assert_ne!(first, second);
+        assert_eq!(program.tape().get_value_at_index(first), 3);
+        assert_eq!(program.tape().get_value_at_index(second), 1);
     }
 }
@@ -1,4 +1,10 @@
-use std::ops::Rem;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    io::{self, IsTerminal},
+    ops::{Range, RangeInclusive, Rem},
+    str::FromStr,
+};
 
 use clap::ValueEnum;
 
@@ -7,8 +13,13 @@ use crate::{
     TapeFlags,
 };
 
-fn zeros(size: u128) -> Vec<u8> {
-    vec![0; size as usize]
+/// The start of a saved tape, from [`Tape::to_snapshot`], followed by the
+/// version of the format.
+const STATE_MAGIC: &[u8] = b"bfem state";
+const STATE_VERSION: u8 = 1;
+
+fn filled(size: u128, value: u8) -> Vec<u8> {
+    vec![value; size as usize]
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
@@ -17,68 +28,530 @@ pub enum TapeMode {
     Circular,
     /// Create new cells at the end
     Append,
-    /// Panic
+    /// Panic, stopping the program with an error
     Panic,
 }
 
+impl TapeMode {
+    /// What happens in this mode when the pointer moves off the tape.
+    pub fn example(&self) -> &'static str {
+        match self {
+            TapeMode::Circular => "< on the first cell moves to the last",
+            TapeMode::Append => "> on the last cell adds a new cell holding the fill value",
+            TapeMode::Panic => "< on the first cell stops with an error",
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 pub enum CellMode {
     /// -1 becomes 255. 256 becomes 0.
     Circular,
-    /// Nothing. -1 becomes 0. 256 becomes 255.
+    /// Nothing. Anything that would take a cell below 0 or above 255 leaves it
+    /// as it was.
     Nothing,
-    /// Panic
+    /// Clamp. -1 becomes 0. 256 becomes 255.
+    Saturate,
+    /// Panic, stopping the program with an error
     Panic,
 }
 
-pub struct Tape {
+impl CellMode {
+    /// What happens in this mode when a cell goes past 0 or 255.
+    pub fn example(&self) -> &'static str {
+        match self {
+            CellMode::Circular => "- on a cell holding 0 leaves 255",
+            CellMode::Nothing => "+10 on a cell holding 250 leaves 250",
+            CellMode::Saturate => "+10 on a cell holding 250 leaves 255",
+            CellMode::Panic => "- on a cell holding 0 stops with an error",
+        }
+    }
+}
+
+impl FromStr for TapeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "circular" => Ok(TapeMode::Circular),
+            "append" => Ok(TapeMode::Append),
+            "panic" => Ok(TapeMode::Panic),
+            _ => Err(format!("Unknown tape mode: {}", s)),
+        }
+    }
+}
+
+impl FromStr for CellMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "circular" => Ok(CellMode::Circular),
+            "nothing" => Ok(CellMode::Nothing),
+            "saturate" => Ok(CellMode::Saturate),
+            "panic" => Ok(CellMode::Panic),
+            _ => Err(format!("Unknown cell mode: {}", s)),
+        }
+    }
+}
+
+const POINTER_COLOR: &str = "\x1b[1;32m";
+const ALIAS_COLOR: &str = "\x1b[36m";
+const RESET: &str = "\x1b[0m";
+
+/// How a tape dump marks the pointer and alias cells.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum DumpStyle {
+    /// Markers on a row under the values
+    Plain,
+    /// ANSI colours on the values themselves
+    Color,
+}
+
+impl DumpStyle {
+    /// Colour when writing to a terminal, unless `NO_COLOR` is set.
+    pub fn detect() -> Self {
+        if std::env::var_os("NO_COLOR").is_none() && io::stderr().is_terminal() {
+            DumpStyle::Color
+        } else {
+            DumpStyle::Plain
+        }
+    }
+}
+
+/// How cell values are written in a tape dump.
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum DumpFormat {
+    /// `0x41`
+    Hex,
+    /// `65`
+    Dec,
+    /// `'A'`, or an escape such as `\x00` if it isn't printable
+    Char,
+}
+
+impl DumpFormat {
+    pub fn format(&self, value: u8) -> String {
+        match self {
+            DumpFormat::Hex => format!("0x{:02x}", value),
+            DumpFormat::Dec => value.to_string(),
+            DumpFormat::Char if value.is_ascii_graphic() || value == b' ' => {
+                format!("'{}'", value as char)
+            }
+            DumpFormat::Char => format!("\\x{:02x}", value),
+        }
+    }
+}
+
+/// Which end of the tape to start searching from.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum SearchDirection {
+    /// From the first cell up
+    Forward,
+    /// From the last cell down
+    Backward,
+}
+
+/// Where `@N` counts cells from, and so which cell `?` says the pointer is
+/// on, once an Append tape has added cells to the front. Aliases always keep
+/// their cell.
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum Addressing {
+    /// The cell the tape started on, like alias addresses, so cells added to
+    /// the front come before cell 0
+    Start,
+    /// The tape's current first cell
+    Front,
+}
+
+/// Builds a [`Tape`] without needing the CLI's [`TapeFlags`].
+#[derive(Copy, Clone)]
+pub struct TapeBuilder {
     size: u128,
+    tape_mode: TapeMode,
+    seek_mode: Option<TapeMode>,
+    cell_mode: CellMode,
+    fill: u8,
+    addressing: Addressing,
+}
+
+impl Default for TapeBuilder {
+    fn default() -> Self {
+        Self {
+            size: 30000,
+            tape_mode: TapeMode::Circular,
+            seek_mode: None,
+            cell_mode: CellMode::Circular,
+            fill: 0,
+            addressing: Addressing::Start,
+        }
+    }
+}
+
+impl TapeBuilder {
+    pub fn size(mut self, size: u128) -> Self {
+        self.size = size;
+        self
+    }
+
+    pub fn tape_mode(mut self, tape_mode: TapeMode) -> Self {
+        self.tape_mode = tape_mode;
+        self
+    }
+
+    /// What `@N` seeks past the end of the tape do, if not what the tape
+    /// mode says.
+    pub fn seek_mode(mut self, seek_mode: TapeMode) -> Self {
+        self.seek_mode = Some(seek_mode);
+        self
+    }
+
+    pub fn cell_mode(mut self, cell_mode: CellMode) -> Self {
+        self.cell_mode = cell_mode;
+        self
+    }
+
+    pub fn fill(mut self, fill: u8) -> Self {
+        self.fill = fill;
+        self
+    }
+
+    pub fn addressing(mut self, addressing: Addressing) -> Self {
+        self.addressing = addressing;
+        self
+    }
+
+    /// Check the tape this would build is usable. Only Append tapes grow, so
+    /// any other tape or seek mode needs at least one cell.
+    pub fn validate(&self) -> Result<(), BFError> {
+        if self.size > 0 {
+            return Ok(());
+        }
+
+        let seek_mode = self.seek_mode.unwrap_or(self.tape_mode);
+        match [("tape", self.tape_mode), ("seek", seek_mode)]
+            .into_iter()
+            .find(|(_, mode)| *mode != TapeMode::Append)
+        {
+            Some((kind, mode)) => Err(BFError::new(
+                BFErrors::RuntimeError,
+                format!(
+                    "A tape of size 0 needs an append {} mode, not {}, as only append tapes grow",
+                    kind,
+                    mode.to_possible_value().unwrap().get_name()
+                ),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    pub fn build(self) -> Tape {
+        Tape {
+            initial_size: self.size,
+            cells: filled(self.size, self.fill),
+            tape_behaviour: self.tape_mode,
+            seek_behaviour: self.seek_mode.unwrap_or(self.tape_mode),
+            cell_behaviour: self.cell_mode,
+            fill: self.fill,
+            addressing: self.addressing,
+            pointer: 0,
+            shift: 0,
+            pointer_history: None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Tape {
+    /// How many cells the tape starts with, and goes back to when cleared.
+    /// Append tapes grow past this, so use `size()` for the live length.
+    initial_size: u128,
     cells: Vec<u8>,
     tape_behaviour: TapeMode,
+    /// How seeks past the end are handled, which is the tape mode unless set
+    /// separately
+    seek_behaviour: TapeMode,
     cell_behaviour: CellMode,
-    /// Pointer
+    /// The value fresh cells start with
+    fill: u8,
+    /// Where `@N` counts from
+    addressing: Addressing,
+    /// Pointer, as an index into `cells`. Adding cells to the front moves
+    /// it along with the cell it was on, so `get_value` and `set_value` can
+    /// use it as it is, whatever the shift.
     pointer: u128,
 
     /// The amount indexes should be shifted. This only applies
     /// when we add cells to the _start_ but we have named cells.
+    /// It counts every cell an Append tape has added to the front, so an
+    /// address counted from where the tape started (such as an alias's, or
+    /// the cell in `@N`) is at index `address + shift` in `cells`.
     pub shift: u128,
+
+    /// Sorted, separate inclusive ranges of cells the pointer has been on,
+    /// if they are being tracked. Cells are counted from where the tape
+    /// started, like alias addresses, so they are negative in front of it.
+    pointer_history: Option<Vec<(i128, i128)>>,
+}
+
+/// Tapes are equal when they hold the same cells, have the pointer in the
+/// same place and behave the same way. The configured size and `shift` are
+/// ignored, as `cells` already reflects any growth.
+impl PartialEq for Tape {
+    fn eq(&self, other: &Self) -> bool {
+        self.cells == other.cells
+            && self.pointer == other.pointer
+            && self.tape_behaviour == other.tape_behaviour
+            && self.cell_behaviour == other.cell_behaviour
+            && self.fill == other.fill
+    }
+}
+
+impl Eq for Tape {}
+
+/// Assert that two tapes are equal, reporting the first difference (such as
+/// the first cell that holds a different value) if they aren't.
+#[macro_export]
+macro_rules! assert_tape_eq {
+    ($left:expr, $right:expr $(,)?) => {
+        if let Some(difference) = $left.difference(&$right) {
+            panic!("assertion failed: tapes differ: {}", difference);
+        }
+    };
 }
 
 impl Default for Tape {
     fn default() -> Self {
-        Self {
-            size: 30000,
-            cells: zeros(30000),
-            tape_behaviour: TapeMode::Circular,
-            cell_behaviour: CellMode::Circular,
-            pointer: 0,
-            shift: 0,
-        }
+        Tape::builder().build()
     }
 }
 
 impl Tape {
     pub fn new(flags: TapeFlags) -> Self {
-        Self {
-            size: flags.tape_size,
-            cells: zeros(flags.tape_size),
-            tape_behaviour: flags.tape_mode,
-            cell_behaviour: flags.cell_mode,
-            pointer: 0,
-            shift: 0,
+        Tape::builder_for(flags).build()
+    }
+
+    /// Check the tape `flags` describe is usable, as with
+    /// [`TapeBuilder::validate`].
+    pub fn validate_flags(flags: TapeFlags) -> Result<(), BFError> {
+        Tape::builder_for(flags).validate()
+    }
+
+    fn builder_for(flags: TapeFlags) -> TapeBuilder {
+        let builder = Tape::builder()
+            .size(flags.tape_size)
+            .tape_mode(flags.tape_mode)
+            .cell_mode(flags.cell_mode)
+            .fill(flags.fill)
+            .addressing(flags.addressing);
+        match flags.seek_policy {
+            Some(seek_mode) => builder.seek_mode(seek_mode),
+            None => builder,
         }
     }
 
+    pub fn builder() -> TapeBuilder {
+        TapeBuilder::default()
+    }
+
     pub fn realign(&mut self) {
         self.pointer = 0;
     }
 
     pub fn clear(&mut self) {
-        self.cells = zeros(self.size);
+        self.cells = filled(self.initial_size, self.fill);
+        self.shift = 0;
+    }
+
+    /// Copy `bytes` into the cells from index 0. Append tapes grow to fit
+    /// them; other tapes error if they are too short.
+    pub fn load(&mut self, bytes: &[u8]) -> Result<(), BFError> {
+        let length = bytes.len() as u128;
+        if length > self.size() {
+            if self.tape_behaviour != TapeMode::Append {
+                return Err(BFError::new(
+                    BFErrors::RuntimeError,
+                    format!(
+                        "Can't load {} bytes onto a tape of {} cells",
+                        length,
+                        self.size()
+                    ),
+                ));
+            }
+            let mut data = filled(length - self.size(), self.fill);
+            self.cells.append(&mut data);
+        }
+
+        self.cells[..bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Save the tape's cells, pointer and modes, for [`Tape::from_snapshot`]
+    /// to rebuild it from later. After the header and version, the modes and
+    /// fill are a byte each, then the starting size, shift, pointer and cell
+    /// count are little endian u128s, and then the cells follow.
+    pub fn to_snapshot(&self) -> Vec<u8> {
+        let mut bytes = STATE_MAGIC.to_vec();
+        bytes.push(STATE_VERSION);
+        bytes.extend([
+            self.tape_behaviour as u8,
+            self.seek_behaviour as u8,
+            self.cell_behaviour as u8,
+            self.fill,
+        ]);
+        for number in [
+            self.initial_size,
+            self.shift,
+            self.pointer,
+            self.cells.len() as u128,
+        ] {
+            bytes.extend(number.to_le_bytes());
+        }
+        bytes.extend(&self.cells);
+        bytes
     }
 
+    /// Rebuild a tape saved by [`Tape::to_snapshot`], exactly as it was.
+    /// Pointer tracking isn't saved, so it starts off.
+    pub fn from_snapshot(bytes: &[u8]) -> Result<Tape, BFError> {
+        let invalid = |message: &str| {
+            BFError::new(
+                BFErrors::IoError,
+                format!("Invalid saved state: {}", message),
+            )
+        };
+        let rest = bytes
+            .strip_prefix(STATE_MAGIC)
+            .ok_or_else(|| invalid("not a bfem state file"))?;
+        let (&version, rest) = rest.split_first().ok_or_else(|| invalid("no version"))?;
+        if version != STATE_VERSION {
+            return Err(invalid(&format!(
+                "version {} is not supported (expected {})",
+                version, STATE_VERSION
+            )));
+        }
+
+        let tape_mode = |byte: u8| {
+            TapeMode::value_variants()
+                .get(byte as usize)
+                .copied()
+                .ok_or_else(|| invalid(&format!("unknown tape mode {}", byte)))
+        };
+        let modes: [u8; 4] = rest
+            .get(..4)
+            .and_then(|modes| modes.try_into().ok())
+            .ok_or_else(|| invalid("the modes are missing"))?;
+        let [tape_behaviour, seek_behaviour, cell_behaviour, fill] = modes;
+        let cell_behaviour = CellMode::value_variants()
+            .get(cell_behaviour as usize)
+            .copied()
+            .ok_or_else(|| invalid(&format!("unknown cell mode {}", cell_behaviour)))?;
+
+        let mut numbers = rest[4..]
+            .chunks(16)
+            .take(4)
+            .map(|chunk| chunk.try_into().map(u128::from_le_bytes));
+        let mut number = |name: &str| {
+            numbers
+                .next()
+                .and_then(Result::ok)
+                .ok_or_else(|| invalid(&format!("the {} is missing", name)))
+        };
+        let initial_size = number("tape size")?;
+        let shift = number("shift")?;
+        let pointer = number("pointer")?;
+        let length = number("cell count")?;
+
+        let cells = rest[4 + 16 * 4..].to_vec();
+        if cells.len() as u128 != length {
+            return Err(invalid(&format!(
+                "expected {} cells, but there are {}",
+                length,
+                cells.len()
+            )));
+        }
+        let tape = Tape {
+            initial_size,
+            cells,
+            tape_behaviour: tape_mode(tape_behaviour)?,
+            seek_behaviour: tape_mode(seek_behaviour)?,
+            cell_behaviour,
+            fill,
+            addressing: Addressing::Start,
+            pointer,
+            shift,
+            pointer_history: None,
+        };
+        tape.check_invariants()
+            .map_err(|message| invalid(&message))?;
+
+        Ok(tape)
+    }
+
+    /// Count `@N` from where `addressing` says. Saved states don't keep this,
+    /// so it is set again after loading one.
+    pub fn set_addressing(&mut self, addressing: Addressing) {
+        self.addressing = addressing;
+    }
+
+    /// The index into the cells of `address`, an index counted from where
+    /// the tape started before any cells were added to the front.
+    pub fn physical_index(&self, address: u128) -> u128 {
+        address + self.shift
+    }
+
+    /// Check that the pointer and shift still agree with the cells, after
+    /// any growth.
+    pub fn check_invariants(&self) -> Result<(), String> {
+        if self.shift > self.size() {
+            return Err(format!(
+                "shift {} is past the end of a {} cell tape",
+                self.shift,
+                self.size()
+            ));
+        }
+        // Only Append tapes make cells lazily, so only they can point past
+        // the end
+        if self.pointer >= self.size() && self.tape_behaviour != TapeMode::Append {
+            return Err(format!(
+                "pointer {} is past the end of a {} cell tape",
+                self.pointer,
+                self.size()
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Whether every cell still holds the fill value, as on a new tape.
+    pub fn is_fresh(&self) -> bool {
+        self.cells.iter().all(|value| *value == self.fill)
+    }
+
+    /// The value an untouched cell holds.
+    pub fn fill(&self) -> u8 {
+        self.fill
+    }
+
+    /// The value of the cell the pointer is on. The pointer moves along with
+    /// its cell when cells are added to the front, so this needs no `shift`.
     pub fn get_value(&self) -> u8 {
-        self.cells[self.pointer as usize]
+        match self.cells.get(self.pointer as usize) {
+            Some(value) => *value,
+            // Cells past the end of an Append tape just haven't been made yet
+            None if self.tape_behaviour == TapeMode::Append => self.fill,
+            None => self.cells[self.pointer as usize],
+        }
+    }
+
+    /// In Append mode the pointer can sit past the cells created so far (for
+    /// example on a tape that started with no cells), so grow the tape to
+    /// include it before it is written to.
+    fn ensure_pointer(&mut self) {
+        if self.tape_behaviour == TapeMode::Append && self.pointer >= self.size() {
+            let mut data = filled(self.pointer + 1 - self.size(), self.fill);
+            self.cells.append(&mut data);
+        }
     }
 
     pub fn get_value_at_index(&self, address: u128) -> u8 {
@@ -89,7 +562,42 @@ impl Tape {
         self.cells[address as usize] = value;
     }
 
+    /// Like [`Tape::get_value_at_index`], but errors instead of panicking
+    /// when `address` is past the end of the cells.
+    pub fn try_get_value_at_index(&self, address: u128) -> Result<u8, BFError> {
+        self.cells
+            .get(address as usize)
+            .copied()
+            .ok_or_else(|| self.index_error(address))
+    }
+
+    /// Like [`Tape::set_value_at_index`], but errors instead of panicking
+    /// when `address` is past the end of the cells.
+    pub fn try_set_value_at_index(&mut self, address: u128, value: u8) -> Result<(), BFError> {
+        match self.cells.get_mut(address as usize) {
+            Some(cell) => {
+                *cell = value;
+                Ok(())
+            }
+            None => Err(self.index_error(address)),
+        }
+    }
+
+    fn index_error(&self, address: u128) -> BFError {
+        BFError::new(
+            BFErrors::RuntimeError,
+            format!(
+                "Cell {} is past the end of a {} cell tape",
+                address,
+                self.size()
+            ),
+        )
+    }
+
+    /// Set the cell the pointer is on, which like [`Tape::get_value`] is the
+    /// same cell however many have been added to the front.
     pub fn set_value(&mut self, value: u8) {
+        self.ensure_pointer();
         self.cells[self.pointer as usize] = value;
     }
 
@@ -99,13 +607,308 @@ impl Tape {
 
     pub fn set_pointer(&mut self, value: u128) {
         self.pointer = value;
+        let address = self.address();
+        self.visit(address, address);
+    }
+
+    /// The pointer counted from where the tape started.
+    fn address(&self) -> i128 {
+        self.pointer as i128 - self.shift as i128
+    }
+
+    /// The pointer counted the way `@N` counts cells, for reporting where it
+    /// is with `?`.
+    pub fn position(&self) -> i128 {
+        match self.addressing {
+            Addressing::Start => self.address(),
+            Addressing::Front => self.pointer as i128,
+        }
+    }
+
+    /// The index into the cells of `@index`, before any wrapping or growth.
+    pub fn seek_index(&self, index: u128) -> u128 {
+        match self.addressing {
+            Addressing::Start => self.physical_index(index),
+            Addressing::Front => index,
+        }
+    }
+
+    /// Start recording every cell the pointer moves over, forgetting any
+    /// recorded before.
+    pub fn track_pointer(&mut self) {
+        let address = self.address();
+        self.pointer_history = Some(vec![(address, address)]);
+    }
+
+    /// Every cell the pointer has moved over since `track_pointer`, as
+    /// inclusive ranges of indexes in order.
+    pub fn pointer_history(&self) -> Option<Vec<RangeInclusive<u128>>> {
+        let shift = self.shift as i128;
+        self.pointer_history.as_ref().map(|ranges| {
+            ranges
+                .iter()
+                .map(|(start, end)| (start + shift) as u128..=(end + shift) as u128)
+                .collect()
+        })
+    }
+
+    /// Record that the pointer has been on every cell from `start` to `end`.
+    fn visit(&mut self, start: i128, end: i128) {
+        let Some(ranges) = &mut self.pointer_history else {
+            return;
+        };
+
+        let (mut start, mut end) = (start.min(end), start.max(end));
+        // Absorb every range that overlaps or touches the new one
+        ranges.retain(|(other_start, other_end)| {
+            if *other_end + 1 < start || *other_start > end + 1 {
+                return true;
+            }
+            start = start.min(*other_start);
+            end = end.max(*other_end);
+            false
+        });
+        let index = ranges.partition_point(|(other_start, _)| *other_start < start);
+        ranges.insert(index, (start, end));
+    }
+
+    /// Record the cells passed over moving `count` cells from `address`.
+    fn visit_move(&mut self, address: i128, count: u128, forward: bool) {
+        if self.pointer_history.is_none() {
+            return;
+        }
+
+        let size = self.size() as i128;
+        let count = count.min(i128::MAX as u128) as i128;
+        let end = if forward {
+            address + count
+        } else {
+            address - count
+        };
+        if self.tape_behaviour != TapeMode::Circular || (0..size).contains(&end) {
+            self.visit(address, end);
+        } else if count >= size {
+            self.visit(0, size - 1);
+        } else if forward {
+            self.visit(address, size - 1);
+            self.visit(0, end - size);
+        } else {
+            self.visit(0, address);
+            self.visit(end + size, size - 1);
+        }
+    }
+
+    /// The live cells, including any created by growing the tape.
+    pub fn cells(&self) -> &[u8] {
+        &self.cells
+    }
+
+    /// Every cell that doesn't hold zero, as (index, value) pairs in order.
+    pub fn nonzero_cells(&self) -> impl Iterator<Item = (u128, u8)> + '_ {
+        self.cells
+            .iter()
+            .enumerate()
+            .filter(|(_, value)| **value != 0)
+            .map(|(index, value)| (index as u128, *value))
+    }
+
+    /// The cells within `radius` of the pointer, as a row of indexes above
+    /// a row of values written in `format`. In plain style a row of markers below shows the
+    /// pointer (`^`) and any `alias_cells` (`*`); in colour style those
+    /// values are coloured instead. Either way a legend follows if there are
+    /// alias cells shown.
+    pub fn dump(
+        &self,
+        radius: u128,
+        style: DumpStyle,
+        format: DumpFormat,
+        alias_cells: &[u128],
+    ) -> String {
+        let start = self.pointer.saturating_sub(radius);
+        let end = (self.pointer + radius + 1).min(self.size());
+
+        let mut indexes = String::from("index");
+        let mut values = String::from("value");
+        let mut markers = String::from("     ");
+        let mut shows_alias = false;
+        for index in start..end {
+            let is_alias = alias_cells.contains(&index);
+            shows_alias |= is_alias;
+
+            indexes.push_str(&format!(" {:>5}", index));
+            let value = format!("{:>5}", format.format(self.cells[index as usize]));
+            match style {
+                DumpStyle::Plain => {
+                    values.push_str(&format!(" {}", value));
+                    markers.push_str(if index == self.pointer {
+                        "     ^"
+                    } else if is_alias {
+                        "     *"
+                    } else {
+                        "      "
+                    });
+                }
+                DumpStyle::Color if index == self.pointer => {
+                    values.push_str(&format!(" {}{}{}", POINTER_COLOR, value, RESET))
+                }
+                DumpStyle::Color if is_alias => {
+                    values.push_str(&format!(" {}{}{}", ALIAS_COLOR, value, RESET))
+                }
+                DumpStyle::Color => values.push_str(&format!(" {}", value)),
+            }
+        }
+
+        let mut out = match style {
+            DumpStyle::Plain => format!("{}\n{}\n{}\n", indexes, values, markers.trim_end()),
+            DumpStyle::Color => format!("{}\n{}\n", indexes, values),
+        };
+        if shows_alias {
+            out.push_str(&match style {
+                DumpStyle::Plain => "^ pointer  * alias\n".to_string(),
+                DumpStyle::Color => format!(
+                    "{}pointer{}  {}alias{}\n",
+                    POINTER_COLOR, RESET, ALIAS_COLOR, RESET
+                ),
+            });
+        }
+
+        out
+    }
+
+    /// Render the cells in `range` as an SVG row of boxes, each labelled with
+    /// its index and value. The pointer's box is filled and has a triangle
+    /// under it. The range is cut short at the end of the tape.
+    pub fn to_svg(&self, range: Range<u128>) -> String {
+        const CELL: u128 = 40;
+        let end = range.end.min(self.size());
+        let start = range.start.min(end);
+        let width = (end - start) * CELL;
+
+        let mut out = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"80\" font-family=\"monospace\" font-size=\"12\" text-anchor=\"middle\">\n",
+            width
+        );
+        for index in start..end {
+            let x = (index - start) * CELL;
+            let fill = if index == self.pointer {
+                "#b6e3b6"
+            } else {
+                "white"
+            };
+            out.push_str(&format!(
+                "  <rect x=\"{}\" y=\"16\" width=\"{}\" height=\"40\" fill=\"{}\" stroke=\"black\"/>\n",
+                x, CELL, fill
+            ));
+            out.push_str(&format!(
+                "  <text x=\"{}\" y=\"12\">{}</text>\n",
+                x + CELL / 2,
+                index
+            ));
+            out.push_str(&format!(
+                "  <text x=\"{}\" y=\"40\">{}</text>\n",
+                x + CELL / 2,
+                self.cells[index as usize]
+            ));
+            if index == self.pointer {
+                out.push_str(&format!(
+                    "  <polygon class=\"pointer\" points=\"{},60 {},72 {},72\" fill=\"black\"/>\n",
+                    x + CELL / 2,
+                    x + CELL / 2 - 6,
+                    x + CELL / 2 + 6
+                ));
+            }
+        }
+        out.push_str("</svg>\n");
+        out
+    }
+
+    /// Find `count` neighbouring cells that still hold the fill value and
+    /// that `allocated` doesn't claim, returning the lowest index of the run.
+    /// Searching backward finds the run nearest the end of the tape.
+    pub fn find_alias_free_run(
+        &self,
+        count: u128,
+        direction: SearchDirection,
+        allocated: impl Fn(u128) -> bool,
+    ) -> Option<u128> {
+        if count == 0 || count > self.size() {
+            return None;
+        }
+
+        let is_free = |index: u128| {
+            self.try_get_value_at_index(index)
+                .is_ok_and(|value| value == self.fill)
+                && !allocated(index)
+        };
+        let fits = |base: u128| (base..base + count).all(is_free);
+        let last_base = self.size() - count;
+
+        match direction {
+            SearchDirection::Forward => (0..=last_base).find(|base| fits(*base)),
+            SearchDirection::Backward => (0..=last_base).rev().find(|base| fits(*base)),
+        }
+    }
+
+    /// Describe the first way `other` differs from this tape, or `None` if
+    /// they are equal.
+    pub fn difference(&self, other: &Tape) -> Option<String> {
+        if self.tape_behaviour != other.tape_behaviour
+            || self.cell_behaviour != other.cell_behaviour
+        {
+            return Some("tape or cell modes differ".to_string());
+        }
+        if self.fill != other.fill {
+            return Some(format!("fill is {} and {}", self.fill, other.fill));
+        }
+        if self.pointer != other.pointer {
+            return Some(format!(
+                "pointer is at {} and {}",
+                self.pointer, other.pointer
+            ));
+        }
+        if let Some(index) = (0..self.cells.len().min(other.cells.len()))
+            .find(|index| self.cells[*index] != other.cells[*index])
+        {
+            return Some(format!(
+                "cell {} holds {} and {}",
+                index, self.cells[index], other.cells[index]
+            ));
+        }
+        if self.cells.len() != other.cells.len() {
+            return Some(format!(
+                "tapes have {} and {} cells",
+                self.cells.len(),
+                other.cells.len()
+            ));
+        }
+
+        None
     }
 
     pub fn size(&self) -> u128 {
         self.cells.len() as u128
     }
 
+    /// A fingerprint of the cells and the pointer, for quickly checking
+    /// whether two runs left the tape in the same state. Like `==`, this
+    /// ignores the configured size and `shift`.
+    pub fn checksum(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.cells.hash(&mut hasher);
+        self.pointer.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    pub fn get_tape_mode(&self) -> TapeMode {
+        self.tape_behaviour
+    }
+
+    pub fn get_cell_mode(&self) -> CellMode {
+        self.cell_behaviour
+    }
+
     pub fn add(&mut self, count: u8) -> Result<(), BFError> {
+        self.ensure_pointer();
         match self.cell_behaviour {
             CellMode::Circular => {
                 let value = self.cells[self.pointer as usize];
@@ -113,9 +916,13 @@ impl Tape {
                 Ok(())
             }
             CellMode::Nothing => {
-                self.cells[self.pointer as usize] = self.cells[self.pointer as usize]
-                    .checked_add(count)
-                    .unwrap_or(u8::MAX);
+                let value = self.cells[self.pointer as usize];
+                self.cells[self.pointer as usize] = value.checked_add(count).unwrap_or(value);
+                Ok(())
+            }
+            CellMode::Saturate => {
+                self.cells[self.pointer as usize] =
+                    self.cells[self.pointer as usize].saturating_add(count);
                 Ok(())
             }
             CellMode::Panic => {
@@ -125,10 +932,7 @@ impl Tape {
                         BFErrors::RuntimeError,
                         format!(
                             "Cell {} (value {}) would go above {} if {} were added",
-                            self.pointer,
-                            self.cells[self.pointer as usize],
-                            0,
-                            count
+                            self.pointer, self.cells[self.pointer as usize], 0, count
                         ),
                     ))
                 } else {
@@ -140,6 +944,7 @@ impl Tape {
     }
 
     pub fn sub(&mut self, count: u8) -> Result<(), BFError> {
+        self.ensure_pointer();
         match self.cell_behaviour {
             CellMode::Circular => {
                 let value = self.cells[self.pointer as usize];
@@ -147,9 +952,13 @@ impl Tape {
                 Ok(())
             }
             CellMode::Nothing => {
-                self.cells[self.pointer as usize] = self.cells[self.pointer as usize]
-                    .checked_sub(count)
-                    .unwrap_or(0);
+                let value = self.cells[self.pointer as usize];
+                self.cells[self.pointer as usize] = value.checked_sub(count).unwrap_or(value);
+                Ok(())
+            }
+            CellMode::Saturate => {
+                self.cells[self.pointer as usize] =
+                    self.cells[self.pointer as usize].saturating_sub(count);
                 Ok(())
             }
             CellMode::Panic => {
@@ -169,17 +978,26 @@ impl Tape {
                     self.cells[self.pointer as usize] = pointer;
                     Ok(())
                 }
-            },
+            }
         }
     }
 
     pub fn left(&mut self, count: u128) -> Result<(), BFError> {
+        let address = self.address();
+        self.move_left(count)?;
+        self.visit_move(address, count, false);
+        Ok(())
+    }
+
+    fn move_left(&mut self, count: u128) -> Result<(), BFError> {
         match self.tape_behaviour {
             TapeMode::Circular => {
+                // Whole trips round the tape end where they started
+                let count = count.rem(self.size());
                 if self.pointer >= count {
                     self.pointer -= count;
                 } else {
-                    self.pointer = self.cells.len() as u128 - (count - self.pointer)
+                    self.pointer = self.size() - (count - self.pointer)
                 }
 
                 Ok(())
@@ -188,8 +1006,12 @@ impl Tape {
                 if self.pointer >= count {
                     self.pointer -= count;
                 } else {
-                    // Create more cells
-                    self.cells.splice(0..0, zeros(count - self.pointer).iter().cloned());
+                    // Create more cells, remembering how many so that
+                    // addresses from before still find their cell
+                    let added = count - self.pointer;
+                    self.cells
+                        .splice(0..0, filled(added, self.fill).iter().cloned());
+                    self.shift += added;
                     self.pointer = 0;
                 }
 
@@ -213,34 +1035,78 @@ impl Tape {
         }
     }
 
+    /// Move the pointer to cell `index`, counted as the [`Addressing`] says,
+    /// following the seek mode if it is past the end: Circular wraps it
+    /// round, Append grows the tape to reach it and Panic errors.
+    pub fn seek(&mut self, index: u128) -> Result<(), BFError> {
+        let physical = self.seek_index(index);
+        match self.seek_behaviour {
+            TapeMode::Circular => {
+                self.set_pointer(physical.rem(self.size()));
+                Ok(())
+            }
+            TapeMode::Append => {
+                // Grown here rather than with `ensure_pointer`, as the tape
+                // itself may not be an Append tape
+                if physical >= self.size() {
+                    let mut data = filled(physical + 1 - self.size(), self.fill);
+                    self.cells.append(&mut data);
+                }
+                self.set_pointer(physical);
+                Ok(())
+            }
+            TapeMode::Panic => {
+                if physical >= self.size() {
+                    Err(BFError::new(
+                        BFErrors::RuntimeError,
+                        format!(
+                            "Tape pointer would be above {} if moved to cell {}",
+                            self.size().saturating_sub(1 + physical - index),
+                            index
+                        ),
+                    ))
+                } else {
+                    self.set_pointer(physical);
+                    Ok(())
+                }
+            }
+        }
+    }
+
     pub fn right(&mut self, count: u128) -> Result<(), BFError> {
+        let address = self.address();
+        self.move_right(count)?;
+        self.visit_move(address, count, true);
+        Ok(())
+    }
+
+    fn move_right(&mut self, count: u128) -> Result<(), BFError> {
         match self.tape_behaviour {
             TapeMode::Circular => {
-                let index = self.pointer.overflowing_add(count).0;
-                self.pointer = index.rem(self.size);
+                // Reduce first so a huge count can't overflow the addition
+                let count = count.rem(self.size());
+                self.pointer = (self.pointer + count).rem(self.size());
 
                 Ok(())
             }
             TapeMode::Append => {
                 self.pointer += count;
-                if self.pointer < self.cells.len() as u128 {
-                    return Ok(());
-                }
 
                 // Create more cells
-                let mut data = zeros(self.pointer - self.cells.len() as u128);
-                self.cells.append(&mut data);
+                self.ensure_pointer();
 
                 Ok(())
             }
             TapeMode::Panic => {
+                // The last valid index is `len - 1`, so the new pointer must be
+                // strictly less than the live tape length.
                 let (pointer, overflow) = self.pointer.overflowing_add(count);
-                if overflow || pointer >= self.size {
+                if overflow || pointer >= self.size() {
                     Err(BFError::new(
                         BFErrors::RuntimeError,
                         format!(
                             "Tape pointer would be above {} if moved right {} spaces from {}",
-                            self.cells.len(),
+                            self.size().saturating_sub(1),
                             count,
                             self.pointer
                         ),
@@ -253,3 +1119,476 @@ impl Tape {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn panic_tape(size: u128) -> Tape {
+        Tape::builder()
+            .size(size)
+            .tape_mode(TapeMode::Panic)
+            .build()
+    }
+
+    fn circular_tape(size: u128) -> Tape {
+        Tape::builder()
+            .size(size)
+            .tape_mode(TapeMode::Circular)
+            .build()
+    }
+
+    #[test]
+    fn panic_tape_can_move_to_the_last_cell() {
+        let mut tape = panic_tape(5);
+
+        assert!(tape.right(4).is_ok());
+        assert_eq!(tape.get_pointer(), 4);
+    }
+
+    #[test]
+    fn panic_tape_errors_moving_past_the_last_cell() {
+        let mut tape = panic_tape(5);
+
+        assert!(tape.right(5).is_err());
+        tape.right(4).unwrap();
+        assert!(tape.right(1).is_err());
+        assert_eq!(tape.get_pointer(), 4);
+    }
+
+    #[test]
+    fn panic_tape_errors_moving_left_of_the_first_cell() {
+        let mut tape = panic_tape(5);
+
+        assert!(tape.left(1).is_err());
+        assert_eq!(tape.get_pointer(), 0);
+    }
+
+    #[test]
+    fn fill_sets_every_cell() {
+        let mut tape = Tape::builder().size(8).fill(255).build();
+
+        assert!(tape.cells().iter().all(|cell| *cell == 255));
+        tape.set_value(3);
+        tape.clear();
+        assert!(tape.cells().iter().all(|cell| *cell == 255));
+    }
+
+    #[test]
+    fn arithmetic_starts_from_the_fill_value() {
+        let expected = [
+            (CellMode::Circular, Some(0)),
+            (CellMode::Nothing, Some(255)),
+            (CellMode::Saturate, Some(255)),
+            (CellMode::Panic, None),
+        ];
+        for (cell_mode, after_add) in expected {
+            let mut tape = Tape::builder()
+                .size(4)
+                .fill(255)
+                .cell_mode(cell_mode)
+                .build();
+            assert_eq!(tape.add(1).ok().map(|_| tape.get_value()), after_add);
+
+            let mut tape = Tape::builder()
+                .size(4)
+                .fill(255)
+                .cell_mode(cell_mode)
+                .build();
+            tape.sub(1).unwrap();
+            assert_eq!(tape.get_value(), 254);
+        }
+    }
+
+    #[test]
+    fn empty_append_tape_grows_on_first_use() {
+        let mut tape = Tape::builder().size(0).tape_mode(TapeMode::Append).build();
+
+        assert_eq!(tape.get_value(), 0);
+        tape.add(1).unwrap();
+        assert_eq!(tape.get_value(), 1);
+        assert_eq!(tape.size(), 1);
+
+        tape.right(3).unwrap();
+        tape.sub(1).unwrap();
+        assert_eq!(tape.get_value(), 255);
+        assert_eq!(tape.cells(), [1, 0, 0, 255]);
+    }
+
+    #[test]
+    fn free_runs_skip_used_and_allocated_cells() {
+        let mut tape = panic_tape(12);
+        for index in [2, 7] {
+            tape.set_pointer(index);
+            tape.set_value(1);
+        }
+
+        assert_eq!(
+            tape.find_alias_free_run(4, SearchDirection::Forward, |_| false),
+            Some(3)
+        );
+        assert_eq!(
+            tape.find_alias_free_run(4, SearchDirection::Backward, |_| false),
+            Some(8)
+        );
+        assert_eq!(
+            tape.find_alias_free_run(4, SearchDirection::Backward, |index| index == 9),
+            Some(3)
+        );
+        assert_eq!(
+            tape.find_alias_free_run(5, SearchDirection::Forward, |_| false),
+            None
+        );
+        assert_eq!(
+            tape.find_alias_free_run(13, SearchDirection::Forward, |_| false),
+            None
+        );
+    }
+
+    #[test]
+    fn only_append_tapes_can_start_empty() {
+        let empty = Tape::builder().size(0);
+        assert!(empty.tape_mode(TapeMode::Append).validate().is_ok());
+        assert!(Tape::builder().size(1).validate().is_ok());
+
+        let Err(error) = empty.validate() else {
+            panic!("an empty circular tape was allowed");
+        };
+        assert_eq!(
+            error.message,
+            "A tape of size 0 needs an append tape mode, not circular, as only append tapes grow"
+        );
+        assert!(empty.tape_mode(TapeMode::Panic).validate().is_err());
+        let seek = empty
+            .tape_mode(TapeMode::Append)
+            .seek_mode(TapeMode::Circular);
+        let Err(error) = seek.validate() else {
+            panic!("circular seeks on an empty tape were allowed");
+        };
+        assert!(error.message.contains("append seek mode, not circular"));
+    }
+
+    #[test]
+    fn append_tapes_grow_past_their_starting_size() {
+        let mut tape = Tape::builder().size(2).tape_mode(TapeMode::Append).build();
+
+        tape.right(5).unwrap();
+        tape.add(1).unwrap();
+        tape.right(1).unwrap();
+        assert_eq!((tape.get_pointer(), tape.size()), (6, 7));
+
+        tape.left(6).unwrap();
+        assert_eq!(tape.get_pointer(), 0);
+        tape.right(5).unwrap();
+        assert_eq!(tape.get_value(), 1);
+
+        tape.clear();
+        assert_eq!(tape.size(), 2);
+    }
+
+    #[test]
+    fn front_appends_keep_the_pointer_on_its_cell() {
+        let mut tape = Tape::builder().size(3).tape_mode(TapeMode::Append).build();
+        tape.right(1).unwrap();
+        tape.set_value(7);
+
+        // Two cells go on the front, moving the pointer along with its cell
+        tape.left(3).unwrap();
+        assert_eq!((tape.shift, tape.size()), (2, 5));
+        tape.right(3).unwrap();
+        assert_eq!(tape.get_pointer(), 3);
+        assert_eq!(tape.get_value(), 7);
+        tape.set_value(8);
+        assert_eq!(tape.get_value_at_index(tape.physical_index(1)), 8);
+    }
+
+    #[test]
+    fn seeks_count_from_where_the_addressing_says() {
+        let mut tape = Tape::builder().size(3).tape_mode(TapeMode::Append).build();
+        tape.seek(1).unwrap();
+        tape.set_value(7);
+        tape.left(3).unwrap();
+        assert_eq!(tape.shift, 2);
+
+        // The same cell as before cells were added to the front
+        tape.seek(1).unwrap();
+        assert_eq!((tape.get_pointer(), tape.position()), (3, 1));
+        assert_eq!(tape.get_value(), 7);
+        tape.seek(0).unwrap();
+        tape.left(1).unwrap();
+        assert_eq!(tape.position(), -1);
+
+        let mut tape = Tape::builder()
+            .size(3)
+            .tape_mode(TapeMode::Append)
+            .addressing(Addressing::Front)
+            .build();
+        tape.seek(1).unwrap();
+        tape.set_value(7);
+        tape.left(3).unwrap();
+        tape.seek(1).unwrap();
+        assert_eq!((tape.get_pointer(), tape.position()), (1, 1));
+        assert_eq!(tape.get_value(), 0);
+        tape.seek(3).unwrap();
+        assert_eq!(tape.get_value(), 7);
+
+        // Past the end is counted the same way too
+        let mut tape = Tape::builder()
+            .size(3)
+            .tape_mode(TapeMode::Append)
+            .seek_mode(TapeMode::Panic)
+            .build();
+        tape.left(2).unwrap();
+        tape.seek(2).unwrap();
+        let Err(error) = tape.seek(3) else {
+            panic!("seeking past the end succeeded");
+        };
+        assert_eq!(
+            error.message,
+            "Tape pointer would be above 2 if moved to cell 3"
+        );
+    }
+
+    #[test]
+    fn plain_dumps_mark_the_pointer_and_aliases() {
+        let mut tape = panic_tape(10);
+        tape.right(4).unwrap();
+        tape.add(7).unwrap();
+
+        assert_eq!(
+            tape.dump(2, DumpStyle::Plain, DumpFormat::Dec, &[5]),
+            concat!(
+                "index     2     3     4     5     6\n",
+                "value     0     0     7     0     0\n",
+                "                      ^     *\n",
+                "^ pointer  * alias\n",
+            )
+        );
+    }
+
+    #[test]
+    fn colour_dumps_colour_the_pointer_and_aliases() {
+        let mut tape = panic_tape(10);
+        tape.right(1).unwrap();
+
+        assert_eq!(
+            tape.dump(1, DumpStyle::Color, DumpFormat::Dec, &[2]),
+            format!(
+                "index     0     1     2\nvalue     0 {}    0{} {}    0{}\n{}pointer{}  {}alias{}\n",
+                POINTER_COLOR, RESET, ALIAS_COLOR, RESET, POINTER_COLOR, RESET, ALIAS_COLOR, RESET
+            )
+        );
+    }
+
+    #[test]
+    fn dump_formats_write_each_value() {
+        let expected = [
+            (DumpFormat::Hex, "0x41", "0x00"),
+            (DumpFormat::Dec, "65", "0"),
+            (DumpFormat::Char, "'A'", "\\x00"),
+        ];
+        for (format, letter, zero) in expected {
+            assert_eq!(format.format(65), letter);
+            assert_eq!(format.format(0), zero);
+        }
+    }
+
+    #[test]
+    fn circular_left_wraps_moves_longer_than_the_tape() {
+        let mut tape = circular_tape(5);
+
+        tape.left(5 * 3 + 2).unwrap();
+        assert_eq!(tape.get_pointer(), 3);
+        tape.left(5 * 3).unwrap();
+        assert_eq!(tape.get_pointer(), 3);
+        tape.left(4).unwrap();
+        assert_eq!(tape.get_pointer(), 4);
+
+        // 2^128 - 1 is a multiple of 5
+        tape.left(u128::MAX).unwrap();
+        assert_eq!(tape.get_pointer(), 4);
+    }
+
+    #[test]
+    fn circular_right_wraps_moves_longer_than_the_tape() {
+        let mut tape = circular_tape(5);
+        tape.right(2).unwrap();
+
+        tape.right(5 * 2 + 5).unwrap();
+        assert_eq!(tape.get_pointer(), 2);
+        tape.right(5 * 2 + 4).unwrap();
+        assert_eq!(tape.get_pointer(), 1);
+
+        // Adding 2^128 - 1 to the pointer directly would overflow
+        tape.right(u128::MAX).unwrap();
+        assert_eq!(tape.get_pointer(), 1);
+    }
+
+    #[test]
+    fn load_fills_cells_from_the_start() {
+        let mut tape = panic_tape(5);
+        tape.load(&[1, 2, 3]).unwrap();
+        assert_eq!(tape.cells(), [1, 2, 3, 0, 0]);
+
+        let error = tape.load(&[0; 6]).unwrap_err();
+        assert_eq!(error.message, "Can't load 6 bytes onto a tape of 5 cells");
+    }
+
+    #[test]
+    fn svg_draws_a_box_per_cell_and_marks_the_pointer() {
+        let mut tape = panic_tape(10);
+        tape.right(3).unwrap();
+        tape.add(65).unwrap();
+
+        let svg = tape.to_svg(2..6);
+        assert!(svg.starts_with("<svg "));
+        assert!(svg.ends_with("</svg>\n"));
+        assert_eq!(svg.matches("<rect ").count(), 4);
+        assert_eq!(svg.matches("class=\"pointer\"").count(), 1);
+        assert!(svg.contains("<polygon class=\"pointer\" points=\"60,60 54,72 66,72\""));
+        assert!(svg.contains("<text x=\"60\" y=\"40\">65</text>"));
+
+        // The range is clipped to the tape
+        assert_eq!(tape.to_svg(8..20).matches("<rect ").count(), 2);
+    }
+
+    #[test]
+    fn pointer_history_records_every_cell_passed() {
+        let mut tape = panic_tape(30);
+        assert!(tape.pointer_history().is_none());
+        tape.track_pointer();
+        tape.right(10).unwrap();
+        tape.left(5).unwrap();
+        assert_eq!(tape.pointer_history(), Some(vec![0..=10]));
+
+        let mut tape = circular_tape(10);
+        tape.track_pointer();
+        tape.left(2).unwrap();
+        assert_eq!(tape.pointer_history(), Some(vec![0..=0, 8..=9]));
+    }
+
+    #[test]
+    fn checked_access_past_the_end_errors() {
+        let mut tape = panic_tape(3);
+        tape.try_set_value_at_index(2, 7).unwrap();
+        assert_eq!(tape.try_get_value_at_index(2).unwrap(), 7);
+
+        let error = tape.try_get_value_at_index(3).unwrap_err();
+        assert_eq!(error.message, "Cell 3 is past the end of a 3 cell tape");
+        assert!(tape.try_set_value_at_index(u128::MAX, 1).is_err());
+        assert_eq!(tape.cells(), [0, 0, 7]);
+    }
+
+    #[test]
+    fn saturating_cells_clamp_and_nothing_cells_stay_put() {
+        let after = |cell_mode: CellMode, start: u8, add: bool, count: u8| {
+            let mut tape = Tape::builder().size(1).cell_mode(cell_mode).build();
+            tape.set_value(start);
+            match add {
+                true => tape.add(count).unwrap(),
+                false => tape.sub(count).unwrap(),
+            }
+            tape.get_value()
+        };
+
+        assert_eq!(after(CellMode::Saturate, 250, true, 10), 255);
+        assert_eq!(after(CellMode::Nothing, 250, true, 10), 250);
+        assert_eq!(after(CellMode::Saturate, 3, false, 5), 0);
+        assert_eq!(after(CellMode::Nothing, 3, false, 5), 3);
+        assert_eq!(after(CellMode::Saturate, 0, false, 1), 0);
+        assert_eq!(after(CellMode::Nothing, 0, false, 1), 0);
+        // Both act like circular cells when nothing overflows
+        assert_eq!(after(CellMode::Saturate, 5, true, 10), 15);
+        assert_eq!(after(CellMode::Nothing, 5, true, 10), 15);
+    }
+
+    #[test]
+    fn seek_policies_are_separate_from_the_tape_mode() {
+        let mut tape = Tape::builder()
+            .size(3)
+            .tape_mode(TapeMode::Append)
+            .seek_mode(TapeMode::Panic)
+            .build();
+        assert!(tape.seek(5).is_err());
+        tape.right(5).unwrap();
+        assert_eq!(tape.size(), 6);
+        tape.seek(1).unwrap();
+        assert_eq!(tape.get_pointer(), 1);
+
+        // Without a seek policy, seeks follow the tape mode
+        let mut tape = Tape::builder().size(3).tape_mode(TapeMode::Append).build();
+        tape.seek(5).unwrap();
+        assert_eq!((tape.get_pointer(), tape.size()), (5, 6));
+
+        let mut tape = Tape::builder()
+            .size(3)
+            .tape_mode(TapeMode::Panic)
+            .seek_mode(TapeMode::Circular)
+            .build();
+        tape.seek(4).unwrap();
+        assert_eq!(tape.get_pointer(), 1);
+        assert!(tape.right(2).is_err());
+    }
+
+    #[test]
+    fn snapshots_rebuild_the_exact_tape() {
+        let mut tape = Tape::builder()
+            .size(3)
+            .tape_mode(TapeMode::Append)
+            .seek_mode(TapeMode::Panic)
+            .cell_mode(CellMode::Saturate)
+            .fill(9)
+            .build();
+        tape.add(1).unwrap();
+        tape.left(2).unwrap();
+        tape.sub(4).unwrap();
+        tape.right(6).unwrap();
+
+        let bytes = tape.to_snapshot();
+        let loaded = Tape::from_snapshot(&bytes).unwrap();
+        assert_eq!(loaded.to_snapshot(), bytes);
+        assert_eq!(loaded.cells(), [5, 9, 10, 9, 9, 9, 9]);
+        assert_eq!(loaded.get_pointer(), 6);
+        assert_eq!(loaded.physical_index(0), 2);
+        assert!(loaded.get_cell_mode() == CellMode::Saturate);
+        assert!(loaded.get_tape_mode() == TapeMode::Append);
+        assert!(tape.difference(&loaded).is_none());
+
+        let Err(error) = Tape::from_snapshot(&bytes[..bytes.len() - 1]) else {
+            panic!("a truncated snapshot loaded");
+        };
+        assert_eq!(
+            error.message,
+            "Invalid saved state: expected 7 cells, but there are 6"
+        );
+        assert!(Tape::from_snapshot(b"not a state").is_err());
+
+        // Empty append tapes point at a cell that hasn't been made yet
+        let empty = Tape::builder().size(0).tape_mode(TapeMode::Append).build();
+        let loaded = Tape::from_snapshot(&empty.to_snapshot()).unwrap();
+        assert_eq!((loaded.size(), loaded.get_pointer()), (0, 0));
+
+        // The shift and pointer have to be within the cells
+        let number = |at: usize, value: u128, mut bytes: Vec<u8>| {
+            let start = STATE_MAGIC.len() + 5 + 16 * at;
+            bytes[start..start + 16].copy_from_slice(&value.to_le_bytes());
+            Tape::from_snapshot(&bytes).map(|_| ())
+        };
+        let Err(error) = number(1, 8, bytes.clone()) else {
+            panic!("a shift past the end loaded");
+        };
+        assert_eq!(
+            error.message,
+            "Invalid saved state: shift 8 is past the end of a 7 cell tape"
+        );
+        let bytes = panic_tape(3).to_snapshot();
+        assert!(number(2, 2, bytes.clone()).is_ok());
+        let Err(error) = number(2, 3, bytes) else {
+            panic!("a pointer past the end loaded");
+        };
+        assert_eq!(
+            error.message,
+            "Invalid saved state: pointer 3 is past the end of a 3 cell tape"
+        );
+    }
+}
@@ -0,0 +1,780 @@
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+};
+
+/// Write `src` to a file called `name` for a test to run.
+fn program(name: &str, src: &str) -> PathBuf {
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    fs::write(&path, src).unwrap();
+    path
+}
+
+/// Run bfem with `args`, giving it `stdin`.
+fn bfem(args: &[&str], stdin: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_bfem"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // Commands that don't read their input may have closed it already
+    let _ = child.stdin.take().unwrap().write_all(stdin);
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).to_string()
+}
+
+#[test]
+fn compile_to_bf_lowers_aliases() {
+    let path = program("compile_to_bf.bfem", "{a}+{b}++{a}.");
+    let out = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("compile_to_bf.bf");
+    let output = bfem(
+        &[
+            "--tape-size",
+            "10",
+            "compile",
+            path.to_str().unwrap(),
+            out.to_str().unwrap(),
+        ],
+        b"",
+    );
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(fs::read_to_string(out).unwrap(), ">>>>>>>>>+<++>.");
+}
+
+#[test]
+fn fill_sets_the_starting_cell_value() {
+    let path = program("fill.bfem", ".+.>-.");
+    let output = bfem(
+        &["--numeric", "--fill", "255", "run", path.to_str().unwrap()],
+        b"",
+    );
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "255 0 254 ");
+}
+
+#[test]
+fn explain_trace_aliases_prints_the_alias_table() {
+    let path = program("trace_aliases.bfem", "{beta}+{alpha}+");
+    let output = bfem(
+        &[
+            "--tape-size",
+            "10",
+            "explain",
+            "--trace-aliases",
+            path.to_str().unwrap(),
+        ],
+        b"",
+    );
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "Alias  Address\nalpha  8\nbeta   9\n");
+}
+
+#[test]
+fn echo_input_interleaves_with_output() {
+    let path = program("echo_input.bfem", ",+.,+.");
+    let echoed = bfem(&["--echo-input", "run", path.to_str().unwrap()], b"ab");
+    let plain = bfem(&["run", path.to_str().unwrap()], b"ab");
+
+    assert!(echoed.status.success(), "{}", stderr(&echoed));
+    assert_eq!(stdout(&echoed), "abbc");
+    assert_eq!(stdout(&plain), "bc");
+}
+
+#[test]
+fn runtime_errors_give_the_line_and_column() {
+    let path = program("runtime_error.bfem", "+\n+\n  ---");
+    let output = bfem(
+        &["--cell-mode", "panic", "run", path.to_str().unwrap()],
+        b"",
+    );
+
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("runtime_error.bfem:3:3:"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn check_lists_every_error() {
+    let path = program("check_errors.bfem", "+]\n{a\n[");
+    // JSON keeps each error on one line, however long the path is
+    let output = bfem(
+        &["--error-format", "json", "check", path.to_str().unwrap()],
+        b"",
+    );
+    let errors = stderr(&output);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        errors.contains("Unmatched ] with no loop to close"),
+        "{}",
+        errors
+    );
+    assert!(
+        errors.contains("Alias is never closed with }"),
+        "{}",
+        errors
+    );
+    assert!(errors.contains("Loop is never closed with ]"), "{}", errors);
+    assert_eq!(errors.lines().count(), 3, "{}", errors);
+}
+
+#[test]
+fn check_passes_a_valid_program_without_running_it() {
+    // Running this would wait for input and print
+    let path = program("check_valid.bfem", ",[.,]");
+    let output = bfem(&["check", path.to_str().unwrap()], b"x");
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "");
+}
+
+#[test]
+fn ensure_newline_only_adds_a_missing_newline() {
+    let path = program("ensure_newline.bfem", ",.");
+    let path = path.to_str().unwrap();
+
+    assert_eq!(
+        stdout(&bfem(&["--ensure-newline", "run", path], b"A")),
+        "A\n"
+    );
+    assert_eq!(
+        stdout(&bfem(&["--ensure-newline", "run", path], b"\n")),
+        "\n"
+    );
+    assert_eq!(stdout(&bfem(&["run", path], b"A")), "A");
+}
+
+#[test]
+fn output_file_gets_the_raw_bytes() {
+    let path = program("output_file.bfem", "-.+.+.");
+    let target = Path::new(env!("CARGO_TARGET_TMPDIR")).join("output_file.bin");
+    let output = bfem(
+        &[
+            "--output-file",
+            target.to_str().unwrap(),
+            "run",
+            path.to_str().unwrap(),
+        ],
+        b"",
+    );
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(fs::read(&target).unwrap(), [255, 0, 1]);
+
+    // Echoed input goes to stderr, keeping the file to what `.` wrote
+    let path = program("output_file_echo.bfem", ",.");
+    let output = bfem(
+        &[
+            "--echo-input",
+            "--output-file",
+            target.to_str().unwrap(),
+            "run",
+            path.to_str().unwrap(),
+        ],
+        b"A",
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "");
+    assert!(stderr(&output).starts_with('A'), "{}", stderr(&output));
+    assert_eq!(fs::read(&target).unwrap(), b"A");
+
+    let charset = program("output_file.charset", &"a".repeat(256));
+    for flags in [
+        vec!["--numeric"],
+        vec!["--ensure-newline"],
+        vec!["--charset", charset.to_str().unwrap()],
+    ] {
+        let mut args = flags.clone();
+        args.extend(["--output-file", target.to_str().unwrap()]);
+        args.extend(["run", path.to_str().unwrap()]);
+        let output = bfem(&args, b"A");
+        assert!(!output.status.success(), "{:?} was allowed", flags);
+    }
+}
+
+#[test]
+fn dump_on_error_shows_the_failing_cell() {
+    let path = program("dump_on_error.bfem", &"+".repeat(256));
+    let args = ["--cell-mode", "panic", "--error-format", "json", "run"];
+    let dumped = bfem(
+        &[&args[..], &["--dump-on-error", path.to_str().unwrap()]].concat(),
+        b"",
+    );
+    let plain = bfem(&[&args[..], &[path.to_str().unwrap()]].concat(), b"");
+
+    assert!(!dumped.status.success());
+    let dumped = stderr(&dumped);
+    assert!(
+        dumped.contains("\"message\":\"Cell 0 (value 255)"),
+        "{}",
+        dumped
+    );
+    assert!(
+        dumped.contains("index     0     1     2     3     4     5\nvalue   255     0"),
+        "{}",
+        dumped
+    );
+    assert!(!stderr(&plain).contains("index"));
+}
+
+#[test]
+fn json_errors_give_the_kind_and_span() {
+    let path = program("json_error.bfem", "+\n<");
+    let output = bfem(
+        &[
+            "--tape-mode",
+            "panic",
+            "--error-format",
+            "json",
+            "run",
+            path.to_str().unwrap(),
+        ],
+        b"",
+    );
+
+    assert!(!output.status.success());
+    assert_eq!(
+        stderr(&output),
+        format!(
+            "{{\"kind\":\"RuntimeError\",\"message\":\"Tape pointer would be below 0 if moved left 1 spaces from 0\",\"offset\":2,\"length\":1,\"file\":\"{}\"}}\n",
+            path.display()
+        )
+    );
+}
+
+#[test]
+fn quiet_compiles_print_nothing() {
+    let path = program("quiet.bfem", "+.");
+    let path = path.to_str().unwrap();
+    let target = Path::new(env!("CARGO_TARGET_TMPDIR")).join("quiet.bf");
+    let output = bfem(&["--quiet", "compile", path, target.to_str().unwrap()], b"");
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(fs::read_to_string(&target).unwrap(), "+.");
+
+    // Without a known extension only the instruction tree is printed
+    let tree = bfem(&["compile", path, "quiet.txt"], b"");
+    let quiet = bfem(&["--quiet", "compile", path, "quiet.txt"], b"");
+    assert!(stdout(&tree).contains("Output(1)"), "{}", stdout(&tree));
+    assert_eq!(stdout(&quiet), "");
+}
+
+#[test]
+fn explain_step_counts_instructions_as_written() {
+    let path = program("explain_step.bfem", "+++>++");
+    let output = bfem(&["explain", "--step", "4", path.to_str().unwrap()], b"");
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        concat!(
+            "index     0     1     2     3     4     5     6\n",
+            "value     3     0     0     0     0     0     0\n",
+            "                ^\n",
+            "Next: Add 1 at 1:5\n",
+        )
+    );
+}
+
+#[test]
+fn debug_print_goes_to_stderr() {
+    let path = program("debug_print.bfem", "+++?");
+    let output = bfem(&["run", path.to_str().unwrap()], b"");
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(stderr(&output), "pointer 0, value 3\n");
+}
+
+#[test]
+fn seed_tape_preloads_the_cells() {
+    let path = program("seed_tape.bfem", ">.");
+    let seed = Path::new(env!("CARGO_TARGET_TMPDIR")).join("seed_tape.bin");
+    fs::write(&seed, [1, 2, 3]).unwrap();
+    let output = bfem(
+        &[
+            "--numeric",
+            "run",
+            "--seed-tape",
+            seed.to_str().unwrap(),
+            path.to_str().unwrap(),
+        ],
+        b"",
+    );
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "2 ");
+}
+
+#[test]
+fn dry_run_compiles_write_nothing() {
+    let path = program("dry_run.bfem", ",[->+<]>.");
+    let target = Path::new(env!("CARGO_TARGET_TMPDIR")).join("dry_run.bf");
+    let _ = fs::remove_file(&target);
+    let output = bfem(
+        &[
+            "compile",
+            "--dry-run",
+            path.to_str().unwrap(),
+            target.to_str().unwrap(),
+        ],
+        b"",
+    );
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "9 bytes, 8 instructions\n");
+    assert!(!target.exists());
+}
+
+#[test]
+fn numeric_output_uses_the_separator() {
+    let path = program("numeric_sep.bfem", "+++.>++.");
+    let path = path.to_str().unwrap();
+    let comma = bfem(&["--numeric", "--numeric-sep", ",", "run", path], b"");
+    let newline = bfem(&["--numeric", "--numeric-newline", "run", path], b"");
+
+    assert!(comma.status.success(), "{}", stderr(&comma));
+    assert_eq!(stdout(&comma), "3,2,");
+    assert_eq!(stdout(&newline), "3\n2\n");
+}
+
+#[test]
+fn check_handles_an_empty_append_tape() {
+    let path = program("check_empty_tape.bfem", "+[]");
+    let output = bfem(
+        &[
+            "--tape-mode",
+            "append",
+            "--tape-size",
+            "0",
+            "--error-format",
+            "json",
+            "check",
+            path.to_str().unwrap(),
+        ],
+        b"",
+    );
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("\"message\":\"This loop can never finish\",\"offset\":1"));
+}
+
+#[test]
+fn verify_passes_a_correct_program() {
+    let path = program("verify.bfem", ",[->++<]>.");
+    let output = bfem(
+        &["--numeric", "run", "--verify", path.to_str().unwrap()],
+        &[3],
+    );
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "6 ");
+}
+
+#[test]
+fn convert_round_trips_between_brainfuck_and_bfem() {
+    let tmp = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let bf = program("convert.bf", "say {x} hi ++++++[>++++++++<-]>+.");
+    let bfem_path = tmp.join("convert.bfem");
+    let back = tmp.join("convert_back.bf");
+
+    let output = bfem(
+        &["convert", bf.to_str().unwrap(), bfem_path.to_str().unwrap()],
+        b"",
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    // The braces were comments in brainfuck, so they mustn't become an alias
+    assert!(!fs::read_to_string(&bfem_path).unwrap().contains('{'));
+
+    let output = bfem(
+        &[
+            "convert",
+            bfem_path.to_str().unwrap(),
+            back.to_str().unwrap(),
+        ],
+        b"",
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    for path in [&bfem_path, &back] {
+        let output = bfem(&["run", path.to_str().unwrap()], b"");
+        assert!(output.status.success(), "{}", stderr(&output));
+        assert_eq!(stdout(&output), "1");
+    }
+}
+
+#[test]
+fn list_modes_describes_every_mode() {
+    let output = bfem(&["list-modes"], b"");
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    let text = stdout(&output);
+    let (tape, cell) = text.split_once("Cell modes").unwrap();
+    for mode in ["circular", "append", "panic"] {
+        assert!(tape.contains(&format!("\n  {}  ", mode)), "{}", text);
+    }
+    for mode in ["circular", "nothing", "saturate", "panic"] {
+        assert!(cell.contains(&format!("\n  {}  ", mode)), "{}", text);
+    }
+}
+
+#[test]
+fn warnings_as_errors_fails_on_a_warning() {
+    let path = program("warnings_as_errors.bfem", "+[]>");
+    let output = bfem(
+        &["--error-format", "json", "check", path.to_str().unwrap()],
+        b"",
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("This loop can never finish"));
+
+    let output = bfem(
+        &["--warnings-as-errors", "check", path.to_str().unwrap()],
+        b"",
+    );
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("2 warnings treated as errors"));
+
+    let path = program("no_warnings.bfem", "+[-]>");
+    let output = bfem(
+        &["--warnings-as-errors", "check", path.to_str().unwrap()],
+        b"",
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+}
+
+#[test]
+fn closed_input_follows_the_eof_policy() {
+    let path = program("eof.bfem", "+++,.,.");
+    let output = bfem(
+        &[
+            "--numeric",
+            "--eof",
+            "unchanged",
+            "run",
+            path.to_str().unwrap(),
+        ],
+        b"",
+    );
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "3 3 ");
+}
+
+#[test]
+fn saved_state_carries_over_to_the_next_run() {
+    let state = Path::new(env!("CARGO_TARGET_TMPDIR")).join("saved.state");
+    let first = program("save_state.bfem", "+++>++");
+    let second = program("load_state.bfem", ".<.");
+
+    let output = bfem(
+        &[
+            "run",
+            "--save-state",
+            state.to_str().unwrap(),
+            first.to_str().unwrap(),
+        ],
+        b"",
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let output = bfem(
+        &[
+            "--numeric",
+            "run",
+            "--load-state",
+            state.to_str().unwrap(),
+            second.to_str().unwrap(),
+        ],
+        b"",
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "2 3 ");
+}
+
+#[test]
+fn bench_reruns_the_program_the_same_each_time() {
+    let path = program("bench.bfem", ",[>++<-]>.");
+    let output = bfem(
+        &["bench", "--iterations", "3", path.to_str().unwrap()],
+        &[4],
+    );
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    let text = stdout(&output);
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines.len(), 5, "{}", text);
+    // Every run reads the same input, so takes as many steps
+    let steps: Vec<&str> = lines[..3]
+        .iter()
+        .enumerate()
+        .map(|(index, line)| {
+            let run = format!("Run {}: ", index + 1);
+            let line = line
+                .strip_prefix(&run)
+                .unwrap_or_else(|| panic!("{}", text));
+            line.split_once(" steps in ").unwrap().0
+        })
+        .collect();
+    assert_eq!(steps, [steps[0]; 3]);
+    assert!(lines[3].starts_with("Min "), "{}", text);
+    assert!(lines[3].contains(" ms, median "), "{}", text);
+    assert!(lines[3].contains(" ms, max "), "{}", text);
+    let total: u64 = steps[0].parse::<u64>().unwrap() * 3;
+    assert_eq!(lines[4], format!("Total: {} steps", total));
+    assert!(total > 3 * 4, "{}", text);
+}
+
+#[test]
+fn empty_programs_warn_that_nothing_runs() {
+    let path = program("empty.bfem", "");
+    let output = bfem(
+        &["--error-format", "json", "run", path.to_str().unwrap()],
+        b"",
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "");
+    assert!(stderr(&output).contains("The file is empty, so there is nothing to run"));
+
+    let path = program("only_comments.bfem", "# nothing\n# here\n");
+    let output = bfem(
+        &["--error-format", "json", "run", path.to_str().unwrap()],
+        b"",
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("The program is only comments"));
+
+    let output = bfem(&["--quiet", "run", path.to_str().unwrap()], b"");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stderr(&output), "");
+}
+
+#[test]
+fn compile_to_bf_rejects_gotos_after_unbalanced_loops() {
+    let path = program("unbalanced_goto.bfem", "{a},[>]{b}+");
+    let out = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("unbalanced_goto.bf");
+    let _ = fs::remove_file(&out);
+    let output = bfem(
+        &[
+            "--tape-size",
+            "10",
+            "--error-format",
+            "json",
+            "compile",
+            path.to_str().unwrap(),
+            out.to_str().unwrap(),
+        ],
+        b"",
+    );
+
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Alias b can't be lowered to a relative move"));
+    assert!(!out.exists());
+}
+
+#[test]
+fn empty_tapes_must_be_append_tapes() {
+    let path = program("empty_tape.bfem", "+>.");
+    let output = bfem(
+        &[
+            "--tape-size",
+            "0",
+            "--error-format",
+            "json",
+            "run",
+            path.to_str().unwrap(),
+        ],
+        b"",
+    );
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("A tape of size 0 needs an append tape mode"));
+
+    let output = bfem(
+        &[
+            "--numeric",
+            "--tape-size",
+            "0",
+            "--tape-mode",
+            "append",
+            "run",
+            path.to_str().unwrap(),
+        ],
+        b"",
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "0 ");
+}
+
+#[test]
+fn missing_files_are_reported_without_panicking() {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("does_not_exist.bf");
+    for args in [
+        vec!["run", path.to_str().unwrap()],
+        vec!["--charset", path.to_str().unwrap(), "run", "/dev/null"],
+        vec!["fmt", path.to_str().unwrap()],
+    ] {
+        let output = bfem(&args, b"");
+        assert!(!output.status.success());
+        let stderr = stderr(&output);
+        assert!(!stderr.contains("panicked"), "{}", stderr);
+        assert!(stderr.contains("does_not_exist.bf"), "{}", stderr);
+    }
+}
+
+#[test]
+fn streamed_programs_run_and_report_missing_files() {
+    let path = program("stream.bfem", "{a}+++{b}++{a}.");
+    let output = bfem(
+        &["--numeric", "run", "--stream", path.to_str().unwrap()],
+        b"",
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "3 ");
+
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("stream_missing.bfem");
+    let output = bfem(&["run", "--stream", path.to_str().unwrap()], b"");
+    assert!(!output.status.success());
+    assert!(!stderr(&output).contains("panicked"), "{}", stderr(&output));
+    assert!(stderr(&output).contains("stream_missing.bfem"));
+}
+
+#[test]
+fn loops_at_the_start_unroll_unless_the_tape_is_seeded() {
+    let path = program("unroll_start.bfem", "+++[>+<-]>.");
+    let output = bfem(&["explain", "--optimisations", path.to_str().unwrap()], b"");
+    assert!(output.status.success(), "{}", stderr(&output));
+    let text = stdout(&output);
+    let unroll = text
+        .lines()
+        .find(|line| line.starts_with("unroll:"))
+        .unwrap();
+    assert!(!unroll.contains("no changes"), "{}", text);
+
+    let output = bfem(&["--numeric", "run", path.to_str().unwrap()], b"");
+    assert_eq!(stdout(&output), "3 ");
+
+    // A seeded or loaded cell means the trip count isn't known
+    let seed = Path::new(env!("CARGO_TARGET_TMPDIR")).join("unroll_start.bin");
+    fs::write(&seed, [2]).unwrap();
+    let output = bfem(
+        &[
+            "--numeric",
+            "run",
+            "--seed-tape",
+            seed.to_str().unwrap(),
+            path.to_str().unwrap(),
+        ],
+        b"",
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "5 ");
+
+    let state = Path::new(env!("CARGO_TARGET_TMPDIR")).join("unroll_start.state");
+    let first = program("unroll_start_save.bfem", "++");
+    let output = bfem(
+        &[
+            "run",
+            "--save-state",
+            state.to_str().unwrap(),
+            first.to_str().unwrap(),
+        ],
+        b"",
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let output = bfem(
+        &[
+            "--numeric",
+            "run",
+            "--load-state",
+            state.to_str().unwrap(),
+            path.to_str().unwrap(),
+        ],
+        b"",
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "5 ");
+}
+
+#[test]
+fn empty_append_tapes_save_and_load() {
+    let state = Path::new(env!("CARGO_TARGET_TMPDIR")).join("empty.state");
+    let path = program("empty_state.bfem", "");
+    let tape = ["--tape-size", "0", "--tape-mode", "append"];
+
+    let mut args = tape.to_vec();
+    args.extend(["run", "--save-state", state.to_str().unwrap()]);
+    args.push(path.to_str().unwrap());
+    let output = bfem(&args, b"");
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let mut args = tape.to_vec();
+    args.extend(["run", "--load-state", state.to_str().unwrap()]);
+    args.push(path.to_str().unwrap());
+    let output = bfem(&args, b"");
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let missing = Path::new(env!("CARGO_TARGET_TMPDIR")).join("missing.state");
+    let output = bfem(
+        &[
+            "run",
+            "--load-state",
+            missing.to_str().unwrap(),
+            path.to_str().unwrap(),
+        ],
+        b"",
+    );
+    assert!(!output.status.success());
+    assert!(!stderr(&output).contains("panicked"), "{}", stderr(&output));
+    assert!(stderr(&output).contains("missing.state"));
+}
+
+#[test]
+fn addressing_picks_where_seeks_count_from() {
+    let path = program("addressing.bfem", "@1+++<<<@1.?");
+    let run = |addressing: &str| {
+        bfem(
+            &[
+                "--numeric",
+                "--tape-mode",
+                "append",
+                "--tape-size",
+                "3",
+                "--addressing",
+                addressing,
+                "run",
+                path.to_str().unwrap(),
+            ],
+            b"",
+        )
+    };
+
+    let output = run("start");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "3 ");
+    assert!(stderr(&output).contains("pointer 1, value 3"));
+
+    let output = run("front");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "0 ");
+    assert!(stderr(&output).contains("pointer 1, value 0"));
+}